@@ -0,0 +1,590 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+use num::complex::Complex;
+use rustfft::{FFTplanner, FFT};
+use super::resample::kaiser;
+use super::bands::*;
+use super::hpss::*;
+use super::onset::*;
+use super::pitch::*;
+use super::envelope::*;
+
+// Lowest window value divided out when a frame is taken back to the time
+// domain, so the samples at the tapered edges are not blown up
+const WINDOW_FLOOR: f32 = 0.1;
+
+// Window applied to every frame before the FFT, to keep the edges of the
+// frame from smearing energy across the spectrum
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowFunction {
+    Rectangular,
+    Hann,
+    Hamming,
+    BlackmanHarris,
+    // Kaiser window with the given shape parameter
+    Kaiser(f32)
+}
+
+impl WindowFunction {
+    // Parses "hann", "hamming", "blackman-harris", "rectangular" or
+    // "kaiser" (optionally with a beta, e.g. "kaiser:8.6")
+    pub fn from_name(name: &str) -> Option<WindowFunction> {
+        let mut parts = name.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("rectangular"), None) => Some(WindowFunction::Rectangular),
+            (Some("hann"), None) => Some(WindowFunction::Hann),
+            (Some("hamming"), None) => Some(WindowFunction::Hamming),
+            (Some("blackman-harris"), None) => Some(WindowFunction::BlackmanHarris),
+            (Some("kaiser"), None) => Some(WindowFunction::Kaiser(8.6)),
+            (Some("kaiser"), Some(beta)) => beta.parse().ok().map(WindowFunction::Kaiser),
+            _ => None
+        }
+    }
+
+    // The window's coefficients for a frame of `len` samples. The windows
+    // are periodic, so frames overlapped at the usual hops sum evenly.
+    pub fn coefficients(self, len: usize) -> Vec<f32> {
+        let n = len as f32;
+        (0..len).map(|i| {
+            let phase = 2f32 * PI * i as f32 / n;
+            match self {
+                WindowFunction::Rectangular => 1f32,
+                WindowFunction::Hann => 0.5f32 - 0.5f32 * phase.cos(),
+                WindowFunction::Hamming => 0.54f32 - 0.46f32 * phase.cos(),
+                WindowFunction::BlackmanHarris => 0.35875f32 - 0.48829f32 * phase.cos()
+                    + 0.14128f32 * (2f32 * phase).cos() - 0.01168f32 * (3f32 * phase).cos(),
+                WindowFunction::Kaiser(beta) => {
+                    kaiser(2f64 * i as f64 / len as f64 - 1f64, beta as f64) as f32
+                }
+            }
+        }).collect()
+    }
+}
+
+/*
+How audio is cut into frames for spectral analysis. Every frame holds
+`fft_size` samples, consecutive frames start `hop_size` samples apart, and
+each frame is windowed and then padded with zeros to `zero_padding` times
+its length before the FFT, which interpolates the spectrum onto finer bins.
+The spectrum of every frame is then summarised in `bands`, onsets are
+picked out of the changes between frames as `onsets` describes, the
+pitch of every frame is tracked as `pitch` describes, levels are
+smoothed from frame to frame as `envelope` describes, and spectra are
+split into harmonic and percussive layers as `hpss` describes.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalysisConfig {
+    pub fft_size: usize,
+    pub hop_size: usize,
+    pub window: WindowFunction,
+    pub zero_padding: usize,
+    pub bands: BandScale,
+    pub onsets: OnsetConfig,
+    pub pitch: PitchConfig,
+    pub envelope: EnvelopeConfig,
+    pub hpss: HpssConfig
+}
+
+impl Default for AnalysisConfig {
+    // 2048 samples (46 ms at 44.1 kHz) every 512 samples, Hann windowed
+    fn default() -> AnalysisConfig {
+        AnalysisConfig {
+            fft_size: 2048,
+            hop_size: 512,
+            window: WindowFunction::Hann,
+            zero_padding: 1,
+            bands: BandScale::ThirdOctave,
+            onsets: OnsetConfig::default(),
+            pitch: PitchConfig::default(),
+            envelope: EnvelopeConfig::default(),
+            hpss: HpssConfig::default()
+        }
+    }
+}
+
+impl AnalysisConfig {
+    // Sets one option from the command line: "fft-size", "hop-size",
+    // "window", "zero-padding", "bands", "onset-threshold", "onset-per-band",
+    // "onset-layer", "pitch-threshold", "pitch-range" (as "min-hz:max-hz"),
+    // "envelope-detector", "envelope-attack" or "envelope-release" (both in
+    // seconds), "hpss-harmonic-secs", "hpss-percussive-hz" or "layers" (the
+    // layers described on their own, as "harmonic", "percussive", both
+    // separated by a comma, or "none")
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let count = || match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Expected a positive whole number for {}, got {}", name, value))
+        };
+        match name {
+            "fft-size" => self.fft_size = count()?,
+            "hop-size" => self.hop_size = count()?,
+            "zero-padding" => self.zero_padding = count()?,
+            "window" => self.window = WindowFunction::from_name(value)
+                .ok_or_else(|| format!("Unknown window function: {}", value))?,
+            "bands" => self.bands = BandScale::from_name(value)
+                .ok_or_else(|| format!("Unknown band scale: {}", value))?,
+            "onset-threshold" => self.onsets.threshold = match value.parse::<f32>() {
+                Ok(threshold) if threshold > 0f32 => threshold,
+                _ => return Err(format!("Expected a positive number for {}, got {}", name, value))
+            },
+            "onset-per-band" => self.onsets.per_band = match value {
+                "yes" | "true" => true,
+                "no" | "false" => false,
+                _ => return Err(format!("Expected yes or no for {}, got {}", name, value))
+            },
+            "onset-layer" => self.onsets.layer = Layer::from_name(value)
+                .ok_or_else(|| format!("Unknown layer: {}", value))?,
+            "pitch-threshold" => self.pitch.threshold = match value.parse::<f32>() {
+                Ok(threshold) if threshold > 0f32 && threshold < 1f32 => threshold,
+                _ => return Err(format!("Expected a number between 0 and 1 for {}, got {}", name, value))
+            },
+            "pitch-range" => {
+                let mut parts = value.splitn(2, ':').map(|part| part.parse::<f32>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(min)), Some(Ok(max))) if 0f32 < min && min < max => {
+                        self.pitch.min_hz = min;
+                        self.pitch.max_hz = max;
+                    },
+                    _ => return Err(format!("Expected min-hz:max-hz for {}, got {}", name, value))
+                }
+            },
+            "envelope-detector" => self.envelope.detector = Detector::from_name(value)
+                .ok_or_else(|| format!("Unknown envelope detector: {}", value))?,
+            "envelope-attack" | "envelope-release" => {
+                let secs = match value.parse::<f32>() {
+                    Ok(secs) if secs >= 0f32 => secs,
+                    _ => return Err(format!("Expected a time in seconds for {}, got {}", name, value))
+                };
+                if name == "envelope-attack" {
+                    self.envelope.attack_secs = secs;
+                } else {
+                    self.envelope.release_secs = secs;
+                }
+            },
+            "hpss-harmonic-secs" | "hpss-percussive-hz" => {
+                let amount = match value.parse::<f32>() {
+                    Ok(amount) if amount > 0f32 => amount,
+                    _ => return Err(format!("Expected a positive number for {}, got {}", name, value))
+                };
+                if name == "hpss-harmonic-secs" {
+                    self.hpss.harmonic_secs = amount;
+                } else {
+                    self.hpss.percussive_hz = amount;
+                }
+            },
+            "layers" => {
+                self.hpss.harmonic_features = false;
+                self.hpss.percussive_features = false;
+                for layer in value.split(',').filter(|&layer| layer != "none") {
+                    match Layer::from_name(layer) {
+                        Some(Layer::Harmonic) => self.hpss.harmonic_features = true,
+                        Some(Layer::Percussive) => self.hpss.percussive_features = true,
+                        _ => return Err(format!("Expected harmonic, percussive or none for {}, got {}", name, value))
+                    }
+                }
+            },
+            _ => return Err(format!("Unknown option: --{}", name))
+        }
+        Ok(())
+    }
+
+    // Length of the transform, padding included
+    pub fn padded_size(&self) -> usize {
+        self.fft_size * self.zero_padding.max(1)
+    }
+
+    // Number of spectrum bins from DC up to Nyquist
+    pub fn num_bins(&self) -> usize {
+        self.padded_size() / 2 + 1
+    }
+
+    // Width of one spectrum bin, in Hz
+    pub fn bin_width(&self, sample_rate: u32) -> f32 {
+        sample_rate as f32 / self.padded_size() as f32
+    }
+
+    // Width in bins of the flat band that would pass as much noise power as
+    // the window does. A pure tone's energy is spread over about this many bins.
+    pub fn noise_bandwidth(&self) -> f32 {
+        let window = self.window.coefficients(self.fft_size);
+        let sum = window.iter().sum::<f32>();
+        self.padded_size() as f32 * window.iter().map(|w| w * w).sum::<f32>() / (sum * sum)
+    }
+
+    // Seconds from the start of a frame to its centre, which is the time
+    // every per-frame result is stamped with
+    pub fn centre_offset_secs(&self, sample_rate: u32) -> f64 {
+        self.fft_size as f64 / 2f64 / sample_rate as f64
+    }
+}
+
+/*
+Reusable short-time Fourier transform for one configuration. It owns the
+FFT plan and every buffer, so analysing a frame never allocates. Magnitudes
+are scaled so a full scale sine wave peaks at 1.
+*/
+pub struct Stft {
+    fft: Arc<dyn FFT<f32>>,
+    window: Vec<f32>,
+    input: Vec<Complex<f32>>,
+    spectrum: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+    resynthesis: Vec<Complex<f32>>,
+    scale: f32
+}
+
+impl Stft {
+    pub fn new(config: AnalysisConfig) -> Stft {
+        let mut planner = FFTplanner::new(false);
+        Stft::with_planner(config, &mut planner)
+    }
+
+    // Shares FFT plans with other users of `planner`
+    pub fn with_planner(config: AnalysisConfig, planner: &mut FFTplanner<f32>) -> Stft {
+        let size = config.padded_size();
+        let window = config.window.coefficients(config.fft_size);
+        let scale = 2f32 / window.iter().sum::<f32>();
+        Stft {
+            window, scale,
+            fft: planner.plan_fft(size),
+            input: vec![Complex::new(0f32, 0f32); size],
+            spectrum: vec![Complex::new(0f32, 0f32); size],
+            magnitudes: vec![0f32; config.num_bins()],
+            resynthesis: vec![Complex::new(0f32, 0f32); size]
+        }
+    }
+
+    // Windows and transforms one frame of `fft_size` samples, returning the
+    // magnitude of every bin from DC up to Nyquist
+    pub fn process(&mut self, frame: &[f32]) -> &[f32] {
+        for (i, c) in self.input.iter_mut().enumerate() {
+            let sample = match (frame.get(i), self.window.get(i)) {
+                (Some(&x), Some(&w)) => x * w,
+                _ => 0f32
+            };
+            *c = Complex::new(sample, 0f32);
+        }
+        self.fft.process(&mut self.input, &mut self.spectrum);
+        for (magnitude, c) in self.magnitudes.iter_mut().zip(&self.spectrum) {
+            *magnitude = c.norm() * self.scale;
+        }
+        &self.magnitudes
+    }
+
+    // Takes the frame last processed back to the time domain with every bin
+    // scaled by `gains` (one per bin from DC up to Nyquist), e.g. to hear a
+    // single layer of it, and writes its `fft_size` samples into `frame`.
+    // The window is divided back out except where it tapers below
+    // `WINDOW_FLOOR`.
+    pub fn resynthesise(&mut self, gains: &[f32], frame: &mut Vec<f32>) {
+        let size = self.spectrum.len();
+        // the inverse transform, done forwards on the conjugate, with the
+        // negative frequencies scaled like the positive ones they mirror
+        for (i, (c, bin)) in self.input.iter_mut().zip(&self.spectrum).enumerate() {
+            let gain = gains.get(i.min(size - i)).cloned().unwrap_or(0f32);
+            *c = (*bin * gain).conj();
+        }
+        self.fft.process(&mut self.input, &mut self.resynthesis);
+        frame.clear();
+        frame.extend(self.resynthesis.iter().zip(&self.window)
+            .map(|(c, &w)| c.re / size as f32 / w.max(WINDOW_FLOOR)));
+    }
+}
+
+// Frequency of the loudest bin in a magnitude spectrum, passing over any
+// bin that isn't a number
+pub fn spectral_peak(magnitudes: &[f32], bin_width: f32) -> f32 {
+    let max_peak = magnitudes.iter().enumerate()
+        .filter(|&(_, magnitude)| magnitude.is_finite())
+        .max_by(|&(_, a), &(_, b)| a.total_cmp(b));
+    match max_peak {
+        Some((i, _)) => i as f32 * bin_width,
+        None => 0f32
+    }
+}
+
+// Share of the spectrum's energy that lies below the rolloff frequency
+const ROLLOFF_FRACTION: f32 = 0.85;
+// Keeps silent bins from zeroing the geometric mean in the flatness
+const FLATNESS_FLOOR: f32 = 1e-10;
+
+/*
+Descriptors of the timbre of one frame of one signal, stamped with the
+centre of the frame like every other per-frame result.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpectralFeatures {
+    pub time_secs: f64,
+    // frequency of the loudest bin, as `spectral_peak` finds it
+    pub peak_hz: f32,
+    // the spectrum's centre of mass and how widely it spreads around it
+    pub centroid_hz: f32,
+    pub spread_hz: f32,
+    // frequency below which `ROLLOFF_FRACTION` of the energy lies
+    pub rolloff_hz: f32,
+    // from near 0 for a pure tone to 1 for white noise
+    pub flatness: f32,
+    // how much the spectrum grew since the previous frame
+    pub flux: f32,
+    // the loudest bin over the average bin
+    pub crest: f32,
+    // share of consecutive samples that change sign
+    pub zero_crossing_rate: f32
+}
+
+/*
+Works out the spectral features of consecutive frames of one signal. The
+flux needs the previous frame's spectrum, so every signal gets its own.
+*/
+pub struct FeatureExtractor {
+    bin_width: f32,
+    previous: Vec<f32>
+}
+
+impl FeatureExtractor {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> FeatureExtractor {
+        FeatureExtractor { bin_width: config.bin_width(sample_rate), previous: Vec::new() }
+    }
+
+    // Takes the samples of the frame centred on `time_secs` and its
+    // magnitude spectrum
+    pub fn process(&mut self, time_secs: f64, frame: &[f32], magnitudes: &[f32]) -> SpectralFeatures {
+        let hz = |bin: usize| bin as f32 * self.bin_width;
+        let total: f32 = magnitudes.iter().sum();
+        let energy: f32 = magnitudes.iter().map(|m| m * m).sum();
+        let mut features = SpectralFeatures {
+            time_secs,
+            peak_hz: spectral_peak(magnitudes, self.bin_width),
+            centroid_hz: 0f32,
+            spread_hz: 0f32,
+            rolloff_hz: 0f32,
+            flatness: 0f32,
+            flux: 0f32,
+            crest: 0f32,
+            zero_crossing_rate: 0f32
+        };
+
+        if total > 0f32 {
+            let centroid = magnitudes.iter().enumerate().map(|(bin, m)| hz(bin) * m).sum::<f32>() / total;
+            let variance = magnitudes.iter().enumerate()
+                .map(|(bin, m)| (hz(bin) - centroid) * (hz(bin) - centroid) * m)
+                .sum::<f32>() / total;
+            features.centroid_hz = centroid;
+            features.spread_hz = variance.sqrt();
+            features.crest = magnitudes.iter().cloned().fold(0f32, f32::max)
+                / (total / magnitudes.len() as f32);
+
+            let mut below = 0f32;
+            let rolloff_bin = magnitudes.iter().position(|m| {
+                below += m * m;
+                below >= ROLLOFF_FRACTION * energy
+            });
+            features.rolloff_hz = hz(rolloff_bin.unwrap_or(magnitudes.len() - 1));
+
+            // geometric over arithmetic mean of the power spectrum
+            let powers = magnitudes.iter().map(|m| (m * m).max(FLATNESS_FLOOR));
+            let log_mean = powers.clone().map(f32::ln).sum::<f32>() / magnitudes.len() as f32;
+            let mean = powers.sum::<f32>() / magnitudes.len() as f32;
+            features.flatness = (log_mean.exp() / mean).min(1f32);
+        }
+
+        if self.previous.len() == magnitudes.len() {
+            features.flux = magnitudes.iter().zip(&self.previous)
+                .map(|(now, before)| (now - before).max(0f32).powi(2))
+                .sum::<f32>()
+                .sqrt();
+        }
+        self.previous.clear();
+        self.previous.extend_from_slice(magnitudes);
+
+        let crossings = frame.windows(2).filter(|pair| (pair[0] >= 0f32) != (pair[1] >= 0f32)).count();
+        features.zero_crossing_rate = crossings as f32 / frame.len().saturating_sub(1).max(1) as f32;
+        features
+    }
+
+    // Forgets the previous frame, e.g. after playback jumps
+    pub fn reset(&mut self) {
+        self.previous.clear();
+    }
+}
+
+/*
+Gathers the signals of a stream into overlapping analysis frames. Values
+are pushed one time step at a time, and whenever a full frame of every
+signal is available it is handed out and the frames move on by a hop.
+*/
+pub struct SignalFrames {
+    fft_size: usize,
+    hop_size: usize,
+    windows: Vec<Vec<f32>>,
+    // time step the next pushed values belong to
+    position: u64,
+    // values still to be dropped when the hop is longer than a frame
+    skip: usize
+}
+
+impl SignalFrames {
+    pub fn new(config: &AnalysisConfig, num_signals: usize) -> SignalFrames {
+        SignalFrames {
+            fft_size: config.fft_size,
+            hop_size: config.hop_size.max(1),
+            windows: vec![Vec::with_capacity(config.fft_size); num_signals],
+            position: 0,
+            skip: 0
+        }
+    }
+
+    // Adds the value of every signal at the next time step. Once that
+    // completes a frame, returns the time step the frame starts at; the frame
+    // itself can then be read with `frames` until the next push.
+    pub fn push(&mut self, values: &[f32]) -> Option<u64> {
+        if self.windows[0].len() == self.fft_size {
+            // move on from the frame handed out last time
+            let hop = self.hop_size.min(self.fft_size);
+            for window in &mut self.windows {
+                window.drain(..hop);
+            }
+            self.skip = self.hop_size - hop;
+        }
+        self.position += 1;
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        for (window, &value) in self.windows.iter_mut().zip(values) {
+            window.push(value);
+        }
+        if self.windows[0].len() == self.fft_size {
+            Some(self.position - self.fft_size as u64)
+        } else {
+            None
+        }
+    }
+
+    // The latest complete frame of every signal
+    pub fn frames(&self) -> &[Vec<f32>] {
+        &self.windows
+    }
+
+    // Forgets everything pushed so far and carries on from time step
+    // `position`, e.g. after playback jumps
+    pub fn reset(&mut self, position: u64) {
+        for window in &mut self.windows {
+            window.clear();
+        }
+        self.position = position;
+        self.skip = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_overlapped_at_their_hops_sum_evenly() {
+        let len = 1024;
+        for &(window, hop, sum) in &[(WindowFunction::Rectangular, len, 1f32),
+                                      (WindowFunction::Hann, len / 2, 1f32),
+                                      (WindowFunction::Hann, len / 4, 2f32),
+                                      (WindowFunction::Hamming, len / 2, 1.08f32),
+                                      (WindowFunction::BlackmanHarris, len / 4, 4f32 * 0.35875f32)] {
+            let coefficients = window.coefficients(len);
+            for i in 0..hop {
+                let total: f32 = coefficients.iter().skip(i).step_by(hop).sum();
+                assert!((total - sum).abs() < 1e-4, "{:?} at {}: {}", window, i, total);
+            }
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_reads_0_db_at_its_bin() {
+        let sample_rate = 44100;
+        for &window in &[WindowFunction::Rectangular, WindowFunction::Hann, WindowFunction::Hamming,
+                         WindowFunction::BlackmanHarris, WindowFunction::Kaiser(8.6)] {
+            for &zero_padding in &[1, 2] {
+                let config = AnalysisConfig { window, zero_padding, ..AnalysisConfig::default() };
+                // exactly on bin 100 of the unpadded transform
+                let bin = 100;
+                let hz = bin as f32 * sample_rate as f32 / config.fft_size as f32;
+                let frame: Vec<f32> = (0..config.fft_size)
+                    .map(|i| (2f32 * PI * hz * i as f32 / sample_rate as f32).sin())
+                    .collect();
+                let mut stft = Stft::new(config);
+                let magnitudes = stft.process(&frame);
+                let db = 20f32 * magnitudes[bin * zero_padding].log10();
+                assert!(db.abs() < 0.01, "{:?} x{}: {} dB", window, zero_padding, db);
+                assert_eq!(spectral_peak(magnitudes, config.bin_width(sample_rate)), hz);
+            }
+        }
+    }
+
+    #[test]
+    fn hann_noise_bandwidth_is_one_and_a_half_bins() {
+        let config = AnalysisConfig::default();
+        assert!((config.noise_bandwidth() - 1.5f32).abs() < 1e-3);
+        // in the finer bins of a padded transform
+        let padded = AnalysisConfig { zero_padding: 2, ..config };
+        assert!((padded.noise_bandwidth() - 3f32).abs() < 1e-3);
+    }
+
+    #[test]
+    fn spectral_peak_passes_over_nan() {
+        assert_eq!(spectral_peak(&[0f32, f32::NAN, 1f32, 0.5f32], 10f32), 20f32);
+        assert_eq!(spectral_peak(&[], 10f32), 0f32);
+    }
+
+    fn sine(hz: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| 0.5f32 * (2f32 * PI * hz * i as f32 / sample_rate as f32).sin()).collect()
+    }
+
+    #[test]
+    fn sine_centroid_is_its_frequency() {
+        let config = AnalysisConfig::default();
+        let mut stft = Stft::new(config);
+        let mut extractor = FeatureExtractor::new(&config, 44100);
+        for &hz in &[440f32, 1000f32, 5000f32] {
+            let frame = sine(hz, 44100, config.fft_size);
+            let features = extractor.process(0f64, &frame, stft.process(&frame));
+            assert!((features.centroid_hz - hz).abs() < hz * 0.02, "{} Hz: {}", hz, features.centroid_hz);
+        }
+    }
+
+    #[test]
+    fn noise_is_flat_and_a_tone_is_not() {
+        let config = AnalysisConfig::default();
+        let mut stft = Stft::new(config);
+        let mut extractor = FeatureExtractor::new(&config, 44100);
+        // a single frame of noise has a ragged spectrum, so average the
+        // power over many
+        let mut state = 1u32;
+        let mut power = vec![0f32; config.num_bins()];
+        for _ in 0..64 {
+            let frame: Vec<f32> = (0..config.fft_size).map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            }).collect();
+            for (sum, m) in power.iter_mut().zip(stft.process(&frame)) {
+                *sum += m * m / 64f32;
+            }
+        }
+        let magnitudes: Vec<f32> = power.iter().map(|p| p.sqrt()).collect();
+        let noise = extractor.process(0f64, &[], &magnitudes);
+        assert!(noise.flatness > 0.9, "{}", noise.flatness);
+
+        let frame = sine(1000f32, 44100, config.fft_size);
+        let tone = extractor.process(0f64, &frame, stft.process(&frame));
+        assert!(tone.flatness < 0.01, "{}", tone.flatness);
+    }
+
+    #[test]
+    fn square_wave_crosses_zero_every_half_period() {
+        let config = AnalysisConfig::default();
+        let mut extractor = FeatureExtractor::new(&config, 44100);
+        // 4 samples up, 4 down: a crossing every 4 samples
+        let frame: Vec<f32> = (0..2048).map(|i| if (i / 4) % 2 == 0 { 0.5f32 } else { -0.5f32 }).collect();
+        let features = extractor.process(0f64, &frame, &[0f32; 1025]);
+        assert_eq!(features.zero_crossing_rate, 511f32 / 2047f32);
+    }
+
+}
@@ -0,0 +1,263 @@
+// Quietest level reported for a band, in dB
+pub const FLOOR_DB: f32 = -120f32;
+
+// Audible range that the band layouts cover
+const MIN_HZ: f32 = 20f32;
+const MAX_HZ: f32 = 20000f32;
+
+// How the spectrum is divided into bands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandScale {
+    // Standard octave bands, centred on 1 kHz * 2^n
+    Octave,
+    // Standard third-octave bands, centred on 1 kHz * 2^(n/3)
+    ThirdOctave,
+    // The given number of overlapping triangular bands, evenly spaced in mels
+    Mel(usize),
+    // The given number of bands of equal width on a log scale between two
+    // frequencies in Hz
+    Log(usize, f32, f32)
+}
+
+impl BandScale {
+    // Parses "octave", "third-octave", "mel[:count]" or "log:count[:min:max]"
+    pub fn from_name(name: &str) -> Option<BandScale> {
+        let parts: Vec<&str> = name.split(':').collect();
+        let count = |text: &str| text.parse::<usize>().ok().filter(|&count| count > 0);
+        match &parts[..] {
+            ["octave"] => Some(BandScale::Octave),
+            ["third-octave"] => Some(BandScale::ThirdOctave),
+            ["mel"] => Some(BandScale::Mel(40)),
+            ["mel", n] => count(n).map(BandScale::Mel),
+            ["log", n] => count(n).map(|n| BandScale::Log(n, MIN_HZ, MAX_HZ)),
+            ["log", n, min, max] => match (count(n), min.parse::<f32>(), max.parse::<f32>()) {
+                (Some(n), Ok(min), Ok(max)) if 0f32 < min && min < max => Some(BandScale::Log(n, min, max)),
+                _ => None
+            },
+            _ => None
+        }
+    }
+}
+
+// One band, by its edges and centre in Hz
+#[derive(Clone, Copy, Debug)]
+pub struct Band {
+    pub low: f32,
+    pub centre: f32,
+    pub high: f32
+}
+
+/*
+Groups the bins of a magnitude spectrum into bands and measures the energy
+in each. Every band is a weighted sum over bins: flat for the octave and
+log scales, triangular for mel. Levels are in dB, where a full scale sine
+wave inside a band reads about 0 dB whatever the analysis window. Flat
+bands never share a bin, so the narrowest low ones are left out when the
+bins are too coarse for them.
+*/
+pub struct BandAnalyzer {
+    // (bin, weight) pairs making up every band, and its centre in Hz
+    weights: Vec<Vec<(usize, f32)>>,
+    centres: Vec<f32>,
+    // divides out the window's spread of a pure tone over several bins
+    normalization: f32
+}
+
+impl BandAnalyzer {
+    // Lays out `scale` over spectra of `num_bins` bins, each `bin_width` Hz
+    // wide. `noise_bandwidth` is the analysis window's equivalent noise
+    // bandwidth in bins (see `AnalysisConfig::noise_bandwidth`).
+    pub fn new(scale: BandScale, sample_rate: u32, num_bins: usize, bin_width: f32,
+        noise_bandwidth: f32) -> BandAnalyzer {
+        let nyquist = sample_rate as f32 / 2f32;
+        let bands: Vec<Band> = layout(scale, nyquist.min(MAX_HZ)).into_iter()
+            .filter(|band| band.centre < nyquist)
+            .collect();
+        let bin_hz = |bin: usize| bin as f32 * bin_width;
+        let nearest = |band: &Band| ((band.centre / bin_width).round() as usize).min(num_bins - 1);
+        let (bands, weights): (Vec<Band>, Vec<Vec<(usize, f32)>>) = bands.into_iter().filter_map(|band| {
+            let weights: Vec<(usize, f32)> = match scale {
+                BandScale::Mel(_) => {
+                    let mut weights: Vec<(usize, f32)> = (0..num_bins).filter_map(|bin| {
+                        let weight = triangle(&band, bin_hz(bin));
+                        if weight > 0f32 { Some((bin, weight)) } else { None }
+                    }).collect();
+                    // narrow low triangles can fall between bins, so fall
+                    // back on the bin nearest their centre; they overlap
+                    // their neighbours anyway
+                    if weights.is_empty() {
+                        weights.push((nearest(&band), 1f32));
+                    }
+                    weights
+                },
+                _ => (0..num_bins)
+                    .filter(|&bin| bin_hz(bin) >= band.low && bin_hz(bin) < band.high)
+                    .map(|bin| (bin, 1f32))
+                    .collect()
+            };
+            // a flat band too narrow to hold a bin is left out rather than
+            // made to share one with its neighbour
+            if weights.is_empty() {
+                return None;
+            }
+            Some((band, weights))
+        }).unzip();
+        let centres = bands.iter().map(|band| band.centre).collect();
+        BandAnalyzer { weights, centres, normalization: 1f32 / noise_bandwidth }
+    }
+
+    pub fn centres(&self) -> &[f32] {
+        &self.centres
+    }
+
+    // Writes the level of every band in dB into `levels`
+    pub fn levels(&self, magnitudes: &[f32], levels: &mut Vec<f32>) {
+        levels.clear();
+        for weights in &self.weights {
+            let energy: f32 = weights.iter()
+                .map(|&(bin, weight)| weight * magnitudes[bin] * magnitudes[bin])
+                .sum();
+            levels.push(to_db(energy * self.normalization));
+        }
+    }
+}
+
+// Converts an energy (a squared amplitude) to dB, clamped to `FLOOR_DB`
+pub fn to_db(energy: f32) -> f32 {
+    (10f32 * energy.log10()).max(FLOOR_DB)
+}
+
+fn layout(scale: BandScale, max_hz: f32) -> Vec<Band> {
+    match scale {
+        BandScale::Octave => fractional_octaves(1f32, max_hz),
+        BandScale::ThirdOctave => fractional_octaves(3f32, max_hz),
+        BandScale::Mel(count) => {
+            // neighbouring triangles share edges, so count + 2 points are needed
+            let low = hz_to_mel(MIN_HZ);
+            let high = hz_to_mel(max_hz);
+            let points: Vec<f32> = (0..count + 2)
+                .map(|i| mel_to_hz(low + (high - low) * i as f32 / (count + 1) as f32))
+                .collect();
+            points.windows(3).map(|p| Band { low: p[0], centre: p[1], high: p[2] }).collect()
+        },
+        BandScale::Log(count, min_hz, max_hz) => {
+            let ratio = (max_hz / min_hz).powf(1f32 / count as f32);
+            (0..count).map(|i| {
+                let low = min_hz * ratio.powi(i as i32);
+                Band { low, centre: low * ratio.sqrt(), high: low * ratio }
+            }).collect()
+        }
+    }
+}
+
+// Bands of 1/`fraction` of an octave centred on 1 kHz * 2^(n/fraction)
+fn fractional_octaves(fraction: f32, max_hz: f32) -> Vec<Band> {
+    let half_width = 2f32.powf(1f32 / (2f32 * fraction));
+    let first = (fraction * (MIN_HZ / 1000f32).log2()).ceil() as i32;
+    let last = (fraction * (max_hz / 1000f32).log2()).floor() as i32;
+    (first..last + 1).map(|n| {
+        let centre = 1000f32 * 2f32.powf(n as f32 / fraction);
+        Band { low: centre / half_width, centre, high: centre * half_width }
+    }).collect()
+}
+
+fn triangle(band: &Band, hz: f32) -> f32 {
+    if hz <= band.low || hz >= band.high {
+        0f32
+    } else if hz <= band.centre {
+        (hz - band.low) / (band.centre - band.low)
+    } else {
+        (band.high - hz) / (band.high - band.centre)
+    }
+}
+
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595f32 * (1f32 + hz / 700f32).log10()
+}
+
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700f32 * (10f32.powf(mel / 2595f32) - 1f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio::analysis::*;
+    use std::f32::consts::PI;
+
+    // The centres bands are labelled with, from IEC 61260
+    const OCTAVE_CENTRES: [f32; 10] = [31.5, 63., 125., 250., 500., 1000., 2000., 4000., 8000., 16000.];
+    const THIRD_OCTAVE_CENTRES: [f32; 29] = [25., 31.5, 40., 50., 63., 80., 100., 125., 160., 200., 250.,
+        315., 400., 500., 630., 800., 1000., 1250., 1600., 2000., 2500., 3150., 4000., 5000., 6300.,
+        8000., 10000., 12500., 16000.];
+
+    fn check_layout(scale: BandScale, nominal: &[f32], fraction: f32) {
+        let bands = layout(scale, MAX_HZ);
+        assert_eq!(bands.len(), nominal.len(), "{:?}", scale);
+        for (band, &centre) in bands.iter().zip(nominal) {
+            assert!((band.centre / centre - 1f32).abs() < 0.02, "{:?}: {} for {}", scale, band.centre, centre);
+            let width = (band.high / band.low).log2();
+            assert!((width - 1f32 / fraction).abs() < 1e-4, "{:?} at {}: {}", scale, centre, width);
+            assert!((band.centre * band.centre / (band.low * band.high) - 1f32).abs() < 1e-4);
+        }
+        // each band starts where the one below ends
+        for pair in bands.windows(2) {
+            assert!((pair[0].high / pair[1].low - 1f32).abs() < 1e-4, "{:?}", scale);
+        }
+    }
+
+    #[test]
+    fn octave_bands_match_their_nominal_centres() {
+        check_layout(BandScale::Octave, &OCTAVE_CENTRES, 1f32);
+        check_layout(BandScale::ThirdOctave, &THIRD_OCTAVE_CENTRES, 3f32);
+    }
+
+    #[test]
+    fn mels_convert_back_to_themselves() {
+        for &hz in &[0f32, 20f32, 440f32, 1000f32, 8000f32, 20000f32] {
+            let mel = hz_to_mel(hz);
+            assert!((hz_to_mel(mel_to_hz(mel)) - mel).abs() <= mel * 1e-5, "{} Hz", hz);
+            assert!((mel_to_hz(mel) - hz).abs() <= hz * 1e-4 + 1e-3, "{} Hz", hz);
+        }
+        // the scale is pinned to 1000 mels at 1 kHz
+        assert!((hz_to_mel(1000f32) - 1000f32).abs() < 0.1);
+    }
+
+    #[test]
+    fn flat_bands_never_share_a_bin() {
+        let config = AnalysisConfig::default();
+        for &scale in &[BandScale::Octave, BandScale::ThirdOctave, BandScale::Log(24, MIN_HZ, MAX_HZ)] {
+            let analyzer = BandAnalyzer::new(scale, 44100, config.num_bins(), config.bin_width(44100),
+                config.noise_bandwidth());
+            let mut uses = vec![0; config.num_bins()];
+            for weights in &analyzer.weights {
+                for &(bin, _) in weights {
+                    uses[bin] += 1;
+                }
+            }
+            assert!(uses.iter().all(|&count| count <= 1), "{:?}", scale);
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_reads_0_db_in_its_band() {
+        for &zero_padding in &[1, 2] {
+            for &window in &[WindowFunction::Hann, WindowFunction::BlackmanHarris] {
+                let config = AnalysisConfig { window, zero_padding, ..AnalysisConfig::default() };
+                let frame: Vec<f32> = (0..config.fft_size)
+                    .map(|i| (2f32 * PI * 1000f32 * i as f32 / 44100f32).sin())
+                    .collect();
+                let magnitudes = Stft::new(config).process(&frame).to_vec();
+                for &scale in &[BandScale::Octave, BandScale::ThirdOctave] {
+                    let analyzer = BandAnalyzer::new(scale, 44100, config.num_bins(),
+                        config.bin_width(44100), config.noise_bandwidth());
+                    let mut levels = Vec::new();
+                    analyzer.levels(&magnitudes, &mut levels);
+                    let band = analyzer.centres().iter().position(|&centre| centre == 1000f32).unwrap();
+                    assert!(levels[band].abs() < 0.5, "{:?} {:?} x{}: {} dB", scale, window, zero_padding,
+                        levels[band]);
+                }
+            }
+        }
+    }
+}
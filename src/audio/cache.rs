@@ -0,0 +1,511 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use super::analysis::*;
+use super::bands::*;
+use super::channels::*;
+use super::chroma::*;
+use super::envelope::*;
+use super::hpss::*;
+use super::loudness::*;
+use super::onset::*;
+use super::structure::*;
+use super::tempo::*;
+use super::track::*;
+use super::waveform::*;
+
+// Marks a file as a cache entry, and the layout of what follows. Bump the
+// version whenever anything stored changes shape or meaning, so old entries
+// are ignored rather than misread.
+const MAGIC: &[u8; 4] = b"MVAC";
+const FORMAT_VERSION: u32 = 4;
+const EXTENSION: &str = "analysis";
+// Entries kept in a cache directory; saving another removes the oldest
+// beyond this, and anything left half written for longer than an hour
+const MAX_ENTRIES: usize = 256;
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/*
+64-bit FNV-1a hash. Unlike the standard library's hasher its output is fixed,
+so keys stay the same from one build to the next.
+*/
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Identifies the analysis of one file's contents with one configuration.
+// Renaming or moving the file keeps its key; editing it or changing any
+// setting the analysis depends on gives a new one.
+pub fn cache_key(filename: &str, config: &AnalysisConfig, mode: ChannelMode) -> io::Result<u64> {
+    let mut hasher = Fnv::new();
+    let mut file = BufReader::new(File::open(filename)?);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    let mut settings = Vec::new();
+    FORMAT_VERSION.encode(&mut settings);
+    encode_settings(config, mode, &mut settings);
+    hasher.write(&settings);
+    Ok(hasher.finish())
+}
+
+// The settings a track analysis depends on, in a fixed order. Those only
+// the live analysis and the export use, like the pitch range or the layers
+// described on their own, are left out, so changing them keeps the entry.
+fn encode_settings(config: &AnalysisConfig, mode: ChannelMode, out: &mut Vec<u8>) {
+    config.fft_size.encode(out);
+    config.hop_size.encode(out);
+    config.zero_padding.encode(out);
+    match config.window {
+        WindowFunction::Rectangular => 0u8.encode(out),
+        WindowFunction::Hann => 1u8.encode(out),
+        WindowFunction::Hamming => 2u8.encode(out),
+        WindowFunction::BlackmanHarris => 3u8.encode(out),
+        WindowFunction::Kaiser(beta) => (4u8, beta).encode(out)
+    }
+    // the onset flux is measured over the bands
+    match config.bands {
+        BandScale::Octave => 0u8.encode(out),
+        BandScale::ThirdOctave => 1u8.encode(out),
+        BandScale::Mel(count) => (2u8, count).encode(out),
+        BandScale::Log(count, min_hz, max_hz) => ((3u8, count), (min_hz, max_hz)).encode(out)
+    }
+    let onsets = &config.onsets;
+    (onsets.per_band as u8).encode(out);
+    layer_tag(onsets.layer).encode(out);
+    onsets.threshold.encode(out);
+    onsets.window_secs.encode(out);
+    onsets.min_interval_secs.encode(out);
+    // the separation only matters when onsets are found in one of its layers
+    if onsets.layer != Layer::Mix {
+        config.hpss.harmonic_secs.encode(out);
+        config.hpss.percussive_hz.encode(out);
+    }
+    // the envelope gives the loudest moment of every signal
+    let envelope = &config.envelope;
+    let detector: u8 = match envelope.detector {
+        Detector::Rms => 0,
+        Detector::Peak => 1
+    };
+    detector.encode(out);
+    envelope.attack_secs.encode(out);
+    envelope.release_secs.encode(out);
+    let mode: u8 = match mode {
+        ChannelMode::PerChannel => 0,
+        ChannelMode::Mono => 1,
+        ChannelMode::MidSide => 2
+    };
+    mode.encode(out);
+}
+
+fn layer_tag(layer: Layer) -> u8 {
+    match layer {
+        Layer::Mix => 0,
+        Layer::Harmonic => 1,
+        Layer::Percussive => 2
+    }
+}
+
+/*
+A directory of track analyses saved from earlier runs, one file per key.
+Entries are checked when loaded, and anything unreadable, from another
+format version or stored under the wrong key is treated as missing. Only
+the `MAX_ENTRIES` saved most recently are kept. Nothing else is kept in
+the directory, so deleting it clears the cache.
+*/
+pub struct AnalysisCache {
+    dir: PathBuf
+}
+
+impl AnalysisCache {
+    pub fn new(dir: &Path) -> AnalysisCache {
+        AnalysisCache { dir: dir.to_path_buf() }
+    }
+
+    // $XDG_CACHE_HOME/music-visualizer, falling back on ~/.cache and then
+    // the temporary directory
+    pub fn default_dir() -> PathBuf {
+        let base = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(env::temp_dir);
+        base.join("music-visualizer")
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", key, EXTENSION))
+    }
+
+    pub fn load(&self, key: u64) -> Option<TrackAnalysis> {
+        let bytes = fs::read(self.path(key)).ok()?;
+        let mut input = Decoder { bytes: &bytes };
+        if input.take(MAGIC.len())? != MAGIC || u32::decode(&mut input)? != FORMAT_VERSION
+            || u64::decode(&mut input)? != key {
+            return None;
+        }
+        let analysis = TrackAnalysis::decode(&mut input)?;
+        // trailing bytes mean the entry is not what it claims to be
+        if input.bytes.is_empty() { Some(analysis) } else { None }
+    }
+
+    // Saves an entry, replacing any old one in a single step so readers
+    // never see it half written
+    pub fn store(&self, key: u64, analysis: &TrackAnalysis) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        FORMAT_VERSION.encode(&mut out);
+        key.encode(&mut out);
+        analysis.encode(&mut out);
+        let partial = self.dir.join(format!("{:016x}.partial", key));
+        File::create(&partial)?.write_all(&out)?;
+        fs::rename(&partial, self.path(key))?;
+        self.prune(MAX_ENTRIES)
+    }
+
+    // Removes all but the `keep` entries saved most recently, and entries
+    // left half written by runs that ended while saving them
+    fn prune(&self, keep: usize) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let modified = fs::metadata(&path)?.modified()?;
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(EXTENSION) => entries.push((modified, path)),
+                Some("partial") => {
+                    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+                    if age > PARTIAL_MAX_AGE {
+                        fs::remove_file(&path)?;
+                    }
+                },
+                _ => {}
+            }
+        }
+        // newest first
+        entries.sort_by_key(|&(modified, _)| ::std::cmp::Reverse(modified));
+        for (_, path) in entries.into_iter().skip(keep) {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+}
+
+// A value that can be written into a cache entry and read back, in a fixed
+// little-endian layout
+trait CacheData: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(input: &mut Decoder) -> Option<Self>;
+}
+
+macro_rules! cache_number {
+    ($($t:ty),*) => {$(
+        impl CacheData for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(input: &mut Decoder) -> Option<$t> {
+                let mut bytes = [0u8; ::std::mem::size_of::<$t>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(input.take(len)?);
+                Some(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*}
+}
+
+cache_number!(u8, u32, u64, f32, f64);
+
+impl CacheData for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> Option<usize> {
+        u64::decode(input).map(|n| n as usize)
+    }
+}
+
+impl<T: CacheData> CacheData for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Some(ref value) => {
+                1u8.encode(out);
+                value.encode(out);
+            },
+            None => 0u8.encode(out)
+        }
+    }
+
+    fn decode(input: &mut Decoder) -> Option<Option<T>> {
+        match u8::decode(input)? {
+            0 => Some(None),
+            1 => T::decode(input).map(Some),
+            _ => None
+        }
+    }
+}
+
+impl<T: CacheData> CacheData for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for value in self {
+            value.encode(out);
+        }
+    }
+
+    fn decode(input: &mut Decoder) -> Option<Vec<T>> {
+        let len = usize::decode(input)?;
+        // a corrupt length must not be trusted to size the allocation
+        let mut values = Vec::with_capacity(len.min(input.bytes.len()));
+        for _ in 0..len {
+            values.push(T::decode(input)?);
+        }
+        Some(values)
+    }
+}
+
+impl<A: CacheData, B: CacheData> CacheData for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> Option<(A, B)> {
+        Some((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+// Stores a struct as its fields in order
+macro_rules! cache_struct {
+    ($name:ident { $($field:ident),* }) => {
+        impl CacheData for $name {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $(self.$field.encode(out);)*
+            }
+
+            fn decode(input: &mut Decoder) -> Option<$name> {
+                Some($name { $($field: CacheData::decode(input)?),* })
+            }
+        }
+    }
+}
+
+impl CacheData for Mode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let tag: u8 = match *self {
+            Mode::Major => 0,
+            Mode::Minor => 1
+        };
+        tag.encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> Option<Mode> {
+        match u8::decode(input)? {
+            0 => Some(Mode::Major),
+            1 => Some(Mode::Minor),
+            _ => None
+        }
+    }
+}
+
+cache_struct!(Loudness { integrated_lufs, range_lu, max_momentary_lufs, max_short_term_lufs, true_peak_dbtp });
+cache_struct!(OnsetEvent { time_secs, strength, band });
+cache_struct!(BeatGrid { bpm, downbeat_secs, beats_per_bar });
+cache_struct!(Key { tonic, mode, correlation });
+cache_struct!(KeySection { start_secs, end_secs, key });
+cache_struct!(Segment { start_secs, end_secs, label });
+cache_struct!(WaveformBucket { min, max, rms });
+cache_struct!(WaveformLevel { frames_per_bucket, signals });
+cache_struct!(WaveformPyramid { sample_rate, frames, levels });
+cache_struct!(TrackAnalysis { loudness, onsets, beat_grid, key, key_sections, segments, loudest, waveform });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // An analysis with a bit of everything, and `segments` sections
+    fn analysis(segments: usize) -> TrackAnalysis {
+        let key = Key { tonic: 9, mode: Mode::Minor, correlation: 0.75 };
+        TrackAnalysis {
+            loudness: Loudness {
+                integrated_lufs: -14f32,
+                range_lu: 6.5,
+                max_momentary_lufs: -8f32,
+                max_short_term_lufs: -10f32,
+                true_peak_dbtp: -0.3
+            },
+            onsets: vec![
+                OnsetEvent { time_secs: 0.5, strength: 2f32, band: None },
+                OnsetEvent { time_secs: 1.25, strength: 1.5, band: Some(3) }
+            ],
+            beat_grid: Some(BeatGrid { bpm: 128f64, downbeat_secs: 0.1, beats_per_bar: 4 }),
+            key: Some(key),
+            key_sections: vec![
+                KeySection { start_secs: 0f64, end_secs: 10f64, key },
+                KeySection { start_secs: 2.5, end_secs: 12.5, key: Key { tonic: 0, mode: Mode::Major, ..key } }
+            ],
+            segments: (0..segments).map(|i| Segment { start_secs: i as f64, end_secs: i as f64 + 1f64, label: i % 2 })
+                .collect(),
+            loudest: vec![(1.5, -3f32), (2.5, f32::NEG_INFINITY)],
+            waveform: WaveformPyramid {
+                sample_rate: 44100,
+                frames: 300,
+                levels: vec![WaveformLevel {
+                    frames_per_bucket: 256,
+                    signals: vec![vec![WaveformBucket { min: -0.5, max: 0.5, rms: 0.25 }; 2]]
+                }]
+            }
+        }
+    }
+
+    // A cache in a fresh temporary directory
+    fn temp_cache(name: &str) -> AnalysisCache {
+        let dir = env::temp_dir().join(format!("final_proj_cache_{}", name));
+        fs::remove_dir_all(&dir).ok();
+        AnalysisCache::new(&dir)
+    }
+
+    #[test]
+    fn analysis_round_trips() {
+        for &analysis in &[&analysis(3), &TrackAnalysis { beat_grid: None, key: None, ..analysis(0) }] {
+            let mut out = Vec::new();
+            analysis.encode(&mut out);
+            let mut input = Decoder { bytes: &out };
+            assert_eq!(TrackAnalysis::decode(&mut input).as_ref(), Some(analysis));
+            assert!(input.bytes.is_empty());
+        }
+        let cache = temp_cache("round_trip");
+        cache.store(42, &analysis(3)).unwrap();
+        assert_eq!(cache.load(42), Some(analysis(3)));
+        assert_eq!(cache.load(43), None);
+        fs::remove_dir_all(&cache.dir).ok();
+    }
+
+    #[test]
+    fn damaged_entries_are_missing() {
+        let cache = temp_cache("damaged");
+        cache.store(7, &analysis(3)).unwrap();
+        let bytes = fs::read(cache.path(7)).unwrap();
+        let rewrite = |bytes: &[u8]| fs::write(cache.path(7), bytes).unwrap();
+
+        rewrite(&bytes[..bytes.len() - 1]);
+        assert_eq!(cache.load(7), None);
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        rewrite(&trailing);
+        assert_eq!(cache.load(7), None);
+        let mut version = bytes.clone();
+        version[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        rewrite(&version);
+        assert_eq!(cache.load(7), None);
+        // an entry filed under a key it wasn't stored with
+        fs::write(cache.path(8), &bytes).unwrap();
+        assert_eq!(cache.load(8), None);
+
+        rewrite(&bytes);
+        assert_eq!(cache.load(7), Some(analysis(3)));
+        fs::remove_dir_all(&cache.dir).ok();
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_entries() {
+        let cache = temp_cache("prune");
+        for key in 0..4 {
+            cache.store(key, &analysis(1)).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        // a half written entry from a run still saving it
+        fs::write(cache.dir.join("0000000000000009.partial"), b"MVAC").unwrap();
+        cache.prune(2).unwrap();
+        let kept: Vec<bool> = (0..4).map(|key| cache.path(key).exists()).collect();
+        assert_eq!(kept, vec![false, false, true, true]);
+        assert!(cache.dir.join("0000000000000009.partial").exists());
+        fs::remove_dir_all(&cache.dir).ok();
+    }
+
+    #[test]
+    fn keys_follow_the_settings_the_analysis_uses() {
+        let path = env::temp_dir().join("final_proj_cache_key.wav");
+        fs::write(&path, b"not really audio, but hashed all the same").unwrap();
+        let filename = path.to_str().unwrap();
+        let key = |config: &AnalysisConfig, mode: ChannelMode| cache_key(filename, config, mode).unwrap();
+        let config = AnalysisConfig::default();
+        let base = key(&config, ChannelMode::Mono);
+        assert_eq!(key(&config, ChannelMode::Mono), base);
+
+        let mut changed = Vec::new();
+        for &(name, value) in &[("fft-size", "4096"), ("hop-size", "256"), ("window", "kaiser:6"),
+                                ("window", "kaiser:8"), ("bands", "mel:20"), ("onset-threshold", "2"),
+                                ("onset-per-band", "yes"), ("onset-layer", "percussive"),
+                                ("envelope-release", "0.5")] {
+            let mut other = config;
+            other.set_option(name, value).unwrap();
+            changed.push(key(&other, ChannelMode::Mono));
+        }
+        changed.push(key(&config, ChannelMode::MidSide));
+        assert!(!changed.contains(&base));
+        // every change gives a key of its own
+        let mut distinct = changed.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), changed.len());
+
+        for &(name, value) in &[("layers", "harmonic,percussive"), ("pitch-range", "80:800"),
+                                ("hpss-harmonic-secs", "0.4")] {
+            let mut other = config;
+            other.set_option(name, value).unwrap();
+            assert_eq!(key(&other, ChannelMode::Mono), base, "--{} {}", name, value);
+        }
+        // unless onsets are found in a separated layer
+        let mut layered = config;
+        layered.set_option("onset-layer", "harmonic").unwrap();
+        let mut longer = layered;
+        longer.set_option("hpss-harmonic-secs", "0.4").unwrap();
+        assert_ne!(key(&layered, ChannelMode::Mono), key(&longer, ChannelMode::Mono));
+        fs::remove_file(&path).ok();
+    }
+
+}
@@ -0,0 +1,98 @@
+/*
+How the channels of a file are turned into the signals we analyse.
+Feeding interleaved frames straight into the FFT mixes left and right
+samples inside every window, so analysis always goes through here first.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelMode {
+    // Every channel is analysed on its own
+    PerChannel,
+    // All channels averaged into one signal
+    Mono,
+    // (L + R) / 2 and (L - R) / 2, taken from the first two channels
+    MidSide
+}
+
+impl ChannelMode {
+    pub fn from_name(name: &str) -> Option<ChannelMode> {
+        match name {
+            "per-channel" => Some(ChannelMode::PerChannel),
+            "mono" => Some(ChannelMode::Mono),
+            "mid-side" => Some(ChannelMode::MidSide),
+            _ => None
+        }
+    }
+
+    // Number of signals this mode produces for a file with `channels` channels
+    pub fn signal_count(self, channels: usize) -> usize {
+        match self {
+            ChannelMode::PerChannel => channels,
+            ChannelMode::Mono => 1,
+            ChannelMode::MidSide => 2
+        }
+    }
+
+    // Writes the value of every signal for one interleaved frame into `out`
+    pub fn split_frame(self, frame: &[f32], out: &mut [f32]) {
+        match self {
+            ChannelMode::PerChannel => out.copy_from_slice(frame),
+            ChannelMode::Mono => out[0] = downmix(frame),
+            ChannelMode::MidSide => {
+                let (left, right) = stereo_pair(frame);
+                out[0] = (left + right) / 2f32;
+                out[1] = (left - right) / 2f32;
+            }
+        }
+    }
+}
+
+fn downmix(frame: &[f32]) -> f32 {
+    frame.iter().sum::<f32>() / frame.len() as f32
+}
+
+// Mono files are treated as a centred stereo pair
+fn stereo_pair(frame: &[f32]) -> (f32, f32) {
+    if frame.len() >= 2 {
+        (frame[0], frame[1])
+    } else {
+        (frame[0], frame[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(mode: ChannelMode, frame: &[f32]) -> Vec<f32> {
+        let mut out = vec![0f32; mode.signal_count(frame.len())];
+        mode.split_frame(frame, &mut out);
+        out
+    }
+
+    #[test]
+    fn mono_averages_every_channel() {
+        assert_eq!(split(ChannelMode::Mono, &[0.5, -0.25]), vec![0.125]);
+        assert_eq!(split(ChannelMode::Mono, &[0.25, 0.5, 0.75, -0.5]), vec![0.25]);
+        assert_eq!(split(ChannelMode::Mono, &[0.75]), vec![0.75]);
+    }
+
+    #[test]
+    fn mid_side_of_a_stereo_pair() {
+        assert_eq!(split(ChannelMode::MidSide, &[0.5, -0.25]), vec![0.125, 0.375]);
+        // a centred sound has no side, and opposite channels no mid
+        assert_eq!(split(ChannelMode::MidSide, &[0.5, 0.5]), vec![0.5, 0f32]);
+        assert_eq!(split(ChannelMode::MidSide, &[0.5, -0.5]), vec![0f32, 0.5]);
+        // only the first two of more channels count
+        assert_eq!(split(ChannelMode::MidSide, &[0.5, -0.25, 1.0, 1.0]), vec![0.125, 0.375]);
+    }
+
+    #[test]
+    fn mid_side_of_mono_is_all_mid() {
+        assert_eq!(split(ChannelMode::MidSide, &[0.75]), vec![0.75, 0f32]);
+    }
+
+    #[test]
+    fn per_channel_passes_frames_through() {
+        assert_eq!(split(ChannelMode::PerChannel, &[0.5, -0.25, 1.0]), vec![0.5, -0.25, 1.0]);
+    }
+}
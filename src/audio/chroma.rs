@@ -0,0 +1,257 @@
+use std::fmt;
+use super::pitch::*;
+
+// Range of frequencies folded into pitch classes; above it there is mostly
+// percussion. The bottom is raised further wherever the bins are too coarse
+// to tell neighbouring semitones apart.
+const MIN_HZ: f32 = 55f32;
+const MAX_HZ: f32 = 5000f32;
+
+// Krumhansl-Kessler key profiles: how well each pitch class, counted in
+// semitones from the tonic, fits a major or minor key
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+// The energy of every pitch class (C, C#, ... B) in one frame, scaled so
+// the strongest is 1. Silent frames are all 0.
+pub type Chroma = [f32; 12];
+
+/*
+Folds the bins of a magnitude spectrum into the twelve pitch classes, each
+bin going to the class of the equal tempered note nearest its frequency.
+*/
+pub struct ChromaAnalyzer {
+    // (bin, pitch class) of every bin in range
+    classes: Vec<(usize, usize)>
+}
+
+impl ChromaAnalyzer {
+    pub fn new(num_bins: usize, bin_width: f32) -> ChromaAnalyzer {
+        // a semitone above this is wider than a bin
+        let resolved_hz = bin_width / (2f32.powf(1f32 / 12f32) - 1f32);
+        let min_hz = MIN_HZ.max(resolved_hz);
+        let classes = (1..num_bins).filter_map(|bin| {
+            let hz = bin as f32 * bin_width;
+            if !(min_hz..=MAX_HZ).contains(&hz) {
+                return None;
+            }
+            Some((bin, Note::from_frequency(hz).midi.rem_euclid(12) as usize))
+        }).collect();
+        ChromaAnalyzer { classes }
+    }
+
+    pub fn chroma(&self, magnitudes: &[f32]) -> Chroma {
+        let mut chroma = [0f32; 12];
+        for &(bin, class) in &self.classes {
+            chroma[class] += magnitudes[bin] * magnitudes[bin];
+        }
+        let max = chroma.iter().cloned().fold(0f32, f32::max);
+        if max > 0f32 {
+            for energy in &mut chroma {
+                *energy /= max;
+            }
+        }
+        chroma
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Major,
+    Minor
+}
+
+// A musical key, with how well the music matched its profile (the
+// correlation, from -1 to 1)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key {
+    // pitch class of the tonic, 0 for C up to 11 for B
+    pub tonic: usize,
+    pub mode: Mode,
+    pub correlation: f32
+}
+
+// e.g. "F# minor"
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor"
+        };
+        write!(f, "{} {}", NOTE_NAMES[self.tonic], mode)
+    }
+}
+
+// The key whose profile, turned to start on its tonic, correlates best with
+// `chroma`. Returns None for silence, which fits every key equally.
+pub fn estimate_key(chroma: &Chroma) -> Option<Key> {
+    let mut best: Option<Key> = None;
+    for tonic in 0..12 {
+        for &(mode, profile) in &[(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)] {
+            let rotated: Vec<f32> = (0..12).map(|class| profile[(class + 12 - tonic) % 12]).collect();
+            let correlation = pearson(chroma, &rotated)?;
+            let better = match best {
+                Some(key) => correlation > key.correlation,
+                None => true
+            };
+            if better {
+                best = Some(Key { tonic, mode, correlation });
+            }
+        }
+    }
+    best
+}
+
+fn pearson(a: &[f32], b: &[f32]) -> Option<f32> {
+    let n = a.len() as f32;
+    let (mean_a, mean_b) = (a.iter().sum::<f32>() / n, b.iter().sum::<f32>() / n);
+    let (mut covariance, mut var_a, mut var_b) = (0f32, 0f32, 0f32);
+    for (&x, &y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= 0f32 || var_b <= 0f32 {
+        return None;
+    }
+    Some(covariance / (var_a * var_b).sqrt())
+}
+
+// The key of one stretch of a track
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySection {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub key: Key
+}
+
+/*
+The chroma of a whole file frame by frame, taken from a mono mix of its
+channels.
+*/
+pub struct Chromagram {
+    // time of the first frame's centre, and the time between frames
+    pub start_secs: f64,
+    pub frame_secs: f64,
+    pub frames: Vec<Chroma>
+}
+
+impl Chromagram {
+    pub fn time_secs(&self, frame: usize) -> f64 {
+        self.start_secs + frame as f64 * self.frame_secs
+    }
+
+    // The key of the whole track
+    pub fn key(&self) -> Option<Key> {
+        estimate_key(&self.sum(0, self.frames.len()))
+    }
+
+    // The keys of sections `section_secs` long starting every `hop_secs`,
+    // skipping any that are silent. The last section is cut short at the
+    // end of the track.
+    pub fn section_keys(&self, section_secs: f64, hop_secs: f64) -> Vec<KeySection> {
+        let section = ((section_secs / self.frame_secs).round() as usize).max(1);
+        let hop = ((hop_secs / self.frame_secs).round() as usize).max(1);
+        let mut sections = Vec::new();
+        let mut start = 0;
+        while start < self.frames.len() {
+            let end = (start + section).min(self.frames.len());
+            if let Some(key) = estimate_key(&self.sum(start, end)) {
+                sections.push(KeySection {
+                    start_secs: self.time_secs(start) - self.frame_secs / 2f64,
+                    end_secs: self.time_secs(end) - self.frame_secs / 2f64,
+                    key
+                });
+            }
+            if end == self.frames.len() {
+                break;
+            }
+            start += hop;
+        }
+        sections
+    }
+
+    fn sum(&self, start: usize, end: usize) -> Chroma {
+        let mut total = [0f32; 12];
+        for chroma in &self.frames[start..end] {
+            for (sum, &energy) in total.iter_mut().zip(chroma) {
+                *sum += energy;
+            }
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio::analysis::*;
+    use std::f32::consts::PI;
+
+    // A chroma with the given pitch classes at full strength
+    fn notes(classes: &[usize]) -> Chroma {
+        let mut chroma = [0f32; 12];
+        for &class in classes {
+            chroma[class] = 1f32;
+        }
+        chroma
+    }
+
+    // The minor profile turned to start on A
+    fn a_minor() -> Chroma {
+        let mut chroma = [0f32; 12];
+        for (class, energy) in chroma.iter_mut().enumerate() {
+            *energy = MINOR_PROFILE[(class + 12 - 9) % 12] / MINOR_PROFILE[0];
+        }
+        chroma
+    }
+
+    #[test]
+    fn c_major_triad_is_c_major() {
+        let key = estimate_key(&notes(&[0, 4, 7])).unwrap();
+        assert_eq!((key.tonic, key.mode), (0, Mode::Major));
+        assert_eq!(key.to_string(), "C major");
+    }
+
+    #[test]
+    fn rotated_profile_is_its_own_key() {
+        let key = estimate_key(&a_minor()).unwrap();
+        assert_eq!((key.tonic, key.mode), (9, Mode::Minor));
+        assert!((key.correlation - 1f32).abs() < 1e-5, "{}", key.correlation);
+    }
+
+    #[test]
+    fn silence_has_no_key() {
+        assert_eq!(estimate_key(&[0f32; 12]), None);
+    }
+
+    #[test]
+    fn sections_skip_silence_and_stop_at_the_end() {
+        let mut frames = vec![notes(&[0, 4, 7]); 4];
+        frames.extend(vec![[0f32; 12]; 4]);
+        frames.extend(vec![a_minor(); 3]);
+        let chromagram = Chromagram { start_secs: 0.25, frame_secs: 0.5, frames };
+        let sections = chromagram.section_keys(2f64, 2f64);
+        assert_eq!(sections.len(), 2);
+        assert_eq!((sections[0].start_secs, sections[0].end_secs), (0f64, 2f64));
+        assert_eq!((sections[0].key.tonic, sections[0].key.mode), (0, Mode::Major));
+        // the silent stretch from 2 s to 4 s is left out, and the last
+        // section only has three frames
+        assert_eq!((sections[1].start_secs, sections[1].end_secs), (4f64, 5.5f64));
+        assert_eq!((sections[1].key.tonic, sections[1].key.mode), (9, Mode::Minor));
+    }
+
+    #[test]
+    fn sine_at_440_hz_is_an_a() {
+        let config = AnalysisConfig::default();
+        let analyzer = ChromaAnalyzer::new(config.num_bins(), config.bin_width(44100));
+        let frame: Vec<f32> = (0..config.fft_size)
+            .map(|i| 0.5f32 * (2f32 * PI * 440f32 * i as f32 / 44100f32).sin())
+            .collect();
+        let chroma = analyzer.chroma(Stft::new(config).process(&frame));
+        assert_eq!(chroma[9], 1f32);
+        for (class, &energy) in chroma.iter().enumerate().filter(|&(class, _)| class != 9) {
+            assert!(energy < 0.1, "class {} at {}", class, energy);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::Instant;
+
+/*
+The position of the audio actually leaving the speakers, shared between the
+audio callback and any number of readers without locks. Every callback
+records which track frames it just handed to the device and when the first
+of them will reach the DAC. Readers extrapolate from that anchor using the
+time since, so the position moves smoothly between callbacks and stops
+where the last buffer of audio ends (on pause, underrun or end of track).
+
+The fields are published with a sequence counter: the callback is the only
+writer and never waits, and a reader that races with an update simply
+reads again.
+*/
+pub struct PlaybackClock {
+    sample_rate: f64,
+    // reference point for the nanosecond timestamps below
+    epoch: Instant,
+    // odd while the callback is in the middle of an update
+    sequence: AtomicU64,
+    // track frame of the first sample in the latest device buffer
+    start_frame: AtomicU64,
+    // track frame just past the last sample of real audio in that buffer
+    end_frame: AtomicU64,
+    // when `start_frame` reaches the DAC, in nanoseconds since `epoch`
+    dac_nanos: AtomicU64,
+    // the stream's reported output latency in seconds (as f64 bits), used
+    // when the host doesn't provide DAC times in the callback
+    output_latency: AtomicU64
+}
+
+// A consistent view of the clock's fields
+#[derive(Clone, Copy)]
+struct Anchor {
+    start_frame: u64,
+    end_frame: u64,
+    dac_nanos: u64
+}
+
+impl PlaybackClock {
+    pub fn new(sample_rate: u32) -> PlaybackClock {
+        PlaybackClock {
+            sample_rate: sample_rate as f64,
+            epoch: Instant::now(),
+            sequence: AtomicU64::new(0),
+            start_frame: AtomicU64::new(0),
+            end_frame: AtomicU64::new(0),
+            dac_nanos: AtomicU64::new(0),
+            output_latency: AtomicU64::new(0f64.to_bits())
+        }
+    }
+
+    // Called from the audio callback after it fills a buffer holding track
+    // frames `start_frame` up to `end_frame`. `dac_delay` is how long until
+    // the buffer is heard (`buffer_dac - current` from the callback's time info).
+    pub fn update(&self, start_frame: u64, end_frame: u64, dac_delay: f64) {
+        let delay = if dac_delay > 0f64 {
+            dac_delay
+        } else {
+            f64::from_bits(self.output_latency.load(Ordering::Relaxed))
+        };
+        let dac_nanos = self.now_nanos() + (delay * 1e9) as u64;
+        self.publish(Anchor {
+            start_frame,
+            end_frame: end_frame.max(start_frame),
+            dac_nanos
+        });
+    }
+
+    pub fn set_output_latency(&self, secs: f64) {
+        self.output_latency.store(secs.to_bits(), Ordering::Relaxed);
+    }
+
+    // The track position being heard right now, in frames
+    pub fn position_frames(&self) -> f64 {
+        let anchor = self.read();
+        let since = (self.now_nanos() as f64 - anchor.dac_nanos as f64) / 1e9;
+        let frame = anchor.start_frame as f64 + since * self.sample_rate;
+        frame.min(anchor.end_frame as f64).max(0f64)
+    }
+
+    // The track position being heard right now, in seconds
+    pub fn position_secs(&self) -> f64 {
+        self.position_frames() / self.sample_rate
+    }
+
+    // Only ever called from the audio callback, so writes never overlap
+    fn publish(&self, anchor: Anchor) {
+        // make the counter odd, store, then make it even again
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.start_frame.store(anchor.start_frame, Ordering::Relaxed);
+        self.end_frame.store(anchor.end_frame, Ordering::Relaxed);
+        self.dac_nanos.store(anchor.dac_nanos, Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    fn read(&self) -> Anchor {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                continue;
+            }
+            let anchor = Anchor {
+                start_frame: self.start_frame.load(Ordering::Relaxed),
+                end_frame: self.end_frame.load(Ordering::Relaxed),
+                dac_nanos: self.dac_nanos.load(Ordering::Relaxed)
+            };
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return anchor;
+            }
+        }
+    }
+
+    fn now_nanos(&self) -> u64 {
+        let elapsed = self.epoch.elapsed();
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn position_follows_updates() {
+        let clock = PlaybackClock::new(44100);
+        // playing: somewhere in the buffer, just past its start
+        clock.update(1000, 1000 + 44100, 0f64);
+        let position = clock.position_frames();
+        assert!((1000f64..1000f64 + 4410f64).contains(&position), "{}", position);
+        // paused: no audio past the start, so it stays put
+        clock.update(5000, 5000, 0f64);
+        assert_eq!(clock.position_frames(), 5000f64);
+        assert_eq!(clock.position_secs(), 5000f64 / 44100f64);
+        // seeking back
+        clock.update(200, 200, 0f64);
+        assert_eq!(clock.position_frames(), 200f64);
+    }
+
+    #[test]
+    fn readers_never_see_a_torn_update() {
+        let clock = Arc::new(PlaybackClock::new(44100));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..2).map(|_| {
+            let (clock, done) = (clock.clone(), done.clone());
+            thread::spawn(move || {
+                let mut last = 0;
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) || reads == 0 {
+                    let anchor = clock.read();
+                    assert_eq!(anchor.end_frame, 2 * anchor.start_frame);
+                    assert_eq!(anchor.dac_nanos, 3 * anchor.start_frame);
+                    assert!(anchor.start_frame >= last);
+                    last = anchor.start_frame;
+                    reads += 1;
+                }
+            })
+        }).collect();
+        for frame in 1..200000u64 {
+            clock.publish(Anchor { start_frame: frame, end_frame: 2 * frame, dac_nanos: 3 * frame });
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use super::clock::*;
+
+// Requests sent from the controller to the decoder thread
+pub enum DecoderCommand {
+    // Restart decoding at `frame` (in output frames), tagging new chunks with `generation`
+    Seek { generation: u64, frame: u64 },
+    Stop
+}
+
+/*
+State shared by the controller, the decoder thread and the audio callback.
+Every seek bumps the generation, and anything decoded for an older
+generation is thrown away by the callback instead of being played.
+*/
+pub struct PlaybackState {
+    pub paused: AtomicBool,
+    pub stopped: AtomicBool,
+    pub generation: AtomicU64,
+    // output frame just past the last one handed to the device
+    pub position: AtomicU64
+}
+
+impl PlaybackState {
+    pub fn new() -> PlaybackState {
+        PlaybackState {
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            position: AtomicU64::new(0)
+        }
+    }
+}
+
+/*
+Handle for controlling a running playback from any thread. Times are in
+seconds of the track; positions are tracked in frames at the rate the
+device was opened with. The reported position comes from the playback
+clock, so it is what is being heard rather than what was last decoded.
+*/
+#[derive(Clone)]
+pub struct PlaybackController {
+    state: Arc<PlaybackState>,
+    command_tx: Sender<DecoderCommand>,
+    clock: Arc<PlaybackClock>,
+    sample_rate: u32,
+    duration_frames: Option<u64>
+}
+
+impl PlaybackController {
+    pub fn new(state: Arc<PlaybackState>, command_tx: Sender<DecoderCommand>,
+        clock: Arc<PlaybackClock>, sample_rate: u32, duration_frames: Option<u64>)
+        -> PlaybackController {
+        PlaybackController { state, command_tx, clock, sample_rate, duration_frames }
+    }
+
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    // Ends playback; the audio thread finishes as if the track had ended
+    pub fn stop(&self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        self.command_tx.send(DecoderCommand::Stop).ok();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.stopped.load(Ordering::SeqCst)
+    }
+
+    // Jumps to an absolute time, clamped to the track. Returns the time
+    // actually seeked to; the clock catches up once the device asks for more.
+    pub fn seek_to(&self, secs: f64) -> f64 {
+        let mut frame = (secs.max(0f64) * self.sample_rate as f64) as u64;
+        if let Some(duration) = self.duration_frames {
+            frame = frame.min(duration);
+        }
+        let generation = self.state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        // the callback reports this position until the new audio arrives
+        self.state.position.store(frame, Ordering::SeqCst);
+        self.command_tx.send(DecoderCommand::Seek { generation, frame }).ok();
+        frame as f64 / self.sample_rate as f64
+    }
+
+    // Jumps forwards or backwards relative to the current position
+    pub fn seek_by(&self, delta_secs: f64) -> f64 {
+        self.seek_to(self.position_secs() + delta_secs)
+    }
+
+    // The position being heard right now
+    pub fn position_secs(&self) -> f64 {
+        self.clock.position_secs()
+    }
+
+    pub fn clock(&self) -> Arc<PlaybackClock> {
+        self.clock.clone()
+    }
+
+    // Length of the track, when the container records it
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.duration_frames.map(|frames| frames as f64 / self.sample_rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn seeks_are_clamped_to_the_track() {
+        let (command_tx, command_rx) = channel();
+        let clock = Arc::new(PlaybackClock::new(1000));
+        let controller = PlaybackController::new(Arc::new(PlaybackState::new()), command_tx,
+            clock.clone(), 1000, Some(10000));
+        // hearing 2 s in, and holding there
+        clock.update(2000, 2000, 0f64);
+        assert_eq!(controller.position_secs(), 2f64);
+        assert_eq!(controller.duration_secs(), Some(10f64));
+
+        assert_eq!(controller.seek_by(-5f64), 0f64);
+        assert_eq!(controller.seek_by(20f64), 10f64);
+        assert_eq!(controller.seek_by(1.5f64), 3.5f64);
+        assert_eq!(controller.seek_to(-1f64), 0f64);
+        let seeks: Vec<(u64, u64)> = command_rx.try_iter().map(|command| match command {
+            DecoderCommand::Seek { generation, frame } => (generation, frame),
+            DecoderCommand::Stop => panic!("unexpected stop")
+        }).collect();
+        assert_eq!(seeks, vec![(1, 0), (2, 10000), (3, 3500), (4, 0)]);
+    }
+
+    #[test]
+    fn seeks_without_a_duration_only_stop_at_zero() {
+        let (command_tx, _command_rx) = channel();
+        let clock = Arc::new(PlaybackClock::new(1000));
+        let controller = PlaybackController::new(Arc::new(PlaybackState::new()), command_tx,
+            clock, 1000, None);
+        assert_eq!(controller.seek_by(-3f64), 0f64);
+        assert_eq!(controller.seek_by(600f64), 600f64);
+    }
+}
@@ -0,0 +1,216 @@
+use hound;
+use portaudio;
+
+/*
+The format samples are stored in before we normalize them. Integer formats
+carry their bit depth since FLAC and WAV allow anything from 8 to 32 bits.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Int(u16),
+    Float
+}
+
+impl SampleFormat {
+    // Maps a WAV header onto one of the formats we can decode
+    pub fn from_wav(spec: &hound::WavSpec) -> Option<SampleFormat> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, bits) if (1..=32).contains(&bits) => Some(SampleFormat::Int(bits)),
+            (hound::SampleFormat::Float, 32) => Some(SampleFormat::Float),
+            _ => None
+        }
+    }
+}
+
+// Normalizes a signed integer sample of the given bit depth into [-1, 1).
+// 8-bit WAV data is unsigned on disk, but hound already recenters it.
+pub fn int_to_f32(sample: i32, bits: u16) -> f32 {
+    (sample as f64 / (1i64 << (bits - 1)) as f64) as f32
+}
+
+pub fn i16_to_f32(sample: i16) -> f32 {
+    int_to_f32(sample as i32, 16)
+}
+
+/*
+Sample types we can hand to a PortAudio output stream. Conversion from
+normalized f32 clamps to full scale so hot masters clip instead of wrapping.
+*/
+pub trait DeviceSample: portaudio::Sample + Copy + Send + 'static {
+    fn from_normalized(sample: f32) -> Self;
+}
+
+fn scale_to_int(sample: f32, bits: u32) -> i64 {
+    let full_scale = (1i64 << (bits - 1)) as f64;
+    let scaled = (sample as f64 * full_scale).round();
+    scaled.clamp(-full_scale, full_scale - 1.0) as i64
+}
+
+impl DeviceSample for f32 {
+    fn from_normalized(sample: f32) -> f32 {
+        sample.clamp(-1f32, 1f32)
+    }
+}
+
+impl DeviceSample for i32 {
+    fn from_normalized(sample: f32) -> i32 {
+        scale_to_int(sample, 32) as i32
+    }
+}
+
+impl DeviceSample for i16 {
+    fn from_normalized(sample: f32) -> i16 {
+        scale_to_int(sample, 16) as i16
+    }
+}
+
+impl DeviceSample for i8 {
+    fn from_normalized(sample: f32) -> i8 {
+        scale_to_int(sample, 8) as i8
+    }
+}
+
+impl DeviceSample for u8 {
+    fn from_normalized(sample: f32) -> u8 {
+        (scale_to_int(sample, 8) + 128) as u8
+    }
+}
+
+// The sample types we are willing to open an output stream with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceFormat {
+    F32,
+    I32,
+    I16,
+    U8
+}
+
+impl DeviceFormat {
+    // The device format that keeps all of the precision of the source
+    pub fn preferred_for(format: SampleFormat) -> DeviceFormat {
+        match format {
+            SampleFormat::Float => DeviceFormat::F32,
+            SampleFormat::Int(bits) if bits > 16 => DeviceFormat::I32,
+            SampleFormat::Int(bits) if bits > 8 => DeviceFormat::I16,
+            SampleFormat::Int(_) => DeviceFormat::U8
+        }
+    }
+
+    // Formats to try opening the device with, best first
+    pub fn candidates_for(format: SampleFormat) -> Vec<DeviceFormat> {
+        let preferred = DeviceFormat::preferred_for(format);
+        let mut candidates = vec![preferred];
+        for &fallback in &[DeviceFormat::F32, DeviceFormat::I32, DeviceFormat::I16] {
+            if fallback != preferred {
+                candidates.push(fallback);
+            }
+        }
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio::source::open_source;
+    use audio::stream::for_each_chunk;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn int_samples_normalize_to_full_scale() {
+        for &bits in &[8u16, 16, 24, 32] {
+            let max = ((1i64 << (bits - 1)) - 1) as i32;
+            let min = (-(1i64 << (bits - 1))) as i32;
+            assert_eq!(int_to_f32(min, bits), -1f32);
+            assert!(int_to_f32(max, bits) <= 1f32);
+            assert!(int_to_f32(max, bits) > 0.99f32);
+            assert_eq!(int_to_f32(0, bits), 0f32);
+        }
+    }
+
+    #[test]
+    fn device_conversion_clamps() {
+        assert_eq!(i16::from_normalized(2f32), i16::MAX);
+        assert_eq!(i16::from_normalized(-2f32), i16::MIN);
+        assert_eq!(i32::from_normalized(1f32), i32::MAX);
+        assert_eq!(i8::from_normalized(-1f32), i8::MIN);
+        assert_eq!(u8::from_normalized(0f32), 128);
+        assert_eq!(u8::from_normalized(-1f32), 0);
+        assert_eq!(f32::from_normalized(1.5f32), 1f32);
+    }
+
+    #[test]
+    fn int_round_trip_through_device_formats() {
+        for &sample in &[-32768i16, -1234, 0, 1, 32767] {
+            assert_eq!(i16::from_normalized(i16_to_f32(sample)), sample);
+        }
+        for &sample in &[-128i32, -5, 0, 127] {
+            assert_eq!(i8::from_normalized(int_to_f32(sample, 8)) as i32, sample);
+        }
+    }
+
+    #[test]
+    fn preferred_device_format_keeps_precision() {
+        assert_eq!(DeviceFormat::preferred_for(SampleFormat::Int(8)), DeviceFormat::U8);
+        assert_eq!(DeviceFormat::preferred_for(SampleFormat::Int(16)), DeviceFormat::I16);
+        assert_eq!(DeviceFormat::preferred_for(SampleFormat::Int(24)), DeviceFormat::I32);
+        assert_eq!(DeviceFormat::preferred_for(SampleFormat::Float), DeviceFormat::F32);
+        assert_eq!(DeviceFormat::candidates_for(SampleFormat::Float),
+            vec![DeviceFormat::F32, DeviceFormat::I32, DeviceFormat::I16]);
+    }
+
+    // Writes a stereo WAV with the given format and reads it back through
+    // the same path the app uses
+    fn wav_round_trip(format: hound::SampleFormat, bits: u16, values: &[f32]) -> Vec<f32> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: bits,
+            sample_format: format
+        };
+        let path = env::temp_dir().join(format!("final_proj_convert_{:?}_{}.wav", format, bits));
+        {
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for &value in values {
+                match format {
+                    hound::SampleFormat::Float => writer.write_sample(value).unwrap(),
+                    hound::SampleFormat::Int => {
+                        let full_scale = (1i64 << (bits - 1)) as f32;
+                        let sample = (value * full_scale).round() as i64;
+                        let sample = sample.min((1i64 << (bits - 1)) - 1) as i32;
+                        writer.write_sample(sample).unwrap()
+                    }
+                }
+            }
+            writer.finalize().unwrap();
+        }
+        let mut source = open_source(path.to_str().unwrap()).unwrap();
+        assert_eq!(source.spec().channels, 2);
+        assert_eq!(source.spec().sample_rate, 8000);
+        let mut samples = Vec::new();
+        for_each_chunk(&mut *source, |chunk| samples.extend_from_slice(chunk)).unwrap();
+        fs::remove_file(&path).ok();
+        samples
+    }
+
+    #[test]
+    fn wav_formats_decode_to_f32() {
+        let values = [0f32, 0.5, -0.5, -1.0, 0.25, -0.25];
+        let cases = [
+            (hound::SampleFormat::Int, 8u16, 1f32 / 128f32),
+            (hound::SampleFormat::Int, 16, 1f32 / 32768f32),
+            (hound::SampleFormat::Int, 24, 1f32 / 8388608f32),
+            (hound::SampleFormat::Int, 32, 1e-6),
+            (hound::SampleFormat::Float, 32, 0f32)
+        ];
+        for &(format, bits, tolerance) in &cases {
+            let decoded = wav_round_trip(format, bits, &values);
+            assert_eq!(decoded.len(), values.len());
+            for (got, want) in decoded.iter().zip(values.iter()) {
+                assert!((got - want).abs() <= tolerance,
+                    "{:?} {} bits: got {}, want {}", format, bits, got, want);
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use super::analysis::*;
+use super::bands::*;
+use super::channels::*;
+use super::offline::*;
+use super::source::*;
+
+// How the level of a signal is measured in every frame, before smoothing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detector {
+    Rms,
+    Peak
+}
+
+impl Detector {
+    pub fn from_name(name: &str) -> Option<Detector> {
+        match name {
+            "rms" => Some(Detector::Rms),
+            "peak" => Some(Detector::Peak),
+            _ => None
+        }
+    }
+
+    // The level of a frame, measured over the last `hop` samples (the ones
+    // new to it)
+    pub fn level(self, frame: &[f32], hop: usize) -> f32 {
+        let new = &frame[frame.len().saturating_sub(hop)..];
+        match self {
+            Detector::Rms => (new.iter().map(|x| x * x).sum::<f32>() / new.len().max(1) as f32).sqrt(),
+            Detector::Peak => new.iter().fold(0f32, |peak, x| peak.max(x.abs()))
+        }
+    }
+}
+
+/*
+Settings for the envelope follower. Rising levels are followed with a time
+constant of `attack_secs` and falling ones with `release_secs`, so a short
+attack and a longer release give a level that jumps with the music but
+does not flicker.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvelopeConfig {
+    pub detector: Detector,
+    pub attack_secs: f32,
+    pub release_secs: f32
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> EnvelopeConfig {
+        EnvelopeConfig { detector: Detector::Rms, attack_secs: 0.01, release_secs: 0.3 }
+    }
+}
+
+// A smoothed level, as an amplitude and in dB (clamped to `FLOOR_DB`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level {
+    pub linear: f32,
+    pub db: f32
+}
+
+impl Level {
+    fn new(linear: f32) -> Level {
+        Level { linear, db: to_db(linear * linear) }
+    }
+}
+
+// The smoothed level of every analysed signal, and of every band of each
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub signals: Vec<Level>,
+    pub bands: Vec<Vec<Level>>
+}
+
+/*
+Follows the level of a set of signals and of their bands from one analysis
+frame to the next. Each frame's signal level is measured over the samples
+it adds (the last hop), and band levels come from the band analysis.
+*/
+pub struct EnvelopeFollower {
+    detector: Detector,
+    // how much of the previous level is kept every frame, for rising and
+    // falling levels
+    attack: f32,
+    release: f32,
+    // samples new to every frame
+    hop: usize,
+    envelope: Envelope
+}
+
+impl EnvelopeFollower {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> EnvelopeFollower {
+        let frame_secs = config.hop_size as f32 / sample_rate as f32;
+        let keep = |secs: f32| if secs > 0f32 { (-frame_secs / secs).exp() } else { 0f32 };
+        EnvelopeFollower {
+            detector: config.envelope.detector,
+            attack: keep(config.envelope.attack_secs),
+            release: keep(config.envelope.release_secs),
+            hop: config.hop_size.min(config.fft_size),
+            envelope: Envelope { signals: Vec::new(), bands: Vec::new() }
+        }
+    }
+
+    // Takes the latest frame of every signal and the band levels (in dB)
+    // of each, and returns the levels followed up to them
+    pub fn process(&mut self, frames: &[Vec<f32>], band_levels: &[Vec<f32>]) -> &Envelope {
+        let (detector, hop) = (self.detector, self.hop);
+        self.follow(frames.iter().map(|frame| detector.level(frame, hop)), band_levels)
+    }
+
+    // The same, from levels of the signals already measured with
+    // `Detector::level`
+    pub fn follow<I>(&mut self, levels: I, band_levels: &[Vec<f32>]) -> &Envelope
+        where I: ExactSizeIterator<Item = f32> {
+        let (attack, release) = (self.attack, self.release);
+        let follow = |level: &mut Level, input: f32| {
+            let keep = if input > level.linear { attack } else { release };
+            *level = Level::new(input + keep * (level.linear - input));
+        };
+
+        self.envelope.signals.resize(levels.len(), Level::new(0f32));
+        for (level, input) in self.envelope.signals.iter_mut().zip(levels) {
+            follow(level, input);
+        }
+
+        self.envelope.bands.resize(band_levels.len(), Vec::new());
+        for (levels, inputs) in self.envelope.bands.iter_mut().zip(band_levels) {
+            levels.resize(inputs.len(), Level::new(0f32));
+            for (level, &db) in levels.iter_mut().zip(inputs) {
+                follow(level, 10f32.powf(db / 20f32));
+            }
+        }
+        &self.envelope
+    }
+
+    // Lets go of the levels followed so far, e.g. after playback jumps
+    pub fn reset(&mut self) {
+        self.envelope.signals.clear();
+        self.envelope.bands.clear();
+    }
+}
+
+// Follows the envelope of every signal `mode` makes of a file, measuring
+// the frames on `threads` threads and telling `progress` how far it has
+// got. The envelope of every frame is handed to `each` along with the time
+// of the frame's centre as each batch of frames is measured, rather than
+// kept for the whole file.
+pub fn follow_envelope<F>(filename: &str, config: &AnalysisConfig, mode: ChannelMode, threads: usize,
+    progress: &mut dyn FnMut(Progress), mut each: F) -> Result<(), SourceError>
+    where F: FnMut(f64, &Envelope) {
+    let mut source = open_source(filename)?;
+    let spec = source.spec();
+    let band_analyzer = BandAnalyzer::new(config.bands, spec.sample_rate, config.num_bins(),
+        config.bin_width(spec.sample_rate), config.noise_bandwidth());
+    let band_analyzer = &band_analyzer;
+    let (detector, hop) = (config.envelope.detector, config.hop_size.min(config.fft_size));
+    let mut follower = EnvelopeFollower::new(config, spec.sample_rate);
+    let start_secs = config.centre_offset_secs(spec.sample_rate);
+    let frame_secs = config.hop_size as f64 / spec.sample_rate as f64;
+    let mut frame = 0;
+    // the level of every signal and of its bands is measured on the
+    // workers; only the smoothing has to wait for the frame before
+    analyse_frames_each(&mut *source, config, mode, threads, || {
+        move |stft: &mut Stft, signals: &[&[f32]]| {
+            let levels: Vec<f32> = signals.iter().map(|signal| detector.level(signal, hop)).collect();
+            let band_levels: Vec<Vec<f32>> = signals.iter().map(|signal| {
+                let mut levels = Vec::new();
+                band_analyzer.levels(stft.process(signal), &mut levels);
+                levels
+            }).collect();
+            (levels, band_levels)
+        }
+    }, progress, |(levels, band_levels): (Vec<f32>, Vec<Vec<f32>>)| {
+        each(start_secs + frame as f64 * frame_secs, follower.follow(levels.into_iter(), &band_levels));
+        frame += 1;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 ms frames, rising with a 100 ms time constant and falling with 500 ms
+    fn follower() -> EnvelopeFollower {
+        let config = AnalysisConfig {
+            hop_size: 441,
+            envelope: EnvelopeConfig { detector: Detector::Rms, attack_secs: 0.1, release_secs: 0.5 },
+            ..AnalysisConfig::default()
+        };
+        EnvelopeFollower::new(&config, 44100)
+    }
+
+    #[test]
+    fn attack_and_release_reach_63_percent_at_their_times() {
+        let mut follower = follower();
+        let rise = 1f32 - (-1f32).exp();
+        // a step up, followed for 100 ms
+        for _ in 0..9 {
+            follower.follow(Some(1f32).into_iter(), &[]);
+        }
+        let level = follower.follow(Some(1f32).into_iter(), &[vec![0f32]]).signals[0];
+        assert!((level.linear - rise).abs() < 1e-4, "{}", level.linear);
+        for _ in 0..500 {
+            follower.follow(Some(1f32).into_iter(), &[vec![0f32]]);
+        }
+        // then down, for 500 ms
+        let mut level = Level::new(1f32);
+        let mut band = Level::new(1f32);
+        for _ in 0..50 {
+            let envelope = follower.follow(Some(0f32).into_iter(), &[vec![-200f32]]);
+            level = envelope.signals[0];
+            band = envelope.bands[0][0];
+        }
+        assert!((level.linear - (1f32 - rise)).abs() < 1e-4, "{}", level.linear);
+        assert!((band.linear - (1f32 - rise)).abs() < 1e-4, "{}", band.linear);
+        assert!((level.db - 20f32 * (1f32 - rise).log10()).abs() < 0.01, "{}", level.db);
+    }
+
+    #[test]
+    fn detectors_measure_the_last_hop() {
+        let frame = [1f32, 1f32, 0.5f32, -0.5f32, 0.5f32, -0.5f32];
+        assert_eq!(Detector::Rms.level(&frame, 4), 0.5f32);
+        assert_eq!(Detector::Peak.level(&frame, 4), 0.5f32);
+        assert_eq!(Detector::Peak.level(&frame, 6), 1f32);
+    }
+
+    #[test]
+    fn reset_starts_from_silence() {
+        let mut follower = follower();
+        follower.follow(Some(1f32).into_iter(), &[]);
+        follower.reset();
+        let level = follower.follow(Some(0f32).into_iter(), &[]).signals[0];
+        assert_eq!(level.linear, 0f32);
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt::Display;
+use std::io;
+use std::io::Write;
+use super::analysis::*;
+use super::channels::*;
+use super::hpss::*;
+use super::live::*;
+use super::offline::*;
+use super::onset::*;
+use super::pitch::*;
+use super::source::*;
+use super::stream::*;
+
+// How exported features are laid out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    // one row per frame under a header naming every column
+    Csv,
+    // one JSON object per frame and line
+    JsonLines
+}
+
+impl ExportFormat {
+    // Parses "csv", or "jsonl" or "json" for JSON Lines
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "csv" => Some(ExportFormat::Csv),
+            "jsonl" | "json" => Some(ExportFormat::JsonLines),
+            _ => None
+        }
+    }
+
+    // The format a file name's extension suggests
+    pub fn from_path(path: &str) -> Option<ExportFormat> {
+        path.rsplit_once('.').and_then(|(_, extension)| ExportFormat::from_name(extension))
+    }
+}
+
+/*
+Runs the frame-by-frame analysis behind the visuals over a whole file and
+writes out everything it finds for every frame, as `format` lays it out,
+followed by the description of every separated layer `config` asks for.
+The frames are analysed in order on one thread, since the onsets, the
+envelope and the loudness each carry on from the frame before, and written
+as they come, so the file is never held in memory. Returns the number of
+frames written.
+*/
+pub fn export_features(filename: &str, config: &AnalysisConfig, mode: ChannelMode, format: ExportFormat,
+    out: &mut dyn Write, progress: &mut dyn FnMut(Progress)) -> Result<usize, SourceError> {
+    let mut source = open_source(filename)?;
+    let spec = source.spec();
+    let channels = spec.channels as usize;
+    let sample_rate = spec.sample_rate as f64;
+    let total_secs = spec.total_frames.map(|frames| frames as f64 / sample_rate);
+    let mut analyzer = FrameAnalyzer::new(config, channels, spec.sample_rate, mode);
+    if format == ExportFormat::Csv {
+        write_csv_header(out, mode.signal_count(channels), analyzer.band_centres(), &config.hpss.layers())?;
+    }
+
+    let mut buf = vec![0f32; CHUNK_FRAMES * channels];
+    let mut position = 0u64;
+    let mut written = 0;
+    loop {
+        let n = source.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for frame in buf[..n].chunks(channels) {
+            if let Some(result) = analyzer.push(frame) {
+                match format {
+                    ExportFormat::Csv => write_csv_row(out, &result)?,
+                    ExportFormat::JsonLines => write_json_line(out, &result)?
+                }
+                written += 1;
+            }
+        }
+        position += (n / channels) as u64;
+        progress(Progress { done_secs: position as f64 / sample_rate, total_secs });
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+// Names of the columns describing one signal, in the order they are
+// written. Separated layers have no levels of their own.
+const SPECTRAL_COLUMNS: [&str; 8] = ["peak_hz", "centroid_hz", "spread_hz", "rolloff_hz", "flatness", "flux",
+    "crest", "zero_crossing_rate"];
+const LEVEL_COLUMNS: [&str; 2] = ["rms_db", "level_db"];
+const PITCH_COLUMNS: [&str; 4] = ["pitch_hz", "pitch_confidence", "note", "note_cents"];
+
+fn write_csv_header(out: &mut dyn Write, signals: usize, band_centres: &[f32], layers: &[Layer]) -> io::Result<()> {
+    let mut columns = vec!["time_secs".to_string()];
+    let signal_columns = |columns: &mut Vec<String>, prefix: &str, levels: bool| {
+        for signal in 0..signals {
+            let names = SPECTRAL_COLUMNS.iter().chain(if levels { &LEVEL_COLUMNS[..] } else { &[] })
+                .chain(&PITCH_COLUMNS);
+            columns.extend(names.map(|name| format!("{}s{}_{}", prefix, signal, name)));
+            // numbered, as the centres of narrow low bands can round alike
+            columns.extend(band_centres.iter().enumerate()
+                .map(|(band, hz)| format!("{}s{}_band{}_{}hz_db", prefix, signal, band, hz.round())));
+        }
+    };
+    signal_columns(&mut columns, "", true);
+    columns.extend(NOTE_NAMES.iter().map(|note| format!("chroma_{}", note)));
+    columns.extend(["momentary_lufs", "short_term_lufs", "integrated_lufs", "true_peak_dbtp", "onset_strength"]
+        .iter().map(|name| name.to_string()));
+    for layer in layers {
+        let prefix = format!("{}_", layer.name());
+        signal_columns(&mut columns, &prefix, false);
+        columns.extend(NOTE_NAMES.iter().map(|note| format!("{}chroma_{}", prefix, note)));
+        columns.push(format!("{}onset_strength", prefix));
+    }
+    writeln!(out, "{}", columns.join(","))
+}
+
+// Numbers are written as briefly as they can be read back exactly. Values
+// that are missing, like the pitch of a silent frame, are left empty.
+fn write_csv_row(out: &mut dyn Write, frame: &LiveFrame) -> io::Result<()> {
+    let mut fields = vec![frame.time_secs.to_string()];
+    for signal in 0..frame.features.len() {
+        let levels = [frame.rms_db[signal], frame.envelope.signals[signal].db];
+        csv_signal_fields(&mut fields, &frame.features[signal], &levels, frame.pitches[signal], &frame.bands[signal]);
+    }
+    fields.extend(frame.chroma.iter().map(|energy| energy.to_string()));
+    let loudness = &frame.loudness;
+    fields.extend([loudness.momentary_lufs, loudness.short_term_lufs, loudness.integrated_lufs,
+        loudness.true_peak_dbtp].iter().map(|x| x.to_string()));
+    fields.push(csv_onset_strength(&frame.onsets));
+    for (_, layer) in frame.layers() {
+        for signal in 0..layer.features.len() {
+            csv_signal_fields(&mut fields, &layer.features[signal], &[], layer.pitches[signal], &layer.bands[signal]);
+        }
+        fields.extend(layer.chroma.iter().map(|energy| energy.to_string()));
+        fields.push(csv_onset_strength(&layer.onsets));
+    }
+    writeln!(out, "{}", fields.join(","))
+}
+
+fn csv_signal_fields(fields: &mut Vec<String>, features: &SpectralFeatures, levels: &[f32], pitch: Option<Pitch>,
+    bands: &[f32]) {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    fields.extend([features.peak_hz, features.centroid_hz, features.spread_hz, features.rolloff_hz,
+        features.flatness, features.flux, features.crest, features.zero_crossing_rate].iter()
+        .chain(levels).map(|x| x.to_string()));
+    fields.push(optional(pitch.map(|pitch| pitch.frequency.to_string())));
+    fields.push(optional(pitch.map(|pitch| pitch.confidence.to_string())));
+    fields.push(optional(pitch.map(|pitch| pitch.note.to_string())));
+    fields.push(optional(pitch.map(|pitch| pitch.note.cents.to_string())));
+    fields.extend(bands.iter().map(|db| db.to_string()));
+}
+
+// The strongest of a frame's onsets, or nothing when there are none
+fn csv_onset_strength(onsets: &[OnsetEvent]) -> String {
+    let strongest = onsets.iter().map(|onset| onset.strength).fold(None, |max: Option<f32>, x| {
+        Some(max.map_or(x, |max| max.max(x)))
+    });
+    strongest.map(|x| x.to_string()).unwrap_or_default()
+}
+
+fn write_json_line(out: &mut dyn Write, frame: &LiveFrame) -> io::Result<()> {
+    let signals: Vec<String> = (0..frame.features.len()).map(|signal| {
+        let levels = format!("\"rms_db\":{},\"level_db\":{},", json_number(frame.rms_db[signal]),
+            json_number(frame.envelope.signals[signal].db));
+        json_signal(&frame.features[signal], &levels, frame.pitches[signal], &frame.bands[signal])
+    }).collect();
+    // every separated layer is described under its own name
+    let layers: String = frame.layers().into_iter().map(|(layer, layer_frame)| {
+        let signals: Vec<String> = (0..layer_frame.features.len()).map(|signal| {
+            json_signal(&layer_frame.features[signal], "", layer_frame.pitches[signal], &layer_frame.bands[signal])
+        }).collect();
+        format!(",\"{}\":{{\"signals\":[{}],\"chroma\":{},\"onsets\":{}}}", layer.name(), signals.join(","),
+            json_array(&layer_frame.chroma), json_onsets(&layer_frame.onsets))
+    }).collect();
+    let loudness = &frame.loudness;
+    writeln!(out, "{{\"time_secs\":{},\"signals\":[{}],\"chroma\":{},\"loudness\":{{\"momentary_lufs\":{},\
+        \"short_term_lufs\":{},\"integrated_lufs\":{},\"true_peak_dbtp\":{}}},\"onsets\":{}{}}}",
+        json_number(frame.time_secs), signals.join(","), json_array(&frame.chroma),
+        json_number(loudness.momentary_lufs), json_number(loudness.short_term_lufs),
+        json_number(loudness.integrated_lufs), json_number(loudness.true_peak_dbtp), json_onsets(&frame.onsets),
+        layers)
+}
+
+// One signal as a JSON object, with `levels` (members ending in a comma)
+// after the spectral features
+fn json_signal(features: &SpectralFeatures, levels: &str, pitch: Option<Pitch>, bands: &[f32]) -> String {
+    let pitch = match pitch {
+        Some(pitch) => format!("{{\"hz\":{},\"confidence\":{},\"note\":\"{}\",\"cents\":{}}}",
+            json_number(pitch.frequency), json_number(pitch.confidence), pitch.note,
+            json_number(pitch.note.cents)),
+        None => "null".to_string()
+    };
+    format!("{{\"peak_hz\":{},\"centroid_hz\":{},\"spread_hz\":{},\"rolloff_hz\":{},\"flatness\":{},\
+        \"flux\":{},\"crest\":{},\"zero_crossing_rate\":{},{}\"pitch\":{},\"bands_db\":{}}}",
+        json_number(features.peak_hz), json_number(features.centroid_hz), json_number(features.spread_hz),
+        json_number(features.rolloff_hz), json_number(features.flatness), json_number(features.flux),
+        json_number(features.crest), json_number(features.zero_crossing_rate), levels, pitch, json_array(bands))
+}
+
+fn json_onsets(onsets: &[OnsetEvent]) -> String {
+    let onsets: Vec<String> = onsets.iter().map(|onset| {
+        format!("{{\"time_secs\":{},\"strength\":{},\"band\":{}}}", json_number(onset.time_secs),
+            json_number(onset.strength), onset.band.map_or("null".to_string(), |band| band.to_string()))
+    }).collect();
+    format!("[{}]", onsets.join(","))
+}
+
+// JSON has no infinities (the loudness of silence) or NaN, so they become null
+fn json_number<T: Display + Into<f64> + Copy>(x: T) -> String {
+    if x.into().is_finite() { x.to_string() } else { "null".to_string() }
+}
+
+fn json_array(values: &[f32]) -> String {
+    let values: Vec<String> = values.iter().map(|&x| json_number(x)).collect();
+    format!("[{}]", values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound;
+    use std::collections::HashSet;
+    use std::env;
+    use std::f32::consts::PI;
+    use std::fs;
+
+    // One second of 8 kHz stereo, a different tone on each side and a
+    // click halfway, written to a temporary file
+    fn write_wav(name: &str) -> String {
+        let path = env::temp_dir().join(format!("final_proj_export_{}.wav", name));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..8000 {
+            let t = i as f32 / 8000f32;
+            let click = if i == 4000 { 0.4f32 } else { 0f32 };
+            for &hz in &[440f32, 660f32] {
+                writer.write_sample((((2f32 * PI * hz * t).sin() * 0.5f32 + click) * 32767f32) as i16).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn export(name: &str, format: ExportFormat) -> (usize, String, usize) {
+        let path = write_wav(name);
+        let mut config = AnalysisConfig::default();
+        config.set_option("layers", "harmonic,percussive").unwrap();
+        let bands = FrameAnalyzer::new(&config, 2, 8000, ChannelMode::PerChannel).band_centres().len();
+        let mut out = Vec::new();
+        let written = export_features(&path, &config, ChannelMode::PerChannel, format, &mut out, &mut |_| {});
+        fs::remove_file(&path).ok();
+        (written.unwrap(), String::from_utf8(out).unwrap(), bands)
+    }
+
+    // Frames of the default size and hop in a second at 8 kHz
+    const FRAMES: usize = (8000 - 2048) / 512 + 1;
+
+    #[test]
+    fn csv_has_a_column_for_every_field() {
+        let (written, text, bands) = export("csv", ExportFormat::Csv);
+        assert_eq!(written, FRAMES);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), FRAMES + 1);
+        let header: Vec<&str> = lines[0].split(',').collect();
+        assert_eq!(header[0], "time_secs");
+        assert_eq!(header.iter().collect::<HashSet<_>>().len(), header.len());
+        for row in &lines[1..] {
+            assert_eq!(row.split(',').count(), header.len());
+        }
+        // the bands of every signal, numbered in order
+        for prefix in &["s0", "s1", "harmonic_s0", "harmonic_s1", "percussive_s0", "percussive_s1"] {
+            let numbers: Vec<&str> = header.iter()
+                .filter_map(|column| column.strip_prefix(prefix)?.strip_prefix("_band"))
+                .map(|rest| rest.split('_').next().unwrap())
+                .collect();
+            let expected: Vec<String> = (0..bands).map(|band| band.to_string()).collect();
+            assert_eq!(numbers, expected, "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn every_json_line_parses() {
+        // the checker itself turns away what JSON doesn't allow
+        for bad in &["{\"a\":1,}", "[1 2]", "{\"a\":NaN}", "[inf]", "{a:1}"] {
+            assert_ne!(json_value(bad.as_bytes(), 0), Some(bad.len()), "{}", bad);
+        }
+        let (written, text, _) = export("jsonl", ExportFormat::JsonLines);
+        assert_eq!(written, FRAMES);
+        assert_eq!(text.lines().count(), FRAMES);
+        for line in text.lines() {
+            assert_eq!(json_value(line.as_bytes(), 0), Some(line.len()), "{}", line);
+            assert!(line.starts_with("{\"time_secs\":"));
+            assert!(line.contains(",\"harmonic\":{") && line.contains(",\"percussive\":{"));
+        }
+    }
+
+    #[test]
+    fn format_follows_the_extension() {
+        assert_eq!(ExportFormat::from_path("features.csv"), Some(ExportFormat::Csv));
+        assert_eq!(ExportFormat::from_path("features.jsonl"), Some(ExportFormat::JsonLines));
+        assert_eq!(ExportFormat::from_path("out.d/features.json"), Some(ExportFormat::JsonLines));
+        // standard output, and files that say nothing
+        assert_eq!(ExportFormat::from_path("-"), None);
+        assert_eq!(ExportFormat::from_path("features"), None);
+        assert_eq!(ExportFormat::from_path("features.txt"), None);
+    }
+
+    // Checks the JSON value starting at `i`, and returns where it ends
+    fn json_value(text: &[u8], i: usize) -> Option<usize> {
+        let i = skip_space(text, i);
+        match *text.get(i)? {
+            b'{' => json_members(text, i + 1, b'}', true),
+            b'[' => json_members(text, i + 1, b']', false),
+            b'"' => json_string(text, i),
+            b't' => json_literal(text, i, b"true"),
+            b'f' => json_literal(text, i, b"false"),
+            b'n' => json_literal(text, i, b"null"),
+            _ => {
+                let end = i + text[i..].iter().take_while(|&&c| c.is_ascii_digit() || b"+-.eE".contains(&c)).count();
+                let number = ::std::str::from_utf8(&text[i..end]).ok()?;
+                number.parse::<f64>().ok().filter(|x| x.is_finite()).map(|_| end)
+            }
+        }
+    }
+
+    // The rest of an object or array after its opening bracket
+    fn json_members(text: &[u8], mut i: usize, close: u8, keys: bool) -> Option<usize> {
+        if text.get(skip_space(text, i)) == Some(&close) {
+            return Some(skip_space(text, i) + 1);
+        }
+        loop {
+            if keys {
+                i = json_string(text, skip_space(text, i))?;
+                i = skip_space(text, i);
+                if text.get(i) != Some(&b':') {
+                    return None;
+                }
+                i += 1;
+            }
+            i = skip_space(text, json_value(text, i)?);
+            match text.get(i) {
+                Some(&b',') => i += 1,
+                Some(&c) if c == close => return Some(i + 1),
+                _ => return None
+            }
+        }
+    }
+
+    fn json_string(text: &[u8], i: usize) -> Option<usize> {
+        if text.get(i) != Some(&b'"') {
+            return None;
+        }
+        let mut i = i + 1;
+        loop {
+            match *text.get(i)? {
+                b'"' => return Some(i + 1),
+                b'\\' => i += 2,
+                c if c < 0x20 => return None,
+                _ => i += 1
+            }
+        }
+    }
+
+    fn json_literal(text: &[u8], i: usize, literal: &[u8]) -> Option<usize> {
+        if text[i..].starts_with(literal) { Some(i + literal.len()) } else { None }
+    }
+
+    fn skip_space(text: &[u8], i: usize) -> usize {
+        i + text[i.min(text.len())..].iter().take_while(|c| c.is_ascii_whitespace()).count()
+    }
+}
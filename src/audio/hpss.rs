@@ -0,0 +1,251 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use super::analysis::*;
+
+// One part of the audio, as harmonic/percussive separation splits it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    // everything, unseparated
+    Mix,
+    // steady tones: sung and played notes, chords, pads
+    Harmonic,
+    // short broadband hits: drums, plucks, consonants
+    Percussive
+}
+
+impl Layer {
+    pub fn from_name(name: &str) -> Option<Layer> {
+        match name {
+            "mix" => Some(Layer::Mix),
+            "harmonic" => Some(Layer::Harmonic),
+            "percussive" => Some(Layer::Percussive),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::Mix => "mix",
+            Layer::Harmonic => "harmonic",
+            Layer::Percussive => "percussive"
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Layer::Mix => 0,
+            Layer::Harmonic => 1,
+            Layer::Percussive => 2
+        }
+    }
+}
+
+/*
+Settings for harmonic/percussive separation. Steady tones are smooth along
+time and hits are smooth across frequency, so every bin of a frame is
+compared with the median of that bin over the last `harmonic_secs` and
+with the median of the bins within `percussive_hz` around it, and shared
+between the layers by how the two compare. With `harmonic_features` or
+`percussive_features` set, the live analysis also describes that layer on
+its own.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HpssConfig {
+    pub harmonic_secs: f32,
+    pub percussive_hz: f32,
+    pub harmonic_features: bool,
+    pub percussive_features: bool
+}
+
+impl Default for HpssConfig {
+    fn default() -> HpssConfig {
+        HpssConfig {
+            harmonic_secs: 0.2,
+            percussive_hz: 500f32,
+            harmonic_features: false,
+            percussive_features: false
+        }
+    }
+}
+
+impl HpssConfig {
+    // The layers described on their own, harmonic first
+    pub fn layers(&self) -> Vec<Layer> {
+        let mut layers = Vec::new();
+        if self.harmonic_features {
+            layers.push(Layer::Harmonic);
+        }
+        if self.percussive_features {
+            layers.push(Layer::Percussive);
+        }
+        layers
+    }
+}
+
+// Number of frames, the latest included, the harmonic median runs over
+pub fn hpss_history(config: &AnalysisConfig, sample_rate: u32) -> usize {
+    let frames_per_sec = sample_rate as f32 / config.hop_size as f32;
+    ((config.hpss.harmonic_secs * frames_per_sec).round() as usize).max(1)
+}
+
+/*
+Splits the magnitude spectra of consecutive frames of one signal into
+harmonic and percussive layers by median filtering. Only frames already
+seen go into the harmonic median, so a frame is separated as soon as it
+arrives and the live analysis and the analysis of whole files agree. Each
+bin goes to the layers in proportion to the square of their medians, so
+the layers always add back up to the mix.
+*/
+pub struct HpssSeparator {
+    // the spectra the harmonic median runs over, oldest first
+    history: VecDeque<Vec<f32>>,
+    history_len: usize,
+    // bins either side of every bin in the percussive median
+    half_bins: usize,
+    values: Vec<f32>,
+    // the magnitudes of every layer, and the share of every bin it got
+    magnitudes: [Vec<f32>; 3],
+    masks: [Vec<f32>; 3]
+}
+
+impl HpssSeparator {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> HpssSeparator {
+        let bins = config.num_bins();
+        let half_bins = (config.hpss.percussive_hz / config.bin_width(sample_rate) / 2f32).round() as usize;
+        let history_len = hpss_history(config, sample_rate);
+        HpssSeparator {
+            history: VecDeque::with_capacity(history_len + 1),
+            history_len, half_bins,
+            values: Vec::with_capacity(history_len.max(2 * half_bins + 1)),
+            magnitudes: [vec![0f32; bins], vec![0f32; bins], vec![0f32; bins]],
+            masks: [vec![1f32; bins], vec![0f32; bins], vec![0f32; bins]]
+        }
+    }
+
+    // Separates the magnitude spectrum of the next frame
+    pub fn process(&mut self, magnitudes: &[f32]) {
+        let mut spectrum = if self.history.len() == self.history_len {
+            self.history.pop_front().unwrap()
+        } else {
+            Vec::with_capacity(magnitudes.len())
+        };
+        spectrum.clear();
+        spectrum.extend_from_slice(magnitudes);
+        self.history.push_back(spectrum);
+
+        let [ref mut mix, ref mut harmonic, ref mut percussive] = self.magnitudes;
+        let [_, ref mut harmonic_mask, ref mut percussive_mask] = self.masks;
+        mix.copy_from_slice(magnitudes);
+        for (bin, &magnitude) in magnitudes.iter().enumerate() {
+            self.values.clear();
+            self.values.extend(self.history.iter().map(|spectrum| spectrum[bin]));
+            let steady = median(&mut self.values);
+            self.values.clear();
+            let (low, high) = (bin.saturating_sub(self.half_bins), (bin + self.half_bins + 1).min(magnitudes.len()));
+            self.values.extend_from_slice(&magnitudes[low..high]);
+            let hits = median(&mut self.values);
+
+            let (steady, hits) = (steady * steady, hits * hits);
+            let share = if steady + hits > 0f32 { steady / (steady + hits) } else { 0.5f32 };
+            harmonic_mask[bin] = share;
+            percussive_mask[bin] = 1f32 - share;
+            harmonic[bin] = share * magnitude;
+            percussive[bin] = (1f32 - share) * magnitude;
+        }
+    }
+
+    // The magnitude spectrum of one layer of the latest frame
+    pub fn magnitudes(&self, layer: Layer) -> &[f32] {
+        &self.magnitudes[layer.index()]
+    }
+
+    // The share of every bin of the latest frame that went to one layer,
+    // from 0 to 1
+    pub fn mask(&self, layer: Layer) -> &[f32] {
+        &self.masks[layer.index()]
+    }
+
+    // Forgets the frames seen so far, e.g. after playback jumps
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+}
+
+// The middle value, or the upper of the two middle values of an even count
+fn median(values: &mut [f32]) -> f32 {
+    let middle = values.len() / 2;
+    *values.select_nth_unstable_by(middle, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    // Separates every frame of a signal, handing `each` the separator after
+    // every one
+    fn separate<F: FnMut(&HpssSeparator)>(signal: &[f32], mut each: F) {
+        let config = AnalysisConfig::default();
+        let mut stft = Stft::new(config);
+        let mut separator = HpssSeparator::new(&config, SAMPLE_RATE);
+        let mut start = 0;
+        while start + config.fft_size <= signal.len() {
+            separator.process(stft.process(&signal[start..start + config.fft_size]));
+            each(&separator);
+            start += config.hop_size;
+        }
+    }
+
+    fn sine(hz: f32, samples: usize) -> Vec<f32> {
+        (0..samples).map(|i| 0.5f32 * (2f32 * PI * hz * i as f32 / SAMPLE_RATE as f32).sin()).collect()
+    }
+
+    // Share of the magnitude of all frames that went to a layer
+    fn share(signal: &[f32], layer: Layer) -> f32 {
+        let (mut part, mut whole) = (0f32, 0f32);
+        separate(signal, |separator| {
+            part += separator.magnitudes(layer).iter().sum::<f32>();
+            whole += separator.magnitudes(Layer::Mix).iter().sum::<f32>();
+        });
+        part / whole
+    }
+
+    #[test]
+    fn layers_add_up_to_the_mix() {
+        let mut signal = sine(440f32, SAMPLE_RATE as usize);
+        let mut seed = 1u32;
+        for (i, x) in signal.iter_mut().enumerate() {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            *x += 0.1f32 * (seed >> 8) as f32 / (1u32 << 24) as f32 - 0.05f32;
+            if i % 11025 == 0 {
+                *x += 1f32;
+            }
+        }
+        separate(&signal, |separator| {
+            let mix = separator.magnitudes(Layer::Mix);
+            let layers = separator.magnitudes(Layer::Harmonic).iter().zip(separator.magnitudes(Layer::Percussive));
+            for (&magnitude, (harmonic, percussive)) in mix.iter().zip(layers) {
+                assert!((harmonic + percussive - magnitude).abs() <= 1e-5f32 * magnitude.max(1f32));
+            }
+            let masks = separator.mask(Layer::Harmonic).iter().zip(separator.mask(Layer::Percussive));
+            for (harmonic, percussive) in masks {
+                assert!((harmonic + percussive - 1f32).abs() < 1e-6f32);
+            }
+        });
+    }
+
+    #[test]
+    fn steady_sine_is_harmonic() {
+        let signal = sine(1000f32, SAMPLE_RATE as usize);
+        assert!(share(&signal, Layer::Harmonic) > 0.9f32, "{}", share(&signal, Layer::Harmonic));
+    }
+
+    #[test]
+    fn click_is_percussive() {
+        let mut signal = vec![0f32; SAMPLE_RATE as usize];
+        signal[SAMPLE_RATE as usize / 2] = 1f32;
+        assert!(share(&signal, Layer::Percussive) > 0.9f32, "{}", share(&signal, Layer::Percussive));
+    }
+}
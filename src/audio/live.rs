@@ -0,0 +1,509 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use super::analysis::*;
+use super::bands::*;
+use super::onset::*;
+use super::pitch::*;
+use super::chroma::*;
+use super::loudness::*;
+use super::envelope::*;
+use super::hpss::*;
+use super::channels::*;
+use super::ring::*;
+
+// How long the analysis thread sleeps when it has caught up with playback
+const POLL_MILLIS: u64 = 5;
+
+// The analysis of the frame of audio centred on `time_secs`
+pub struct LiveFrame {
+    pub time_secs: f64,
+    // the peak frequency of every analysed signal, in Hz
+    pub peaks: Vec<f32>,
+    // the timbre of every analysed signal
+    pub features: Vec<SpectralFeatures>,
+    // the RMS level of every analysed signal over the frame, in dB
+    pub rms_db: Vec<f32>,
+    // the level of every band of every analysed signal, in dB
+    pub bands: Vec<Vec<f32>>,
+    // the pitch of every analysed signal, if it is not silent
+    pub pitches: Vec<Option<Pitch>>,
+    // the pitch classes heard across all signals
+    pub chroma: Chroma,
+    // the smoothed level of every analysed signal and its bands
+    pub envelope: Envelope,
+    // the loudness of all the channels together, up to about this frame
+    pub loudness: LoudnessReading,
+    // onsets confirmed by this frame, across all signals
+    pub onsets: Vec<OnsetEvent>,
+    // the harmonic and percussive layers described on their own, when the
+    // analysis is set to separate them
+    pub harmonic: Option<LayerFrame>,
+    pub percussive: Option<LayerFrame>
+}
+
+impl LiveFrame {
+    // The separated layers described in this frame, harmonic first
+    pub fn layers(&self) -> Vec<(Layer, &LayerFrame)> {
+        let harmonic = self.harmonic.as_ref().map(|frame| (Layer::Harmonic, frame));
+        let percussive = self.percussive.as_ref().map(|frame| (Layer::Percussive, frame));
+        harmonic.into_iter().chain(percussive).collect()
+    }
+}
+
+// The analysis of one layer of every analysed signal of a frame
+pub struct LayerFrame {
+    pub features: Vec<SpectralFeatures>,
+    pub bands: Vec<Vec<f32>>,
+    pub pitches: Vec<Option<Pitch>>,
+    pub chroma: Chroma,
+    pub onsets: Vec<OnsetEvent>
+}
+
+/*
+The render loop's side of the live analysis. Results arrive ahead of the
+audio they describe being heard, so they are held back until the playback
+clock reaches them. Onsets from every frame passed over are collected, for
+the mix and every separated layer, so none are lost when the analysis runs
+faster than the render loop.
+*/
+pub struct LiveAnalysis {
+    frame_rx: Receiver<LiveFrame>,
+    pending: VecDeque<LiveFrame>,
+    current: Option<LiveFrame>,
+    onsets: Vec<OnsetEvent>,
+    harmonic_onsets: Vec<OnsetEvent>,
+    percussive_onsets: Vec<OnsetEvent>
+}
+
+impl LiveAnalysis {
+    // Catches up with the audio heard at `position_secs`
+    pub fn update(&mut self, position_secs: f64) {
+        while let Ok(frame) = self.frame_rx.try_recv() {
+            // results going back in time mean playback seeked backwards,
+            // so everything held so far is stale
+            if self.pending.back().is_some_and(|last| last.time_secs > frame.time_secs) {
+                self.pending.clear();
+                self.current = None;
+            }
+            self.pending.push_back(frame);
+        }
+        while self.pending.front().is_some_and(|frame| frame.time_secs <= position_secs) {
+            self.current = self.pending.pop_front();
+            if let Some(ref frame) = self.current {
+                self.onsets.extend_from_slice(&frame.onsets);
+                if let Some(ref layer) = frame.harmonic {
+                    self.harmonic_onsets.extend_from_slice(&layer.onsets);
+                }
+                if let Some(ref layer) = frame.percussive {
+                    self.percussive_onsets.extend_from_slice(&layer.onsets);
+                }
+            }
+        }
+    }
+
+    // The most recent result for audio already heard
+    pub fn current(&self) -> Option<&LiveFrame> {
+        self.current.as_ref()
+    }
+
+    // Onsets heard in `layer` since the last call for it, oldest first
+    pub fn take_onsets(&mut self, layer: Layer) -> Vec<OnsetEvent> {
+        mem::take(match layer {
+            Layer::Mix => &mut self.onsets,
+            Layer::Harmonic => &mut self.harmonic_onsets,
+            Layer::Percussive => &mut self.percussive_onsets
+        })
+    }
+}
+
+// Starts a thread that analyses the audio coming through `reader` as it is
+// played, framing every signal `mode` produces as `config` describes.
+// The thread exits once the ring's writer has gone.
+pub fn spawn_live_analysis(reader: RingReader, channels: usize, sample_rate: u32,
+    mode: ChannelMode, config: AnalysisConfig) -> (LiveAnalysis, JoinHandle<()>) {
+    let (frame_tx, frame_rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        analyse_stream(reader, frame_tx, channels, sample_rate, mode, config);
+    });
+    let analysis = LiveAnalysis {
+        frame_rx,
+        pending: VecDeque::new(),
+        current: None,
+        onsets: Vec::new(),
+        harmonic_onsets: Vec::new(),
+        percussive_onsets: Vec::new()
+    };
+    (analysis, handle)
+}
+
+fn analyse_stream(mut reader: RingReader, frame_tx: Sender<LiveFrame>, channels: usize,
+    sample_rate: u32, mode: ChannelMode, config: AnalysisConfig) {
+    let mut analyzer = FrameAnalyzer::new(&config, channels, sample_rate, mode);
+    let mut samples = Vec::new();
+    let mut track_frames = Vec::new();
+    let mut next_frame = 0;
+
+    loop {
+        // check before reading so the last frames written are still analysed
+        let closed = reader.is_closed();
+        samples.clear();
+        track_frames.clear();
+        if reader.read(&mut samples, &mut track_frames) == 0 {
+            if closed {
+                return;
+            }
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+            continue;
+        }
+
+        for (frame, &track_frame) in samples.chunks(channels).zip(&track_frames) {
+            // start framing afresh whenever playback jumps
+            if track_frame != next_frame {
+                analyzer.reset(track_frame);
+            }
+            next_frame = track_frame + 1;
+            if let Some(result) = analyzer.push(frame) {
+                if frame_tx.send(result).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/*
+Works out everything in a `LiveFrame` from audio fed to it one sample
+frame (every channel at one instant) at a time. The live analysis runs one
+over the audio as it is heard, and the feature export over a whole file,
+so both see exactly the same thing. When the configuration asks for the
+harmonic or percussive layer, every signal is also separated and the
+layer described on its own, its pitch tracked in the layer taken back to
+the time domain.
+*/
+pub struct FrameAnalyzer {
+    mode: ChannelMode,
+    sample_rate: u32,
+    centre_offset: f64,
+    num_signals: usize,
+    stft: Stft,
+    band_analyzer: BandAnalyzer,
+    pitch_tracker: YinTracker,
+    chroma_analyzer: ChromaAnalyzer,
+    meter: LoudnessMeter,
+    follower: EnvelopeFollower,
+    frames: SignalFrames,
+    extractors: Vec<FeatureExtractor>,
+    // onsets and chroma are found in the average spectrum of all the signals
+    onset_detector: OnsetDetector,
+    average: Vec<f32>,
+    average_levels: Vec<f32>,
+    frame_values: Vec<f32>,
+    // one separator for every signal when any layer is described
+    separators: Vec<HpssSeparator>,
+    layers: Vec<LayerAnalyzer>,
+    layer_frame: Vec<f32>
+}
+
+impl FrameAnalyzer {
+    pub fn new(config: &AnalysisConfig, channels: usize, sample_rate: u32, mode: ChannelMode) -> FrameAnalyzer {
+        let bin_width = config.bin_width(sample_rate);
+        let num_signals = mode.signal_count(channels);
+        let layers: Vec<LayerAnalyzer> = config.hpss.layers().into_iter()
+            .map(|layer| LayerAnalyzer::new(layer, config, sample_rate, num_signals))
+            .collect();
+        FrameAnalyzer {
+            mode, sample_rate, num_signals,
+            centre_offset: config.centre_offset_secs(sample_rate),
+            stft: Stft::new(*config),
+            band_analyzer: BandAnalyzer::new(config.bands, sample_rate, config.num_bins(),
+                bin_width, config.noise_bandwidth()),
+            pitch_tracker: YinTracker::new(config, sample_rate),
+            chroma_analyzer: ChromaAnalyzer::new(config.num_bins(), bin_width),
+            meter: LoudnessMeter::new(channels, sample_rate),
+            follower: EnvelopeFollower::new(config, sample_rate),
+            frames: SignalFrames::new(config, num_signals),
+            extractors: (0..num_signals).map(|_| FeatureExtractor::new(config, sample_rate)).collect(),
+            onset_detector: OnsetDetector::new(config, sample_rate),
+            average: vec![0f32; config.num_bins()],
+            average_levels: Vec::new(),
+            frame_values: vec![0f32; num_signals],
+            separators: if layers.is_empty() {
+                Vec::new()
+            } else {
+                (0..num_signals).map(|_| HpssSeparator::new(config, sample_rate)).collect()
+            },
+            layers,
+            layer_frame: Vec::with_capacity(config.padded_size())
+        }
+    }
+
+    // Centre frequency of every band reported, in Hz
+    pub fn band_centres(&self) -> &[f32] {
+        self.band_analyzer.centres()
+    }
+
+    // Forgets everything fed in so far and carries on from sample frame
+    // `position`, e.g. after playback jumps
+    pub fn reset(&mut self, position: u64) {
+        self.frames.reset(position);
+        self.onset_detector.reset();
+        self.meter.reset();
+        self.follower.reset();
+        for extractor in &mut self.extractors {
+            extractor.reset();
+        }
+        for separator in &mut self.separators {
+            separator.reset();
+        }
+        for layer in &mut self.layers {
+            layer.reset();
+        }
+    }
+
+    // Takes the next sample frame, and returns the analysis of the frame
+    // it completes, if any
+    pub fn push(&mut self, frame: &[f32]) -> Option<LiveFrame> {
+        self.meter.process(frame);
+        self.mode.split_frame(frame, &mut self.frame_values);
+        let start = self.frames.push(&self.frame_values)?;
+        let num_signals = self.num_signals;
+        let time_secs = start as f64 / self.sample_rate as f64 + self.centre_offset;
+        let mut peaks = Vec::with_capacity(num_signals);
+        let mut features = Vec::with_capacity(num_signals);
+        let mut rms_db = Vec::with_capacity(num_signals);
+        let mut bands = Vec::with_capacity(num_signals);
+        let mut pitches = Vec::with_capacity(num_signals);
+        self.average.fill(0f32);
+        for layer in &mut self.layers {
+            layer.start();
+        }
+        for (i, (signal, extractor)) in self.frames.frames().iter().zip(&mut self.extractors).enumerate() {
+            let magnitudes = self.stft.process(signal);
+            let signal_features = extractor.process(time_secs, signal, magnitudes);
+            peaks.push(signal_features.peak_hz);
+            features.push(signal_features);
+            rms_db.push(to_db(signal.iter().map(|x| x * x).sum::<f32>() / signal.len().max(1) as f32));
+            let mut levels = Vec::new();
+            self.band_analyzer.levels(magnitudes, &mut levels);
+            bands.push(levels);
+            pitches.push(self.pitch_tracker.process(signal));
+            for (sum, &magnitude) in self.average.iter_mut().zip(magnitudes) {
+                *sum += magnitude / num_signals as f32;
+            }
+
+            if let Some(separator) = self.separators.get_mut(i) {
+                separator.process(magnitudes);
+                for layer in &mut self.layers {
+                    self.stft.resynthesise(separator.mask(layer.layer), &mut self.layer_frame);
+                    layer.process_signal(i, time_secs, &self.layer_frame[..signal.len()],
+                        separator.magnitudes(layer.layer), &self.band_analyzer, &mut self.pitch_tracker);
+                }
+            }
+        }
+
+        let mut onsets = Vec::new();
+        self.band_analyzer.levels(&self.average, &mut self.average_levels);
+        self.onset_detector.process(time_secs, &self.average, &self.average_levels, &mut onsets);
+        let chroma = self.chroma_analyzer.chroma(&self.average);
+        let envelope = self.follower.process(self.frames.frames(), &bands).clone();
+        let mut harmonic = None;
+        let mut percussive = None;
+        for layer in &mut self.layers {
+            let frame = layer.finish(time_secs, &self.band_analyzer, &self.chroma_analyzer);
+            match layer.layer {
+                Layer::Harmonic => harmonic = Some(frame),
+                _ => percussive = Some(frame)
+            }
+        }
+        Some(LiveFrame {
+            time_secs, peaks, features, rms_db, bands, pitches, chroma, envelope, onsets, harmonic, percussive,
+            loudness: self.meter.reading()
+        })
+    }
+}
+
+/*
+Describes one separated layer of every signal, frame by frame, the same
+way `FrameAnalyzer` describes the mix. The signals of a frame are passed
+in one at a time between `start` and `finish`.
+*/
+struct LayerAnalyzer {
+    layer: Layer,
+    extractors: Vec<FeatureExtractor>,
+    onset_detector: OnsetDetector,
+    average: Vec<f32>,
+    average_levels: Vec<f32>,
+    features: Vec<SpectralFeatures>,
+    bands: Vec<Vec<f32>>,
+    pitches: Vec<Option<Pitch>>
+}
+
+impl LayerAnalyzer {
+    fn new(layer: Layer, config: &AnalysisConfig, sample_rate: u32, num_signals: usize) -> LayerAnalyzer {
+        LayerAnalyzer {
+            layer,
+            extractors: (0..num_signals).map(|_| FeatureExtractor::new(config, sample_rate)).collect(),
+            onset_detector: OnsetDetector::new(config, sample_rate),
+            average: vec![0f32; config.num_bins()],
+            average_levels: Vec::new(),
+            features: Vec::with_capacity(num_signals),
+            bands: Vec::with_capacity(num_signals),
+            pitches: Vec::with_capacity(num_signals)
+        }
+    }
+
+    fn start(&mut self) {
+        self.average.fill(0f32);
+    }
+
+    // Takes the layer of signal `signal` as samples and as a magnitude spectrum
+    fn process_signal(&mut self, signal: usize, time_secs: f64, frame: &[f32], magnitudes: &[f32],
+        band_analyzer: &BandAnalyzer, pitch_tracker: &mut YinTracker) {
+        self.features.push(self.extractors[signal].process(time_secs, frame, magnitudes));
+        let mut levels = Vec::new();
+        band_analyzer.levels(magnitudes, &mut levels);
+        self.bands.push(levels);
+        self.pitches.push(pitch_tracker.process(frame));
+        let num_signals = self.extractors.len() as f32;
+        for (sum, &magnitude) in self.average.iter_mut().zip(magnitudes) {
+            *sum += magnitude / num_signals;
+        }
+    }
+
+    fn finish(&mut self, time_secs: f64, band_analyzer: &BandAnalyzer, chroma_analyzer: &ChromaAnalyzer) -> LayerFrame {
+        let mut onsets = Vec::new();
+        band_analyzer.levels(&self.average, &mut self.average_levels);
+        self.onset_detector.process(time_secs, &self.average, &self.average_levels, &mut onsets);
+        LayerFrame {
+            features: mem::take(&mut self.features),
+            bands: mem::take(&mut self.bands),
+            pitches: mem::take(&mut self.pitches),
+            chroma: chroma_analyzer.chroma(&self.average),
+            onsets
+        }
+    }
+
+    fn reset(&mut self) {
+        self.onset_detector.reset();
+        for extractor in &mut self.extractors {
+            extractor.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn onset(time_secs: f64) -> OnsetEvent {
+        OnsetEvent { time_secs, strength: 1f32, band: None }
+    }
+
+    fn layer_frame(onsets: Vec<OnsetEvent>) -> LayerFrame {
+        LayerFrame { features: Vec::new(), bands: Vec::new(), pitches: Vec::new(), chroma: [0f32; 12], onsets }
+    }
+
+    // A frame with an onset at its centre in the mix, and ones a quarter
+    // and a half second later in the harmonic and percussive layers
+    fn frame(time_secs: f64) -> LiveFrame {
+        LiveFrame {
+            time_secs,
+            peaks: Vec::new(),
+            features: Vec::new(),
+            rms_db: Vec::new(),
+            bands: Vec::new(),
+            pitches: Vec::new(),
+            chroma: [0f32; 12],
+            envelope: Envelope { signals: Vec::new(), bands: Vec::new() },
+            loudness: LoudnessReading {
+                momentary_lufs: 0f32,
+                short_term_lufs: 0f32,
+                integrated_lufs: 0f32,
+                true_peak_dbtp: 0f32
+            },
+            onsets: vec![onset(time_secs)],
+            harmonic: Some(layer_frame(vec![onset(time_secs + 0.25)])),
+            percussive: Some(layer_frame(vec![onset(time_secs + 0.5)]))
+        }
+    }
+
+    fn live_analysis() -> (LiveAnalysis, Sender<LiveFrame>) {
+        let (frame_tx, frame_rx) = mpsc::channel();
+        let analysis = LiveAnalysis {
+            frame_rx,
+            pending: VecDeque::new(),
+            current: None,
+            onsets: Vec::new(),
+            harmonic_onsets: Vec::new(),
+            percussive_onsets: Vec::new()
+        };
+        (analysis, frame_tx)
+    }
+
+    fn current_secs(analysis: &LiveAnalysis) -> Option<f64> {
+        analysis.current().map(|frame| frame.time_secs)
+    }
+
+    fn onset_secs(onsets: Vec<OnsetEvent>) -> Vec<f64> {
+        onsets.iter().map(|onset| onset.time_secs).collect()
+    }
+
+    #[test]
+    fn frames_wait_for_playback_to_reach_them() {
+        let (mut analysis, frame_tx) = live_analysis();
+        for &time_secs in &[0.1, 0.2, 0.3] {
+            frame_tx.send(frame(time_secs)).unwrap();
+        }
+        analysis.update(0.05);
+        assert_eq!(current_secs(&analysis), None);
+        assert!(analysis.take_onsets(Layer::Mix).is_empty());
+        analysis.update(0.2);
+        assert_eq!(current_secs(&analysis), Some(0.2));
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Mix)), vec![0.1, 0.2]);
+        analysis.update(0.25);
+        assert_eq!(current_secs(&analysis), Some(0.2));
+        analysis.update(1.0);
+        assert_eq!(current_secs(&analysis), Some(0.3));
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Mix)), vec![0.3]);
+    }
+
+    #[test]
+    fn seeking_back_drops_what_was_held() {
+        let (mut analysis, frame_tx) = live_analysis();
+        for &time_secs in &[1.0, 1.1, 1.2] {
+            frame_tx.send(frame(time_secs)).unwrap();
+        }
+        analysis.update(1.05);
+        assert_eq!(current_secs(&analysis), Some(1.0));
+        analysis.take_onsets(Layer::Mix);
+
+        // playback jumps back to 0.5 with 1.1 and 1.2 still held
+        frame_tx.send(frame(0.5)).unwrap();
+        frame_tx.send(frame(0.6)).unwrap();
+        analysis.update(0.5);
+        assert_eq!(current_secs(&analysis), Some(0.5));
+        analysis.update(1.15);
+        assert_eq!(current_secs(&analysis), Some(0.6));
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Mix)), vec![0.5, 0.6]);
+    }
+
+    #[test]
+    fn onsets_are_taken_layer_by_layer() {
+        let (mut analysis, frame_tx) = live_analysis();
+        frame_tx.send(frame(1.0)).unwrap();
+        frame_tx.send(frame(2.0)).unwrap();
+        analysis.update(2.0);
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Harmonic)), vec![1.25, 2.25]);
+        // taking one layer's leaves the others alone
+        assert!(analysis.take_onsets(Layer::Harmonic).is_empty());
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Percussive)), vec![1.5, 2.5]);
+        assert_eq!(onset_secs(analysis.take_onsets(Layer::Mix)), vec![1.0, 2.0]);
+        assert!(analysis.take_onsets(Layer::Mix).is_empty());
+        assert!(analysis.take_onsets(Layer::Percussive).is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+use std::f64::consts::PI;
+use super::resample::{kaiser, sinc};
+
+// Loudness is measured over 100 ms blocks, and reported over windows of
+// 4 of them (momentary) and 30 of them (short-term)
+const BLOCKS_PER_SEC: u32 = 10;
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+// Gates of ITU-R BS.1770 and EBU Tech 3342: windows quieter than the absolute
+// gate are left out altogether, then so are windows quieter than the
+// relative gate below the loudness of what remains
+const ABSOLUTE_GATE_LUFS: f64 = -70f64;
+const INTEGRATED_RELATIVE_GATE_LU: f64 = -10f64;
+const RANGE_RELATIVE_GATE_LU: f64 = -20f64;
+// Loudness range spans these percentiles of the short-term loudness
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+// Oversampling used to find peaks between samples, and the length in
+// input samples of the interpolating filter for every phase
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS: usize = 12;
+// Rates at or above this are taken to be fine enough for sample peaks
+const TRUE_PEAK_MAX_RATE: u32 = 176400;
+
+/*
+The loudness of a whole track, after EBU R128. Levels are in LUFS (dB
+relative to a full scale, K-weighted) and are negative infinity for silence.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    pub integrated_lufs: f32,
+    // the spread between quiet and loud passages, in LU
+    pub range_lu: f32,
+    pub max_momentary_lufs: f32,
+    pub max_short_term_lufs: f32,
+    // highest level reached between samples as well as on them, in dBTP
+    pub true_peak_dbtp: f32
+}
+
+// The meter's readings at one moment, for showing as the audio plays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoudnessReading {
+    pub momentary_lufs: f32,
+    pub short_term_lufs: f32,
+    // integrated loudness of everything measured so far
+    pub integrated_lufs: f32,
+    // highest true peak since the previous reading, in dBTP
+    pub true_peak_dbtp: f32
+}
+
+// Direct form I biquad filter
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2]
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad { b, a, x: [0f64; 2], y: [0f64; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// The two stages of the K-weighting filter for any sample rate: a high
+// shelf modelling the head, then a high pass cutting the lowest bass
+fn k_weighting(sample_rate: u32) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+    let (f0, gain_db, q) = (1681.974450955533f64, 3.999843853973347f64, 0.7071752369554196f64);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20f64);
+    let vb = vh.powf(0.4996667741545416f64);
+    let a0 = 1f64 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2f64 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [1f64, 2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0]);
+
+    let (f0, q) = (38.13547087602444f64, 0.5003270373238773f64);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1f64 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1f64, -2f64, 1f64],
+        [1f64, 2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0]);
+    (shelf, high_pass)
+}
+
+// How much each channel counts towards the loudness. In 5.1 audio (L, R,
+// C, LFE, Ls, Rs) the LFE is left out and the surrounds count for more.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0f64,
+        (6, 4) | (6, 5) => 1.41f64,
+        _ => 1f64
+    }
+}
+
+fn to_lufs(mean_square: f64) -> f32 {
+    if mean_square > 0f64 {
+        (-0.691f64 + 10f64 * mean_square.log10()) as f32
+    } else {
+        f32::NEG_INFINITY
+    }
+}
+
+/*
+Finds the peaks of a channel between its samples by interpolating it onto
+a finer grid with a windowed sinc filter.
+*/
+struct TruePeak {
+    // filter taps of every phase of the oversampled output
+    phases: Vec<[f64; TRUE_PEAK_TAPS]>,
+    history: [f64; TRUE_PEAK_TAPS]
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32) -> TruePeak {
+        let oversampling = if sample_rate < TRUE_PEAK_MAX_RATE { TRUE_PEAK_OVERSAMPLING } else { 1 };
+        let half = TRUE_PEAK_TAPS as f64 / 2f64;
+        let phases = (0..oversampling).map(|phase| {
+            let mut taps = [0f64; TRUE_PEAK_TAPS];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // distance from the output point to this input sample
+                let t = k as f64 - (half - 1f64) - phase as f64 / oversampling as f64;
+                *tap = sinc(t) * kaiser(t / half, 8f64);
+            }
+            taps
+        }).collect();
+        TruePeak { phases, history: [0f64; TRUE_PEAK_TAPS] }
+    }
+
+    // Takes the next sample and returns the highest magnitude reached
+    // around it
+    fn process(&mut self, x: f64) -> f64 {
+        self.history.copy_within(1.., 0);
+        self.history[TRUE_PEAK_TAPS - 1] = x;
+        self.phases.iter().map(|taps| {
+            taps.iter().zip(&self.history).map(|(tap, x)| tap * x).sum::<f64>().abs()
+        }).fold(0f64, f64::max)
+    }
+}
+
+/*
+Loudness meter after ITU-R BS.1770 and EBU R128. Interleaved audio is fed
+in as it plays or is decoded, and the meter can be read at any time.
+*/
+pub struct LoudnessMeter {
+    channels: usize,
+    sample_rate: u32,
+    filters: Vec<(Biquad, Biquad)>,
+    peaks: Vec<TruePeak>,
+    weights: Vec<f64>,
+    block_len: usize,
+    // weighted sum of squares so far in the block being filled
+    block_sum: f64,
+    block_frames: usize,
+    // mean square of every complete block
+    blocks: Vec<f64>,
+    // integrated loudness, and the number of blocks it was worked out from
+    integrated: (usize, f32),
+    max_peak: f64,
+    recent_peak: f64
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> LoudnessMeter {
+        LoudnessMeter {
+            channels, sample_rate,
+            filters: vec![k_weighting(sample_rate); channels],
+            peaks: (0..channels).map(|_| TruePeak::new(sample_rate)).collect(),
+            weights: (0..channels).map(|channel| channel_weight(channel, channels)).collect(),
+            block_len: (sample_rate / BLOCKS_PER_SEC).max(1) as usize,
+            block_sum: 0f64,
+            block_frames: 0,
+            blocks: Vec::new(),
+            integrated: (0, f32::NEG_INFINITY),
+            max_peak: 0f64,
+            recent_peak: 0f64
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks(self.channels) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                let x = sample as f64;
+                let (ref mut shelf, ref mut high_pass) = self.filters[channel];
+                let weighted = high_pass.process(shelf.process(x));
+                self.block_sum += self.weights[channel] * weighted * weighted;
+                let peak = self.peaks[channel].process(x);
+                self.recent_peak = self.recent_peak.max(peak);
+            }
+            self.block_frames += 1;
+            if self.block_frames == self.block_len {
+                self.blocks.push(self.block_sum / self.block_len as f64);
+                self.block_sum = 0f64;
+                self.block_frames = 0;
+            }
+        }
+    }
+
+    // Forgets everything measured so far, e.g. after playback jumps
+    pub fn reset(&mut self) {
+        *self = LoudnessMeter::new(self.channels, self.sample_rate);
+    }
+
+    // Readings for the audio up to the last complete block
+    pub fn reading(&mut self) -> LoudnessReading {
+        let reading = LoudnessReading {
+            momentary_lufs: to_lufs(self.window(self.blocks.len(), MOMENTARY_BLOCKS)),
+            short_term_lufs: to_lufs(self.window(self.blocks.len(), SHORT_TERM_BLOCKS)),
+            integrated_lufs: self.integrated(),
+            true_peak_dbtp: to_dbtp(self.recent_peak)
+        };
+        self.max_peak = self.max_peak.max(self.recent_peak);
+        self.recent_peak = 0f64;
+        reading
+    }
+
+    pub fn summary(&self) -> Loudness {
+        let momentary = self.windows(MOMENTARY_BLOCKS);
+        let short_term = self.windows(SHORT_TERM_BLOCKS);
+        let max = |windows: &[f64]| to_lufs(windows.iter().cloned().fold(0f64, f64::max));
+        Loudness {
+            integrated_lufs: gated_loudness(&momentary),
+            range_lu: loudness_range(&short_term),
+            max_momentary_lufs: max(&momentary),
+            max_short_term_lufs: max(&short_term),
+            true_peak_dbtp: to_dbtp(self.max_peak.max(self.recent_peak))
+        }
+    }
+
+    // Worked out again only once there are new blocks
+    fn integrated(&mut self) -> f32 {
+        if self.integrated.0 != self.blocks.len() {
+            self.integrated = (self.blocks.len(), gated_loudness(&self.windows(MOMENTARY_BLOCKS)));
+        }
+        self.integrated.1
+    }
+
+    // Mean square over the `len` blocks ending before block `end`, or as
+    // many as there are
+    fn window(&self, end: usize, len: usize) -> f64 {
+        let blocks = &self.blocks[end.saturating_sub(len)..end];
+        if blocks.is_empty() {
+            return 0f64;
+        }
+        blocks.iter().sum::<f64>() / blocks.len() as f64
+    }
+
+    // Mean square of every complete window of `len` blocks, one per block
+    fn windows(&self, len: usize) -> Vec<f64> {
+        (len..self.blocks.len() + 1).map(|end| self.window(end, len)).collect()
+    }
+}
+
+fn to_dbtp(peak: f64) -> f32 {
+    (20f64 * peak.log10()) as f32
+}
+
+fn lufs_to_mean_square(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691f64) / 10f64)
+}
+
+// The windows that pass the absolute gate and then the relative one
+fn gate(windows: &[f64], relative_gate_lu: f64) -> Vec<f64> {
+    let absolute = lufs_to_mean_square(ABSOLUTE_GATE_LUFS);
+    let loud: Vec<f64> = windows.iter().cloned().filter(|&w| w > absolute).collect();
+    if loud.is_empty() {
+        return loud;
+    }
+    let relative = loud.iter().sum::<f64>() / loud.len() as f64 * 10f64.powf(relative_gate_lu / 10f64);
+    loud.into_iter().filter(|&w| w > relative).collect()
+}
+
+// Integrated loudness from momentary windows
+fn gated_loudness(momentary: &[f64]) -> f32 {
+    let kept = gate(momentary, INTEGRATED_RELATIVE_GATE_LU);
+    if kept.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    to_lufs(kept.iter().sum::<f64>() / kept.len() as f64)
+}
+
+// Loudness range after EBU Tech 3342, from short-term windows
+fn loudness_range(short_term: &[f64]) -> f32 {
+    let mut levels: Vec<f32> = gate(short_term, RANGE_RELATIVE_GATE_LU).into_iter().map(to_lufs).collect();
+    if levels.is_empty() {
+        return 0f32;
+    }
+    levels.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+    percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Interleaved stereo of a sine in both channels, `secs` long
+    fn stereo_sine(hz: f64, dbfs: f64, phase: f64, secs: f64, sample_rate: u32) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20f64);
+        (0..(secs * sample_rate as f64) as usize).flat_map(|i| {
+            let x = (amplitude * (2f64 * PI * hz * i as f64 / sample_rate as f64 + phase).sin()) as f32;
+            vec![x, x]
+        }).collect()
+    }
+
+    // EBU Tech 3341, test 1: a 1 kHz sine at -23 dBFS in both channels
+    #[test]
+    fn sine_at_minus_23_dbfs_reads_minus_23_lufs() {
+        for &sample_rate in &[44100, 48000] {
+            let mut meter = LoudnessMeter::new(2, sample_rate);
+            meter.process(&stereo_sine(1000f64, -23f64, 0f64, 5f64, sample_rate));
+            let reading = meter.reading();
+            let summary = meter.summary();
+            for &lufs in &[reading.momentary_lufs, reading.short_term_lufs, reading.integrated_lufs,
+                           summary.integrated_lufs, summary.max_momentary_lufs, summary.max_short_term_lufs] {
+                assert!((lufs + 23f32).abs() <= 0.1, "{} Hz: {} LUFS", sample_rate, lufs);
+            }
+            assert!(summary.range_lu.abs() <= 0.1, "{}", summary.range_lu);
+        }
+    }
+
+    #[test]
+    fn silence_is_minus_infinity_with_no_range() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.process(&vec![0f32; 2 * 48000 * 5]);
+        let summary = meter.summary();
+        assert_eq!(summary.integrated_lufs, f32::NEG_INFINITY);
+        assert_eq!(summary.range_lu, 0f32);
+        assert_eq!(meter.reading().momentary_lufs, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn true_peak_lies_between_samples() {
+        // a quarter of the sample rate, 45 degrees out, never sampled at its crest
+        let sample_rate = 48000;
+        let samples = stereo_sine(12000f64, -6f64, PI / 4f64, 1f64, sample_rate);
+        let sample_peak = 20f32 * samples.iter().fold(0f32, |peak, &x| peak.max(x.abs())).log10();
+        let mut meter = LoudnessMeter::new(2, sample_rate);
+        meter.process(&samples);
+        let true_peak = meter.summary().true_peak_dbtp;
+        assert!((sample_peak + 9.01).abs() < 0.1, "{}", sample_peak);
+        assert!(true_peak > sample_peak + 2f32, "{} vs {}", true_peak, sample_peak);
+        assert!((true_peak + 6f32).abs() < 0.5, "{}", true_peak);
+    }
+}
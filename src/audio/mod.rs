@@ -0,0 +1,215 @@
+extern crate num;
+extern crate rustfft;
+extern crate portaudio;
+
+pub mod source;
+pub mod analysis;
+pub mod bands;
+pub mod hpss;
+pub mod onset;
+pub mod tempo;
+pub mod pitch;
+pub mod chroma;
+pub mod loudness;
+pub mod envelope;
+pub mod waveform;
+pub mod structure;
+pub mod offline;
+pub mod spectrogram;
+pub mod export;
+pub mod track;
+pub mod cache;
+pub mod convert;
+pub mod channels;
+pub mod resample;
+pub mod stream;
+pub mod controller;
+pub mod clock;
+pub mod live;
+pub mod ring;
+
+pub use self::source::*;
+pub use self::analysis::*;
+pub use self::hpss::*;
+pub use self::onset::*;
+pub use self::tempo::*;
+pub use self::pitch::*;
+pub use self::chroma::*;
+pub use self::loudness::*;
+pub use self::envelope::*;
+pub use self::waveform::*;
+pub use self::structure::*;
+pub use self::offline::*;
+pub use self::spectrogram::*;
+pub use self::export::*;
+pub use self::track::*;
+pub use self::cache::*;
+pub use self::convert::*;
+pub use self::channels::*;
+pub use self::stream::*;
+pub use self::controller::*;
+pub use self::clock::*;
+pub use self::live::*;
+pub use self::ring::*;
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc;
+
+// Everything the rest of the app needs from a running playback
+pub struct PlaybackHandles {
+	pub controller: PlaybackController,
+	// analysis of the audio being played, split into signals by the channel mode
+	pub analysis: LiveAnalysis,
+	// the last second or so of audio played
+	pub samples: Arc<SampleRing>
+}
+
+// Why playback could not start or carry on
+#[derive(Debug)]
+pub enum PlaybackError {
+	Source(SourceError),
+	Device(portaudio::Error)
+}
+
+impl fmt::Display for PlaybackError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			PlaybackError::Source(ref err) => write!(f, "{}", err),
+			PlaybackError::Device(ref err) => write!(f, "audio device error: {}", err)
+		}
+	}
+}
+
+impl From<SourceError> for PlaybackError {
+	fn from(err: SourceError) -> PlaybackError {
+		PlaybackError::Source(err)
+	}
+}
+
+impl From<portaudio::Error> for PlaybackError {
+	fn from(err: portaudio::Error) -> PlaybackError {
+		PlaybackError::Device(err)
+	}
+}
+
+// Playback function. Once the stream is set up, the handles for it are
+// passed back on `ready_tx`. If it fails before then, `ready_tx` is dropped
+// without sending anything.
+pub fn playback(filename: &str, mode: ChannelMode, config: AnalysisConfig, pdone_tx: Sender<bool>,
+	ready_tx: Sender<PlaybackHandles>) -> Result<(), PlaybackError> {
+	let source = open_source(filename)?;
+	let spec = source.spec();
+
+	let pa = portaudio::PortAudio::new()?;
+	let ch = spec.channels as i32;
+
+	// Play at the file's own rate when the device allows it, otherwise at the
+	// device's default rate with the audio resampled to match
+	let sr = negotiate_sample_rate(&pa, ch, spec.sample_rate);
+	if sr != spec.sample_rate as f64 {
+		println!("Resampling from {} Hz to {} Hz", spec.sample_rate, sr);
+	}
+
+	// Decode on a separate thread, only ever a few chunks ahead of the device
+	let (mut stream_buffer, controller, decoder_thread) = spawn_decoder(source, sr as u32);
+
+	// Keep the last second of what the callback plays, and analyse it on
+	// another thread as it plays
+	let (ring_writer, samples) = sample_ring(spec.channels as usize, sr as usize);
+	stream_buffer.set_ring(ring_writer);
+	let (analysis, analysis_thread) = spawn_live_analysis(
+		SampleRing::reader(&samples), spec.channels as usize, sr as u32, mode, config);
+	ready_tx.send(PlaybackHandles { controller: controller.clone(), analysis, samples }).ok();
+
+	// Open the device in the first format it accepts, preferring one that
+	// keeps the full precision of the file
+	let format = DeviceFormat::candidates_for(spec.sample_format).into_iter()
+		.find(|&format| device_supports(&pa, format, ch, sr))
+		.unwrap_or(DeviceFormat::F32);
+	let played = match format {
+		DeviceFormat::F32 => play_stream::<f32>(&pa, ch, sr, stream_buffer, controller.clock()),
+		DeviceFormat::I32 => play_stream::<i32>(&pa, ch, sr, stream_buffer, controller.clock()),
+		DeviceFormat::I16 => play_stream::<i16>(&pa, ch, sr, stream_buffer, controller.clock()),
+		DeviceFormat::U8 => play_stream::<u8>(&pa, ch, sr, stream_buffer, controller.clock())
+	};
+
+	// The decoder waits for seeks after reaching the end, so release it
+	controller.stop();
+	if let Err(err) = decoder_thread.join().unwrap() {
+		eprintln!("Stopped decoding: {}", err);
+	}
+	analysis_thread.join().unwrap();
+
+    // Notify main that playback has ended
+    pdone_tx.send(true).ok();
+	played.map_err(PlaybackError::from)
+}
+
+fn negotiate_sample_rate(pa: &portaudio::PortAudio, ch: i32, file_rate: u32) -> f64 {
+	let file_rate = file_rate as f64;
+	if format_supported::<f32>(pa, ch, file_rate) {
+		return file_rate;
+	}
+	pa.default_output_device()
+		.and_then(|device| pa.device_info(device))
+		.map(|info| info.default_sample_rate)
+		.unwrap_or(file_rate)
+}
+
+fn device_supports(pa: &portaudio::PortAudio, format: DeviceFormat, ch: i32, sr: f64) -> bool {
+	match format {
+		DeviceFormat::F32 => format_supported::<f32>(pa, ch, sr),
+		DeviceFormat::I32 => format_supported::<i32>(pa, ch, sr),
+		DeviceFormat::I16 => format_supported::<i16>(pa, ch, sr),
+		DeviceFormat::U8 => format_supported::<u8>(pa, ch, sr)
+	}
+}
+
+fn format_supported<S: DeviceSample>(pa: &portaudio::PortAudio, ch: i32, sr: f64) -> bool {
+	match pa.default_output_stream_params::<S>(ch) {
+		Ok(params) => pa.is_output_format_supported(params, sr).is_ok(),
+		Err(_) => false
+	}
+}
+
+// Streams the decoded samples to the default output device, converting them
+// to the device sample type on the fly, and keeps `clock` in step with what
+// the device is playing. Returns once the stream has finished.
+fn play_stream<S: DeviceSample>(pa: &portaudio::PortAudio, ch: i32, sr: f64,
+	mut stream_buffer: StreamBuffer, clock: Arc<PlaybackClock>) -> Result<(), portaudio::Error> {
+	let buffer_len = 64;
+	let settings = pa.default_output_stream_settings::<S>(ch, sr, buffer_len)?;
+
+	let (complete_tx, complete_rx) = mpsc::channel();
+
+	let callback_clock = clock.clone();
+	let callback = move |portaudio::OutputStreamCallbackArgs { buffer, time, .. }| {
+        // Pass the sample data through to the output buffer
+		let start_frame = stream_buffer.position();
+		let playing = stream_buffer.fill(buffer);
+
+		// Tell the clock which frames this buffer holds and when it will be heard
+		callback_clock.update(start_frame, stream_buffer.position(), time.buffer_dac - time.current);
+
+		if playing {
+			portaudio::Continue
+		} else {
+			complete_tx.send(()).ok();
+			portaudio::Complete
+		}
+	};
+
+	let mut stream = pa.open_non_blocking_stream(settings, callback)?;
+	clock.set_output_latency(stream.info().output_latency);
+	stream.start()?;
+
+	// Wait on a Complete message from the callback. The sender only goes
+	// away early if the callback was dropped, so the stream is done then too.
+	complete_rx.recv().ok();
+	
+    // We recieved a Complete message in the loop so stop/close the stream
+    stream.stop()?;
+	stream.close()
+}
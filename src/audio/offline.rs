@@ -0,0 +1,318 @@
+use std::mem;
+use std::thread;
+use rustfft::FFTplanner;
+use super::analysis::*;
+use super::channels::*;
+use super::source::*;
+use super::stream::*;
+
+// Frames gathered from a file before being shared out between the threads.
+// One batch is analysed while the next is decoded.
+const BATCH_FRAMES: usize = 256;
+
+// How far an analysis of a whole file has got
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub done_secs: f64,
+    // length of the file, when its container tells us
+    pub total_secs: Option<f64>
+}
+
+impl Progress {
+    // Share of the file analysed so far, from 0 to 1, if its length is known
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_secs.filter(|&total| total > 0f64).map(|total| (self.done_secs / total).min(1f64))
+    }
+}
+
+// One analysis thread per core, or a single thread if that can't be found out
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/*
+Runs an analysis over every frame of a file on `threads` threads, and
+returns its result for each frame in order.
+
+Frames are shared out in runs of consecutive frames, and `analyser` is
+called on the thread handling a run to make the function that analyses it,
+which then sees the frame of every signal `mode` makes, one frame after
+another. Every run starts with the frame before it (its result is dropped),
+so anything carried from one frame to the next, such as the previous
+spectrum for the flux, is ready by the first frame that counts. Every
+thread keeps its own transform, planned with its own `FFTplanner`. The
+results are the same whatever the number of threads.
+
+`progress` is told how far the analysis has got after every batch.
+*/
+pub fn analyse_frames<T, A, M>(source: &mut dyn AudioSource, config: &AnalysisConfig, mode: ChannelMode,
+    threads: usize, analyser: M, progress: &mut dyn FnMut(Progress)) -> Result<Vec<T>, SourceError>
+    where T: Send, M: Fn() -> A + Sync, A: FnMut(&mut Stft, &[&[f32]]) -> T {
+    analyse_frames_with_history(source, config, mode, 1, threads, analyser, progress)
+}
+
+// Runs an analysis as `analyse_frames` does, but hands every frame's result
+// to `each` in order as soon as its batch is done instead of keeping them
+pub fn analyse_frames_each<T, A, M, E>(source: &mut dyn AudioSource, config: &AnalysisConfig, mode: ChannelMode,
+    threads: usize, analyser: M, progress: &mut dyn FnMut(Progress), each: E) -> Result<(), SourceError>
+    where T: Send, M: Fn() -> A + Sync, A: FnMut(&mut Stft, &[&[f32]]) -> T, E: FnMut(T) {
+    run_batches(source, config, mode, 1, threads, analyser, progress, each)
+}
+
+// Runs an analysis as `analyse_frames` does, but starts every run with the
+// `history` frames before it, for analyses that carry on from more than
+// one frame back
+pub fn analyse_frames_with_history<T, A, M>(source: &mut dyn AudioSource, config: &AnalysisConfig,
+    mode: ChannelMode, history: usize, threads: usize, analyser: M,
+    progress: &mut dyn FnMut(Progress)) -> Result<Vec<T>, SourceError>
+    where T: Send, M: Fn() -> A + Sync, A: FnMut(&mut Stft, &[&[f32]]) -> T {
+    let mut results = Vec::new();
+    run_batches(source, config, mode, history, threads, analyser, progress, |value| results.push(value))?;
+    Ok(results)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_batches<T, A, M, E>(source: &mut dyn AudioSource, config: &AnalysisConfig, mode: ChannelMode,
+    history: usize, threads: usize, analyser: M, progress: &mut dyn FnMut(Progress),
+    mut each: E) -> Result<(), SourceError>
+    where T: Send, M: Fn() -> A + Sync, A: FnMut(&mut Stft, &[&[f32]]) -> T, E: FnMut(T) {
+    let spec = source.spec();
+    let sample_rate = spec.sample_rate as f64;
+    let total_secs = spec.total_frames.map(|frames| frames as f64 / sample_rate);
+    let mut transforms: Vec<Stft> = (0..threads.max(1)).map(|_| {
+        let mut planner = FFTplanner::new(false);
+        Stft::with_planner(*config, &mut planner)
+    }).collect();
+    let mut reader = FrameReader::new(source, config, mode);
+    let frame_len = reader.frame_len;
+    let fft_size = config.fft_size;
+    let analyser = &analyser;
+
+    // frames whose results have been handed on
+    let mut done = 0;
+    // the frames being analysed, after the last `history` frames before them
+    // when there were any, and the frames of the batch after
+    let mut batch = Vec::new();
+    let mut next = Vec::new();
+    let mut fresh = reader.read(&mut batch, BATCH_FRAMES)?;
+    while fresh > 0 {
+        let primed = batch.len() / frame_len - fresh;
+        let frames = &batch[..];
+        let (values, read) = thread::scope(|scope| {
+            let run = fresh.div_ceil(transforms.len());
+            let workers: Vec<_> = transforms.iter_mut().zip((primed..primed + fresh).step_by(run))
+                .map(|(stft, start)| {
+                    let first = start.saturating_sub(history);
+                    let end = (start + run).min(primed + fresh);
+                    let run_frames = &frames[first * frame_len..end * frame_len];
+                    scope.spawn(move || {
+                        let mut analyse = analyser();
+                        run_frames.chunks(frame_len).enumerate().filter_map(|(i, frame)| {
+                            let signals: Vec<&[f32]> = frame.chunks(fft_size).collect();
+                            let value = analyse(stft, &signals);
+                            if first + i < start { None } else { Some(value) }
+                        }).collect::<Vec<T>>()
+                    })
+                }).collect();
+
+            // decode the next batch while this one is analysed
+            next.clear();
+            next.extend_from_slice(&frames[frames.len().saturating_sub(history * frame_len)..]);
+            let read = reader.read(&mut next, BATCH_FRAMES);
+            let values: Vec<T> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+            (values, read)
+        });
+        done += values.len();
+        values.into_iter().for_each(&mut each);
+        let end_frame = (done - 1) * config.hop_size + fft_size;
+        progress(Progress { done_secs: end_frame as f64 / sample_rate, total_secs });
+        mem::swap(&mut batch, &mut next);
+        fresh = read?;
+    }
+    progress(Progress { done_secs: reader.position as f64 / sample_rate, total_secs });
+    Ok(())
+}
+
+/*
+Decodes a file into analysis frames on demand, a batch at a time, keeping
+its place in the chunk it last read.
+*/
+struct FrameReader<'a> {
+    source: &'a mut dyn AudioSource,
+    mode: ChannelMode,
+    channels: usize,
+    frames: SignalFrames,
+    values: Vec<f32>,
+    // samples of the frame of every signal, one after another
+    frame_len: usize,
+    chunk: Vec<f32>,
+    // the samples read into `chunk` and how many of them have been split
+    len: usize,
+    pos: usize,
+    // input frames read so far
+    position: u64,
+    finished: bool
+}
+
+impl<'a> FrameReader<'a> {
+    fn new(source: &'a mut dyn AudioSource, config: &AnalysisConfig, mode: ChannelMode) -> FrameReader<'a> {
+        let channels = source.spec().channels as usize;
+        let num_signals = mode.signal_count(channels);
+        FrameReader {
+            source, mode, channels,
+            frames: SignalFrames::new(config, num_signals),
+            values: vec![0f32; num_signals],
+            frame_len: num_signals * config.fft_size,
+            chunk: vec![0f32; CHUNK_FRAMES * channels],
+            len: 0,
+            pos: 0,
+            position: 0,
+            finished: false
+        }
+    }
+
+    // Adds up to `count` more frames to `batch`, returning how many there
+    // were. Fewer than `count` means the file has ended.
+    fn read(&mut self, batch: &mut Vec<f32>, count: usize) -> Result<usize, SourceError> {
+        let mut added = 0;
+        while added < count && !self.finished {
+            if self.pos == self.len {
+                self.len = self.source.read(&mut self.chunk)?;
+                self.pos = 0;
+                self.finished = self.len == 0;
+                continue;
+            }
+            self.mode.split_frame(&self.chunk[self.pos..self.pos + self.channels], &mut self.values);
+            self.pos += self.channels;
+            self.position += 1;
+            if self.frames.push(&self.values).is_some() {
+                for signal in self.frames.frames() {
+                    batch.extend_from_slice(signal);
+                }
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio::convert::SampleFormat;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    // Stereo noise held in memory, handed out in small reads
+    struct NoiseSource {
+        samples: Vec<f32>,
+        pos: usize
+    }
+
+    impl NoiseSource {
+        fn new(frames: usize) -> NoiseSource {
+            let mut state = 1u32;
+            let samples = (0..2 * frames).map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            }).collect();
+            NoiseSource { samples, pos: 0 }
+        }
+    }
+
+    impl AudioSource for NoiseSource {
+        fn spec(&self) -> SourceSpec {
+            SourceSpec {
+                channels: 2,
+                sample_rate: 8000,
+                sample_format: SampleFormat::Float,
+                total_frames: Some(self.samples.len() as u64 / 2)
+            }
+        }
+
+        fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+            let n = (buf.len().min(1000) / 2 * 2).min(self.samples.len() - self.pos);
+            buf[..n].copy_from_slice(&self.samples[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+
+        fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+            self.pos = (2 * frame as usize).min(self.samples.len());
+            Ok(())
+        }
+    }
+
+    // Every frame's energy set against the `history` frames before it, so
+    // a run that isn't primed properly gives different numbers
+    fn analyse(threads: usize, history: usize) -> Vec<Vec<f32>> {
+        let config = AnalysisConfig { fft_size: 256, hop_size: 64, ..AnalysisConfig::default() };
+        let mut source = NoiseSource::new(40000);
+        let analyser = || {
+            let mut previous: VecDeque<f32> = VecDeque::new();
+            move |stft: &mut Stft, signals: &[&[f32]]| {
+                signals.iter().map(|signal| {
+                    let energy = stft.process(signal).iter().map(|m| m * m).sum::<f32>();
+                    let before = previous.iter().sum::<f32>();
+                    previous.push_back(energy);
+                    if previous.len() > history * signals.len() {
+                        previous.pop_front();
+                    }
+                    energy - before
+                }).collect()
+            }
+        };
+        if history == 1 {
+            analyse_frames(&mut source, &config, ChannelMode::PerChannel, threads, analyser, &mut |_| {})
+        } else {
+            analyse_frames_with_history(&mut source, &config, ChannelMode::PerChannel, history, threads, analyser,
+                &mut |_| {})
+        }.unwrap()
+    }
+
+    #[test]
+    fn results_are_the_same_on_any_number_of_threads() {
+        for &history in &[1, 5] {
+            let single = analyse(1, history);
+            // more frames than a batch, so runs start in the middle of one
+            // and the next batch carries on from the one before
+            assert_eq!(single.len(), (40000 - 256) / 64 + 1);
+            assert!(single.len() > 2 * BATCH_FRAMES);
+            for &threads in &[3, 8] {
+                assert!(analyse(threads, history) == single, "history {} on {} threads", history, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn progress_reaches_the_end() {
+        let config = AnalysisConfig::default();
+        let mut last = None;
+        analyse_frames(&mut NoiseSource::new(20000), &config, ChannelMode::Mono, 2, || {
+            |_: &mut Stft, _: &[&[f32]]| ()
+        }, &mut |progress| last = Some(progress)).unwrap();
+        let last = last.unwrap();
+        assert_eq!(last.done_secs, 2.5);
+        assert_eq!(last.fraction(), Some(1f64));
+    }
+
+    #[test]
+    fn each_frame_is_handed_on_as_its_batch_finishes() {
+        let config = AnalysisConfig { fft_size: 256, hop_size: 64, ..AnalysisConfig::default() };
+        let values = RefCell::new(Vec::new());
+        // how many values had been handed on each time progress was reported
+        let mut reported = Vec::new();
+        analyse_frames_each(&mut NoiseSource::new(40000), &config, ChannelMode::PerChannel, 3, || {
+            |stft: &mut Stft, signals: &[&[f32]]| -> Vec<f32> {
+                signals.iter().map(|signal| stft.process(signal).iter().map(|m| m * m).sum::<f32>()).collect()
+            }
+        }, &mut |_| reported.push(values.borrow().len()), |value| values.borrow_mut().push(value)).unwrap();
+        let values = values.into_inner();
+        assert_eq!(values.len(), (40000 - 256) / 64 + 1);
+        assert!(values.iter().all(|value| value.len() == 2));
+        let batches: Vec<usize> = (1..values.len() / BATCH_FRAMES + 1).map(|i| i * BATCH_FRAMES)
+            .chain(vec![values.len(), values.len()]).collect();
+        assert_eq!(reported, batches);
+    }
+
+}
@@ -0,0 +1,435 @@
+use std::collections::VecDeque;
+use std::mem;
+use super::analysis::*;
+use super::bands::*;
+use super::hpss::*;
+
+// Compression applied to magnitudes before differencing, so quiet detail
+// still registers next to loud sustained notes
+const COMPRESSION: f32 = 100f32;
+// Flux that never counts as an onset however quiet the music around it
+const FLUX_FLOOR: f32 = 1e-3;
+
+/*
+Settings for onset detection. An onset is a frame whose spectral flux (the
+growth of the spectrum since the previous frame) is a local peak and
+exceeds `threshold` times the average flux over the surrounding
+`window_secs`. With `per_band` set, every band of the analysis band scale
+is tracked on its own and onsets report the band they happened in. The
+onsets and beats of whole tracks are found in `layer`, which can be the
+percussive layer to keep sustained notes from hiding the drums.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OnsetConfig {
+    pub per_band: bool,
+    pub layer: Layer,
+    pub threshold: f32,
+    pub window_secs: f32,
+    // shortest gap allowed between two onsets (in the same band)
+    pub min_interval_secs: f32
+}
+
+impl Default for OnsetConfig {
+    fn default() -> OnsetConfig {
+        OnsetConfig {
+            per_band: false,
+            layer: Layer::Mix,
+            threshold: 1.5,
+            window_secs: 0.5,
+            min_interval_secs: 0.05
+        }
+    }
+}
+
+// A detected onset, stamped with the centre of the frame it happened in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OnsetEvent {
+    pub time_secs: f64,
+    // how far the flux rose above the threshold, as a multiple of it
+    pub strength: f32,
+    // the band the onset was found in, when detecting per band
+    pub band: Option<usize>
+}
+
+/*
+Turns the spectra of consecutive frames into flux values: one for the
+whole spectrum, or one per band.
+*/
+struct SpectralFlux {
+    per_band: bool,
+    previous: Vec<f32>,
+    current: Vec<f32>
+}
+
+impl SpectralFlux {
+    fn new(per_band: bool) -> SpectralFlux {
+        SpectralFlux { per_band, previous: Vec::new(), current: Vec::new() }
+    }
+
+    // Writes the flux of every tracked signal into `flux`, or nothing for
+    // the first frame after a reset
+    fn process(&mut self, magnitudes: &[f32], band_levels: &[f32], flux: &mut Vec<f32>) {
+        flux.clear();
+        self.current.clear();
+        if self.per_band {
+            // back from dB to amplitudes, then compressed like the bins
+            self.current.extend(band_levels.iter()
+                .map(|&level| compress(10f32.powf(level / 20f32))));
+        } else {
+            self.current.extend(magnitudes.iter().map(|&magnitude| compress(magnitude)));
+        }
+        if self.previous.len() == self.current.len() {
+            let rises = self.current.iter().zip(&self.previous)
+                .map(|(&now, &before)| (now - before).max(0f32));
+            if self.per_band {
+                flux.extend(rises);
+            } else {
+                flux.push(rises.sum::<f32>() / self.current.len() as f32);
+            }
+        }
+        mem::swap(&mut self.previous, &mut self.current);
+    }
+
+    fn reset(&mut self) {
+        self.previous.clear();
+    }
+}
+
+fn compress(magnitude: f32) -> f32 {
+    (1f32 + COMPRESSION * magnitude).ln()
+}
+
+/*
+Causal peak picker for one flux signal. A frame can only be confirmed as a
+peak once the next frame is known, so onsets come out one frame late.
+*/
+struct PeakPicker {
+    history: VecDeque<f32>,
+    history_len: usize,
+    // flux two frames back, and the time and flux of the frame before this
+    // one, which is the peak candidate
+    before: f32,
+    candidate: (f64, f32),
+    frames_seen: usize,
+    last_onset: Option<f64>
+}
+
+impl PeakPicker {
+    fn new(history_len: usize) -> PeakPicker {
+        PeakPicker {
+            history: VecDeque::with_capacity(history_len + 1),
+            history_len: history_len.max(1),
+            before: 0f32,
+            candidate: (0f64, 0f32),
+            frames_seen: 0,
+            last_onset: None
+        }
+    }
+
+    fn push(&mut self, time_secs: f64, flux: f32, config: &OnsetConfig) -> Option<(f64, f32)> {
+        let (peak_time, peak) = self.candidate;
+        let mut onset = None;
+        if self.frames_seen >= 2 && peak > self.before && peak >= flux {
+            let threshold = threshold(&self.history, config);
+            let spaced = match self.last_onset {
+                Some(last) => peak_time - last >= config.min_interval_secs as f64,
+                None => true
+            };
+            if peak > threshold && spaced {
+                self.last_onset = Some(peak_time);
+                onset = Some((peak_time, peak / threshold));
+            }
+        }
+        self.history.push_back(flux);
+        if self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+        self.before = peak;
+        self.candidate = (time_secs, flux);
+        self.frames_seen += 1;
+        onset
+    }
+}
+
+fn threshold<'a, I>(flux: I, config: &OnsetConfig) -> f32
+    where I: IntoIterator<Item = &'a f32> {
+    let (sum, count) = flux.into_iter().fold((0f32, 0), |(sum, count), &x| (sum + x, count + 1));
+    let mean = if count > 0 { sum / count as f32 } else { 0f32 };
+    config.threshold * mean + FLUX_FLOOR
+}
+
+// Number of analysis frames making up the threshold window
+fn history_len(config: &AnalysisConfig, sample_rate: u32) -> usize {
+    let frames_per_sec = sample_rate as f32 / config.hop_size as f32;
+    (config.onsets.window_secs * frames_per_sec).round() as usize
+}
+
+/*
+Online onset detector, fed one analysed frame at a time as the audio plays.
+*/
+pub struct OnsetDetector {
+    config: OnsetConfig,
+    flux: SpectralFlux,
+    values: Vec<f32>,
+    pickers: Vec<PeakPicker>,
+    history_len: usize
+}
+
+impl OnsetDetector {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> OnsetDetector {
+        OnsetDetector {
+            config: config.onsets,
+            flux: SpectralFlux::new(config.onsets.per_band),
+            values: Vec::new(),
+            pickers: Vec::new(),
+            history_len: history_len(config, sample_rate)
+        }
+    }
+
+    // Takes the magnitude spectrum and band levels of the frame centred on
+    // `time_secs`, and adds any onsets confirmed by it to `events`
+    pub fn process(&mut self, time_secs: f64, magnitudes: &[f32], band_levels: &[f32],
+        events: &mut Vec<OnsetEvent>) {
+        self.flux.process(magnitudes, band_levels, &mut self.values);
+        while self.pickers.len() < self.values.len() {
+            self.pickers.push(PeakPicker::new(self.history_len));
+        }
+        let per_band = self.config.per_band;
+        for (i, (picker, &flux)) in self.pickers.iter_mut().zip(&self.values).enumerate() {
+            if let Some((time_secs, strength)) = picker.push(time_secs, flux, &self.config) {
+                let band = if per_band { Some(i) } else { None };
+                events.push(OnsetEvent { time_secs, strength, band });
+            }
+        }
+    }
+
+    // Starts over, e.g. after playback jumps
+    pub fn reset(&mut self) {
+        self.flux.reset();
+        self.pickers.clear();
+    }
+}
+
+/*
+The onset strength of a whole file frame by frame, taken from a mono mix
+of its channels, or from one layer of it. Both the onsets and the tempo
+of a track are read from it.
+*/
+pub struct OnsetEnvelope {
+    // time of the first value, and the time between values
+    pub start_secs: f64,
+    pub frame_secs: f64,
+    // flux of the whole spectrum
+    pub total: Vec<f32>,
+    // flux of every band ([band][frame]), when detecting onsets per band
+    pub bands: Vec<Vec<f32>>
+}
+
+impl OnsetEnvelope {
+    pub fn time_secs(&self, frame: usize) -> f64 {
+        self.start_secs + frame as f64 * self.frame_secs
+    }
+
+    // Collects the onset strength of every frame of a file, as
+    // `OnsetFlux` gives it, into an envelope
+    pub fn from_flux(config: &AnalysisConfig, sample_rate: u32, flux: Vec<Option<(f32, Vec<f32>)>>) -> OnsetEnvelope {
+        let frame_secs = config.hop_size as f64 / sample_rate as f64;
+        let mut envelope = OnsetEnvelope {
+            // the first frame has nothing to compare against
+            start_secs: config.centre_offset_secs(sample_rate) + frame_secs,
+            frame_secs,
+            total: Vec::with_capacity(flux.len()),
+            bands: Vec::new()
+        };
+        for (total, band_values) in flux.into_iter().flatten() {
+            envelope.total.push(total);
+            envelope.bands.resize(band_values.len(), Vec::new());
+            for (curve, x) in envelope.bands.iter_mut().zip(band_values) {
+                curve.push(x);
+            }
+        }
+        envelope
+    }
+}
+
+/*
+Works out the onset strength of consecutive frames of a mono mix, or of
+the layer of it onsets are detected in, for the onset envelope of a whole
+file.
+*/
+pub struct OnsetFlux {
+    band_analyzer: BandAnalyzer,
+    per_band: bool,
+    layer: Layer,
+    total_flux: SpectralFlux,
+    band_flux: SpectralFlux,
+    separator: HpssSeparator,
+    levels: Vec<f32>,
+    values: Vec<f32>
+}
+
+impl OnsetFlux {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> OnsetFlux {
+        OnsetFlux {
+            band_analyzer: BandAnalyzer::new(config.bands, sample_rate, config.num_bins(),
+                config.bin_width(sample_rate), config.noise_bandwidth()),
+            per_band: config.onsets.per_band,
+            layer: config.onsets.layer,
+            total_flux: SpectralFlux::new(false),
+            band_flux: SpectralFlux::new(true),
+            separator: HpssSeparator::new(config, sample_rate),
+            levels: Vec::new(),
+            values: Vec::new()
+        }
+    }
+
+    // Frames before a run of frames that have to be seen first for its
+    // first value to be right: the one the flux compares against, and
+    // those the harmonic median of a separated layer runs over
+    pub fn history(config: &AnalysisConfig, sample_rate: u32) -> usize {
+        if config.onsets.layer == Layer::Mix { 1 } else { hpss_history(config, sample_rate) + 1 }
+    }
+
+    // The flux of the whole spectrum of the next frame, and of every band
+    // when detecting onsets per band, or None for the first frame
+    pub fn process(&mut self, magnitudes: &[f32]) -> Option<(f32, Vec<f32>)> {
+        let mut magnitudes = magnitudes;
+        if self.layer != Layer::Mix {
+            self.separator.process(magnitudes);
+            magnitudes = self.separator.magnitudes(self.layer);
+        }
+        self.band_analyzer.levels(magnitudes, &mut self.levels);
+        self.total_flux.process(magnitudes, &self.levels, &mut self.values);
+        let mut band_values = Vec::new();
+        if self.per_band {
+            self.band_flux.process(magnitudes, &self.levels, &mut band_values);
+        }
+        self.values.first().map(|&total| (total, band_values))
+    }
+}
+
+// Picks the onsets out of a whole envelope. With the whole curve known,
+// each frame is compared with the frames either side of it rather than
+// only those before.
+pub fn pick_onsets(envelope: &OnsetEnvelope, config: &OnsetConfig) -> Vec<OnsetEvent> {
+    let half_window = (config.window_secs as f64 / envelope.frame_secs / 2f64).round() as usize;
+    let curves: Vec<(Option<usize>, &Vec<f32>)> = if config.per_band {
+        envelope.bands.iter().enumerate().map(|(band, curve)| (Some(band), curve)).collect()
+    } else {
+        vec![(None, &envelope.total)]
+    };
+    let mut events = Vec::new();
+    for (band, curve) in curves {
+        let mut last_onset: Option<f64> = None;
+        for i in 1..curve.len().saturating_sub(1) {
+            let peak = curve[i];
+            if peak <= curve[i - 1] || peak < curve[i + 1] {
+                continue;
+            }
+            let window = &curve[i.saturating_sub(half_window)..(i + half_window + 1).min(curve.len())];
+            let threshold = threshold(window, config);
+            let time_secs = envelope.time_secs(i);
+            let spaced = match last_onset {
+                Some(last) => time_secs - last >= config.min_interval_secs as f64,
+                None => true
+            };
+            if peak > threshold && spaced {
+                last_onset = Some(time_secs);
+                events.push(OnsetEvent { time_secs, strength: peak / threshold, band });
+            }
+        }
+    }
+    events.sort_by(|a, b| a.time_secs.total_cmp(&b.time_secs));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pushes a flux curve with one value every 10 ms, returning the onsets
+    fn pick(flux: &[f32], config: &OnsetConfig) -> Vec<(f64, f32)> {
+        let mut picker = PeakPicker::new(50);
+        flux.iter().enumerate()
+            .filter_map(|(i, &x)| picker.push(i as f64 * 0.01, x, config))
+            .collect()
+    }
+
+    #[test]
+    fn peak_picker_finds_a_spike_one_frame_late() {
+        let config = OnsetConfig::default();
+        let mut flux = vec![0.1f32; 40];
+        flux[20] = 1f32;
+        let mut picker = PeakPicker::new(50);
+        for (i, &x) in flux.iter().enumerate().take(21) {
+            assert_eq!(picker.push(i as f64 * 0.01, x, &config), None);
+        }
+        let (time_secs, strength) = picker.push(0.21, flux[21], &config).unwrap();
+        assert_eq!(time_secs, 0.2);
+        // the threshold comes from the frames so far, the peak included
+        let mean = (20f32 * 0.1f32 + 1f32) / 21f32;
+        assert!((strength - 1f32 / (1.5f32 * mean + FLUX_FLOOR)).abs() < 1e-3, "{}", strength);
+        assert_eq!(pick(&flux, &config).len(), 1);
+    }
+
+    #[test]
+    fn peak_picker_ignores_quiet_bumps_and_spikes_too_close() {
+        let config = OnsetConfig::default();
+        // a rise that doesn't clear the threshold
+        let mut flux = vec![0.1f32; 60];
+        flux[10] = 0.14f32;
+        // two spikes 20 ms apart, inside the 50 ms minimum interval
+        flux[30] = 1f32;
+        flux[32] = 1f32;
+        let onsets = pick(&flux, &config);
+        assert_eq!(onsets.len(), 1);
+        assert_eq!(onsets[0].0, 0.3);
+        // a plateau only counts once
+        let mut flux = vec![0f32; 20];
+        flux[5] = 1f32;
+        flux[6] = 1f32;
+        assert_eq!(pick(&flux, &config).len(), 1);
+    }
+
+    #[test]
+    fn detector_finds_a_broadband_hit() {
+        let config = AnalysisConfig::default();
+        let mut detector = OnsetDetector::new(&config, 44100);
+        let quiet = vec![0.001f32; config.num_bins()];
+        let loud = vec![0.5f32; config.num_bins()];
+        let mut events = Vec::new();
+        for frame in 0..50 {
+            let magnitudes = if frame >= 25 { &loud } else { &quiet };
+            detector.process(frame as f64 * 0.01, magnitudes, &[], &mut events);
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].time_secs, 0.25);
+        assert_eq!(events[0].band, None);
+        // after a reset the first frame has nothing to rise from
+        detector.reset();
+        events.clear();
+        detector.process(1f64, &loud, &[], &mut events);
+        detector.process(1.01, &loud, &[], &mut events);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn per_band_detector_reports_the_band() {
+        let config = AnalysisConfig {
+            onsets: OnsetConfig { per_band: true, ..OnsetConfig::default() },
+            ..AnalysisConfig::default()
+        };
+        let mut detector = OnsetDetector::new(&config, 44100);
+        let mut events = Vec::new();
+        for frame in 0..50 {
+            let mut levels = vec![-60f32; 8];
+            if frame >= 25 {
+                levels[3] = -6f32;
+            }
+            detector.process(frame as f64 * 0.01, &[], &levels, &mut events);
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].time_secs, 0.25);
+        assert_eq!(events[0].band, Some(3));
+    }
+}
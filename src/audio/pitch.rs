@@ -0,0 +1,254 @@
+use std::fmt;
+use std::sync::Arc;
+use num::complex::Complex;
+use rustfft::{FFTplanner, FFT};
+use super::analysis::*;
+
+// Tuning reference: A4 is MIDI note 69 at 440 Hz
+const A4_HZ: f32 = 440f32;
+const A4_MIDI: i32 = 69;
+pub const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+// Frames with a lower mean square than this are silent and have no pitch
+const SILENCE: f32 = 1e-8;
+
+/*
+Settings for pitch tracking. Only fundamentals between `min_hz` and
+`max_hz` are considered. `threshold` is how far the YIN difference function
+has to dip (0 is a perfectly periodic signal) for the shortest period
+reaching it to be taken over the deepest dip, which keeps the tracker from
+jumping an octave down.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PitchConfig {
+    pub threshold: f32,
+    pub min_hz: f32,
+    pub max_hz: f32
+}
+
+impl Default for PitchConfig {
+    fn default() -> PitchConfig {
+        PitchConfig { threshold: 0.15, min_hz: 40f32, max_hz: 2000f32 }
+    }
+}
+
+// The equal tempered note nearest a frequency
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note {
+    pub midi: i32,
+    // how far the frequency is above (or below) the note, from -50 to 50
+    pub cents: f32
+}
+
+impl Note {
+    pub fn from_frequency(hz: f32) -> Note {
+        let semitones = A4_MIDI as f32 + 12f32 * (hz / A4_HZ).log2();
+        let nearest = semitones.round();
+        Note { midi: nearest as i32, cents: 100f32 * (semitones - nearest) }
+    }
+}
+
+// Scientific pitch notation, e.g. "A4" or "C#3"
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", NOTE_NAMES[self.midi.rem_euclid(12) as usize], self.midi.div_euclid(12) - 1)
+    }
+}
+
+// The fundamental frequency found in one frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pitch {
+    pub frequency: f32,
+    // how periodic the frame is, from 0 (noise) to 1 (a pure tone)
+    pub confidence: f32,
+    pub note: Note
+}
+
+/*
+YIN pitch tracker for monophonic audio. The difference between a frame and
+itself shifted by every candidate period is computed through one FFT
+correlation, normalised by its running mean, and the period where it first
+dips below the threshold gives the pitch. Unlike the loudest bin of the
+spectrum, this follows the fundamental even when a harmonic is louder.
+*/
+pub struct YinTracker {
+    config: PitchConfig,
+    sample_rate: u32,
+    fft: Arc<dyn FFT<f32>>,
+    // samples compared in every difference, and the range of periods tried
+    window: usize,
+    min_period: usize,
+    max_period: usize,
+    frame: Vec<Complex<f32>>,
+    head: Vec<Complex<f32>>,
+    spectrum: Vec<Complex<f32>>,
+    head_spectrum: Vec<Complex<f32>>,
+    energies: Vec<f32>,
+    difference: Vec<f32>
+}
+
+impl YinTracker {
+    // Tracks pitch in frames of `fft_size` samples. Half of every frame is
+    // compared against shifted copies of itself, which limits the lowest
+    // pitch to twice the frame rate. Frames too short to hold a period of
+    // two samples, or a range entirely above that, never have a pitch.
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> YinTracker {
+        let frame_len = config.fft_size;
+        let window = frame_len / 2;
+        let max_period = ((sample_rate as f32 / config.pitch.min_hz).ceil() as usize)
+            .min(frame_len - window);
+        let min_period = ((sample_rate as f32 / config.pitch.max_hz).floor() as usize)
+            .max(2)
+            .min(max_period);
+        // long enough that the correlation does not wrap around
+        let size = (frame_len + window).next_power_of_two();
+        let zero = Complex::new(0f32, 0f32);
+        YinTracker {
+            config: config.pitch, sample_rate, window, min_period, max_period,
+            fft: FFTplanner::new(false).plan_fft(size),
+            frame: vec![zero; size],
+            head: vec![zero; size],
+            spectrum: vec![zero; size],
+            head_spectrum: vec![zero; size],
+            energies: Vec::with_capacity(frame_len + 1),
+            difference: vec![0f32; max_period + 1]
+        }
+    }
+
+    // Finds the pitch of one frame, or None if the frame is silent
+    pub fn process(&mut self, frame: &[f32]) -> Option<Pitch> {
+        if self.max_period < 2 {
+            return None;
+        }
+        let window = self.window;
+        // running sums of squares, for the energy of any stretch of the frame
+        self.energies.clear();
+        self.energies.push(0f32);
+        let mut sum = 0f32;
+        for &x in frame {
+            sum += x * x;
+            self.energies.push(sum);
+        }
+        if self.energies[window] / (window as f32) < SILENCE {
+            return None;
+        }
+
+        // correlation of the first window with the frame at every shift
+        let zero = Complex::new(0f32, 0f32);
+        for (i, c) in self.frame.iter_mut().enumerate() {
+            *c = frame.get(i).map_or(zero, |&x| Complex::new(x, 0f32));
+        }
+        for (i, c) in self.head.iter_mut().enumerate() {
+            *c = if i < window { self.frame[i] } else { zero };
+        }
+        self.fft.process(&mut self.frame, &mut self.spectrum);
+        self.fft.process(&mut self.head, &mut self.head_spectrum);
+        // the inverse transform, done forwards on the conjugate
+        for (a, b) in self.spectrum.iter_mut().zip(&self.head_spectrum) {
+            *a = (*a * b.conj()).conj();
+        }
+        self.fft.process(&mut self.spectrum, &mut self.frame);
+        let size = self.frame.len() as f32;
+
+        // cumulative mean normalised difference
+        self.difference[0] = 1f32;
+        let mut running = 0f32;
+        for period in 1..self.max_period + 1 {
+            let shifted = self.energies[period + window] - self.energies[period];
+            let correlation = self.frame[period].re / size;
+            let difference = (self.energies[window] + shifted - 2f32 * correlation).max(0f32);
+            running += difference;
+            self.difference[period] = if running > 0f32 {
+                difference * period as f32 / running
+            } else {
+                1f32
+            };
+        }
+
+        // the first dip below the threshold, followed down to its bottom,
+        // or failing that the deepest dip of all
+        let candidates = self.min_period..self.max_period + 1;
+        let period = match candidates.clone().find(|&p| self.difference[p] < self.config.threshold) {
+            Some(mut period) => {
+                while period < self.max_period && self.difference[period + 1] < self.difference[period] {
+                    period += 1;
+                }
+                period
+            },
+            None => candidates.min_by(|&a, &b| self.difference[a].total_cmp(&self.difference[b]))?
+        };
+
+        // refine the period between samples with a parabola through its neighbours
+        let mut refined = period as f32;
+        if period > self.min_period && period < self.max_period {
+            let (before, dip, after) = (self.difference[period - 1], self.difference[period],
+                self.difference[period + 1]);
+            let curvature = before - 2f32 * dip + after;
+            if curvature > 0f32 {
+                refined += (0.5f32 * (before - after) / curvature).clamp(-0.5f32, 0.5f32);
+            }
+        }
+        let frequency = self.sample_rate as f32 / refined;
+        Some(Pitch {
+            frequency,
+            confidence: (1f32 - self.difference[period]).clamp(0f32, 1f32),
+            note: Note::from_frequency(frequency)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    // One frame of the default size of a sum of sines, given as (Hz, amplitude)
+    fn tone(partials: &[(f32, f32)], sample_rate: u32) -> Vec<f32> {
+        (0..AnalysisConfig::default().fft_size).map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            partials.iter().map(|&(hz, amplitude)| amplitude * (2f32 * PI * hz * t).sin()).sum()
+        }).collect()
+    }
+
+    #[test]
+    fn sine_at_440_hz_is_a4() {
+        let mut tracker = YinTracker::new(&AnalysisConfig::default(), 44100);
+        let pitch = tracker.process(&tone(&[(440f32, 0.5f32)], 44100)).unwrap();
+        assert!((pitch.frequency - 440f32).abs() < 1f32, "{}", pitch.frequency);
+        assert!(pitch.confidence > 0.95, "{}", pitch.confidence);
+        assert_eq!(pitch.note.to_string(), "A4");
+        assert!(pitch.note.cents.abs() < 5f32);
+    }
+
+    #[test]
+    fn fundamental_wins_over_a_louder_harmonic() {
+        let mut tracker = YinTracker::new(&AnalysisConfig::default(), 44100);
+        let pitch = tracker.process(&tone(&[(110f32, 0.2f32), (220f32, 0.6f32)], 44100)).unwrap();
+        assert!((pitch.frequency - 110f32).abs() < 1f32, "{}", pitch.frequency);
+    }
+
+    #[test]
+    fn silence_has_no_pitch() {
+        let mut tracker = YinTracker::new(&AnalysisConfig::default(), 44100);
+        assert_eq!(tracker.process(&vec![0f32; 2048]), None);
+    }
+
+    #[test]
+    fn smallest_configs_track_without_panicking() {
+        for fft_size in 1..5 {
+            let config = AnalysisConfig { fft_size, ..AnalysisConfig::default() };
+            let mut tracker = YinTracker::new(&config, 44100);
+            let pitch = tracker.process(&vec![0.5f32; fft_size]);
+            // no room for a period of two samples
+            if fft_size < 3 {
+                assert_eq!(pitch, None);
+            }
+        }
+        // a range entirely above what the sample rate can hold
+        let config = AnalysisConfig {
+            pitch: PitchConfig { min_hz: 50000f32, max_hz: 60000f32, ..PitchConfig::default() },
+            ..AnalysisConfig::default()
+        };
+        let mut tracker = YinTracker::new(&config, 44100);
+        assert_eq!(tracker.process(&tone(&[(440f32, 0.5f32)], 44100)), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc kernel on each side of the output sample
+const HALF_ZERO_CROSSINGS: usize = 16;
+// Kernel table entries per unit of input time
+const TABLE_OVERSAMPLING: usize = 512;
+// Kaiser window shape, roughly 90 dB of stopband rejection
+const KAISER_BETA: f64 = 8.6;
+
+/*
+Band-limited sample rate converter using a Kaiser-windowed sinc kernel.
+Input is fed in arbitrary sized interleaved blocks and output is appended
+to the caller's buffer, so it can sit in a streaming pipeline. When
+downsampling the kernel is widened so everything above the new Nyquist
+frequency is filtered out before it can alias.
+*/
+pub struct Resampler {
+    channels: usize,
+    from_rate: u64,
+    to_rate: u64,
+    // output frames per input frame
+    ratio: f64,
+    // half the kernel length, in input frames
+    half_width: f64,
+    table: Vec<f64>,
+    // buffered interleaved input; frame 0 of `history` is input frame `history_start`
+    history: Vec<f32>,
+    history_start: i64,
+    frames_in: u64,
+    frames_out: u64
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Resampler {
+        let ratio = to_rate as f64 / from_rate as f64;
+        // kernel cutoff relative to the input Nyquist frequency
+        let cutoff = ratio.min(1f64);
+        let half_width = HALF_ZERO_CROSSINGS as f64 / cutoff;
+        let table_len = (half_width * TABLE_OVERSAMPLING as f64).ceil() as usize + 2;
+        let table = (0..table_len).map(|i| {
+            let t = i as f64 / TABLE_OVERSAMPLING as f64;
+            cutoff * sinc(cutoff * t) * kaiser(t / half_width, KAISER_BETA)
+        }).collect();
+
+        // pretend the signal was silent before it started so the first
+        // output sample lines up with the first input sample
+        let lead_in = half_width.ceil() as usize;
+        Resampler {
+            channels, ratio, half_width, table,
+            from_rate: from_rate as u64,
+            to_rate: to_rate as u64,
+            history: vec![0f32; lead_in * channels],
+            history_start: -(lead_in as i64),
+            frames_in: 0,
+            frames_out: 0
+        }
+    }
+
+    // Consumes interleaved input frames and appends every output frame that
+    // can be computed from the input seen so far
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+        self.frames_in += (input.len() / self.channels) as u64;
+        self.emit(output, false);
+    }
+
+    // Drains the remaining output once the input has ended
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let tail = (self.half_width.ceil() as usize + 1) * self.channels;
+        let len = self.history.len() + tail;
+        self.history.resize(len, 0f32);
+        self.emit(output, true);
+    }
+
+    fn emit(&mut self, output: &mut Vec<f32>, flushing: bool) {
+        let channels = self.channels;
+        let history_frames = (self.history.len() / channels) as i64;
+        let history_end = self.history_start + history_frames;
+        let expected_out = (self.frames_in * self.to_rate).div_ceil(self.from_rate);
+
+        loop {
+            if flushing && self.frames_out >= expected_out {
+                break;
+            }
+            let time = self.frames_out as f64 / self.ratio;
+            let first = (time - self.half_width).floor() as i64 + 1;
+            let last = (time + self.half_width).floor() as i64;
+            if last >= history_end {
+                break;
+            }
+            for ch in 0..channels {
+                let mut acc = 0f64;
+                for frame in first.max(self.history_start)..last + 1 {
+                    let idx = (frame - self.history_start) as usize * channels + ch;
+                    acc += self.history[idx] as f64 * self.kernel(time - frame as f64);
+                }
+                output.push(acc as f32);
+            }
+            self.frames_out += 1;
+        }
+
+        // drop input that no future output sample can reach
+        let next_time = self.frames_out as f64 / self.ratio;
+        let keep_from = (next_time - self.half_width).floor() as i64;
+        if keep_from > self.history_start {
+            let drop = ((keep_from - self.history_start) as usize).min(history_frames as usize);
+            self.history.drain(..drop * channels);
+            self.history_start += drop as i64;
+        }
+    }
+
+    // Linearly interpolated lookup into the kernel table
+    fn kernel(&self, offset: f64) -> f64 {
+        let pos = offset.abs() * TABLE_OVERSAMPLING as f64;
+        let idx = pos as usize;
+        if idx + 1 >= self.table.len() {
+            return 0f64;
+        }
+        let frac = pos - idx as f64;
+        self.table[idx] + frac * (self.table[idx + 1] - self.table[idx])
+    }
+}
+
+pub fn sinc(x: f64) -> f64 {
+    if x == 0f64 {
+        1f64
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Kaiser window evaluated at x in [-1, 1]
+pub fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() > 1f64 {
+        return 0f64;
+    }
+    bessel_i0(beta * (1f64 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+// Zeroth order modified Bessel function of the first kind, by power series
+pub fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1f64;
+    let mut term = 1f64;
+    let half = x / 2f64;
+    let mut k = 1f64;
+    while term > 1e-12 * sum {
+        term *= (half / k) * (half / k);
+        sum += term;
+        k += 1f64;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resamples interleaved input fed in uneven blocks, as the decoder does
+    fn resample(channels: usize, from_rate: u32, to_rate: u32, input: &[f32]) -> Vec<f32> {
+        let mut resampler = Resampler::new(channels, from_rate, to_rate);
+        let mut output = Vec::new();
+        for block in input.chunks(1000 * channels + channels) {
+            resampler.process(block, &mut output);
+        }
+        resampler.flush(&mut output);
+        output
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        for &(from, to) in &[(44100u32, 48000u32), (48000, 44100)] {
+            let input = vec![0f32; 2 * from as usize];
+            assert_eq!(resample(2, from, to, &input).len(), 2 * to as usize, "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn dc_passes_at_unity_gain() {
+        for &(from, to) in &[(44100u32, 48000u32), (48000, 44100)] {
+            let output = resample(1, from, to, &vec![0.5f32; from as usize]);
+            // away from the edges, where the kernel runs off the signal
+            for &x in &output[100..output.len() - 100] {
+                assert!((x - 0.5f32).abs() < 1e-3, "{} -> {}: {}", from, to, x);
+            }
+        }
+    }
+
+    #[test]
+    fn sine_keeps_its_frequency() {
+        let hz = 1000f64;
+        for &(from, to) in &[(44100u32, 48000u32), (48000, 44100)] {
+            let sine = |rate: u32, n: usize| (2f64 * PI * hz * n as f64 / rate as f64).sin() as f32;
+            // a different tone in each channel, to check they stay apart
+            let input: Vec<f32> = (0..from as usize).flat_map(|n| vec![sine(from, n), -sine(from, n)]).collect();
+            let output = resample(2, from, to, &input);
+            for n in 100..to as usize - 100 {
+                assert!((output[2 * n] - sine(to, n)).abs() < 1e-3, "{} -> {} at {}", from, to, n);
+                assert!((output[2 * n + 1] + sine(to, n)).abs() < 1e-3, "{} -> {} at {}", from, to, n);
+            }
+        }
+    }
+}
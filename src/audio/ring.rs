@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/*
+A fixed size ring holding the most recent frames the audio callback played,
+along with the track frame each one came from. There is exactly one writer,
+the callback, and any number of readers; neither side ever waits for the
+other. The writer claims the slots it is about to overwrite before touching
+them, and readers use the claim to throw away anything that was overwritten
+while they were copying it out.
+*/
+pub struct SampleRing {
+    channels: usize,
+    // capacity in frames, always a power of two
+    capacity: u64,
+    // interleaved samples, stored as f32 bits
+    samples: Vec<AtomicU32>,
+    track_frames: Vec<AtomicU64>,
+    // frames written so far; frame n lives in slot n % capacity
+    written: AtomicU64,
+    // frames the writer has started on; slots up to here may be changing
+    claimed: AtomicU64,
+    closed: AtomicBool
+}
+
+// Creates a ring of at least `frames` frames, returning the one writer and
+// the shared ring to hand out readers from
+pub fn sample_ring(channels: usize, frames: usize) -> (RingWriter, Arc<SampleRing>) {
+    let capacity = frames.next_power_of_two();
+    let ring = Arc::new(SampleRing {
+        channels,
+        capacity: capacity as u64,
+        samples: (0..capacity * channels).map(|_| AtomicU32::new(0)).collect(),
+        track_frames: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+        written: AtomicU64::new(0),
+        claimed: AtomicU64::new(0),
+        closed: AtomicBool::new(false)
+    });
+    (RingWriter { ring: ring.clone() }, ring)
+}
+
+impl SampleRing {
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // A reader that starts with the next frame written
+    pub fn reader(ring: &Arc<SampleRing>) -> RingReader {
+        RingReader { ring: ring.clone(), next: ring.written.load(Ordering::Acquire) }
+    }
+
+    // Copies the `frames` frames leading up to track frame `end_frame` into
+    // `out` (interleaved), or the newest frames if playback has not got that
+    // far. Returns the number of frames copied, which is fewer than asked
+    // for at the start of playback.
+    pub fn recent(&self, frames: usize, end_frame: u64, out: &mut Vec<f32>) -> usize {
+        out.clear();
+        let written = self.written.load(Ordering::Acquire);
+        if written == 0 {
+            return 0;
+        }
+        // the newest frames were handed to the device ahead of being heard
+        let newest_track = self.track_frames[self.slot(written - 1)].load(Ordering::Relaxed);
+        let ahead = newest_track.saturating_sub(end_frame.saturating_sub(1));
+        let end = written - ahead.min(written);
+        let start = end.saturating_sub(frames as u64);
+        let kept_from = self.copy(start, end, out, None);
+        end.saturating_sub(kept_from) as usize
+    }
+
+    // Copies frames [start, end) into `out`, then drops any the writer may
+    // have overwritten in the meantime. Returns the first frame kept.
+    fn copy(&self, start: u64, end: u64, out: &mut Vec<f32>, mut track: Option<&mut Vec<u64>>) -> u64 {
+        let channels = self.channels;
+        let first_out = out.len();
+        let first_track = track.as_ref().map_or(0, |track| track.len());
+        for frame in start..end {
+            let slot = self.slot(frame);
+            for sample in &self.samples[slot * channels..(slot + 1) * channels] {
+                out.push(f32::from_bits(sample.load(Ordering::Relaxed)));
+            }
+            if let Some(ref mut track) = track {
+                track.push(self.track_frames[slot].load(Ordering::Relaxed));
+            }
+        }
+        fence(Ordering::Acquire);
+        let claimed = self.claimed.load(Ordering::Relaxed);
+        let valid_from = claimed.saturating_sub(self.capacity).max(start);
+        if valid_from > start {
+            let stale = (valid_from.min(end) - start) as usize;
+            out.drain(first_out..first_out + stale * channels);
+            if let Some(track) = track {
+                track.drain(first_track..first_track + stale);
+            }
+        }
+        valid_from
+    }
+
+    fn slot(&self, frame: u64) -> usize {
+        (frame & (self.capacity - 1)) as usize
+    }
+}
+
+// The audio callback's end of a sample ring
+pub struct RingWriter {
+    ring: Arc<SampleRing>
+}
+
+impl RingWriter {
+    // Appends interleaved samples whose first frame is track frame `start_frame`
+    pub fn write(&mut self, start_frame: u64, samples: &[f32]) {
+        let ring = &*self.ring;
+        let channels = ring.channels;
+        let max_samples = ring.capacity as usize * channels;
+        let mut track_frame = start_frame;
+        for block in samples.chunks(max_samples) {
+            let start = ring.written.load(Ordering::Relaxed);
+            let end = start + (block.len() / channels) as u64;
+            ring.claimed.store(end, Ordering::Relaxed);
+            fence(Ordering::Release);
+            for (frame, values) in (start..end).zip(block.chunks(channels)) {
+                let slot = ring.slot(frame);
+                for (sample, &value) in ring.samples[slot * channels..].iter().zip(values) {
+                    sample.store(value.to_bits(), Ordering::Relaxed);
+                }
+                ring.track_frames[slot].store(track_frame, Ordering::Relaxed);
+                track_frame += 1;
+            }
+            ring.written.store(end, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for RingWriter {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+    }
+}
+
+// Follows the ring from frame to frame, for consumers that need every frame
+pub struct RingReader {
+    ring: Arc<SampleRing>,
+    next: u64
+}
+
+impl RingReader {
+    // Appends every frame written since the last read to `samples`, and the
+    // track frame of each to `track_frames`. A reader that falls more than
+    // the ring's length behind skips ahead to the oldest frame still there.
+    // Returns the number of frames read.
+    pub fn read(&mut self, samples: &mut Vec<f32>, track_frames: &mut Vec<u64>) -> usize {
+        let ring = &*self.ring;
+        let written = ring.written.load(Ordering::Acquire);
+        let start = self.next.max(written.saturating_sub(ring.capacity));
+        let kept_from = ring.copy(start, written, samples, Some(track_frames));
+        self.next = written;
+        written.saturating_sub(kept_from) as usize
+    }
+
+    // True once the writer is gone and nothing more will arrive
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Interleaved samples for track frames `start..end` that say which
+    // frame and channel they belong to
+    fn frames(start: u64, end: u64) -> Vec<f32> {
+        (start..end).flat_map(|frame| vec![frame as f32, -(frame as f32)]).collect()
+    }
+
+    #[test]
+    fn reader_follows_the_writer_around_the_ring() {
+        let (mut writer, ring) = sample_ring(2, 8);
+        let mut reader = SampleRing::reader(&ring);
+        let (mut samples, mut track_frames) = (Vec::new(), Vec::new());
+        writer.write(100, &frames(100, 105));
+        assert_eq!(reader.read(&mut samples, &mut track_frames), 5);
+        // this wraps past the end of the ring
+        writer.write(105, &frames(105, 111));
+        assert_eq!(reader.read(&mut samples, &mut track_frames), 6);
+        assert_eq!(samples, frames(100, 111));
+        assert_eq!(track_frames, (100..111).collect::<Vec<u64>>());
+        assert_eq!(reader.read(&mut samples, &mut track_frames), 0);
+    }
+
+    #[test]
+    fn lapped_reader_skips_to_the_oldest_frame() {
+        let (mut writer, ring) = sample_ring(2, 8);
+        let mut reader = SampleRing::reader(&ring);
+        let (mut samples, mut track_frames) = (Vec::new(), Vec::new());
+        writer.write(0, &frames(0, 20));
+        assert_eq!(reader.read(&mut samples, &mut track_frames), 8);
+        assert_eq!(samples, frames(12, 20));
+        assert_eq!(track_frames, (12..20).collect::<Vec<u64>>());
+        assert!(!reader.is_closed());
+        drop(writer);
+        assert!(reader.is_closed());
+    }
+
+    #[test]
+    fn recent_ends_at_the_frame_being_heard() {
+        let (mut writer, ring) = sample_ring(2, 16);
+        let mut out = Vec::new();
+        assert_eq!(ring.recent(4, 0, &mut out), 0);
+        writer.write(100, &frames(100, 116));
+        assert_eq!(ring.recent(4, 110, &mut out), 4);
+        assert_eq!(out, frames(106, 110));
+        // not heard that far yet, so the newest frames
+        assert_eq!(ring.recent(4, 1000, &mut out), 4);
+        assert_eq!(out, frames(112, 116));
+        // more than have been played
+        assert_eq!(ring.recent(32, 1000, &mut out), 16);
+        assert_eq!(out, frames(100, 116));
+    }
+
+    #[test]
+    fn concurrent_reads_match_their_track_frames() {
+        let (mut writer, ring) = sample_ring(2, 256);
+        let mut reader = SampleRing::reader(&ring);
+        let total = 500000u64;
+        let writer_thread = thread::spawn(move || {
+            let mut frame = 0;
+            let mut block = 1;
+            while frame < total {
+                let end = (frame + block).min(total);
+                writer.write(frame, &frames(frame, end));
+                frame = end;
+                block = block * 7 % 300 + 1;
+            }
+        });
+        let (mut samples, mut track_frames) = (Vec::new(), Vec::new());
+        let mut last = None;
+        let mut received = 0;
+        loop {
+            let closed = reader.is_closed();
+            samples.clear();
+            track_frames.clear();
+            received += reader.read(&mut samples, &mut track_frames);
+            for (values, &track_frame) in samples.chunks(2).zip(&track_frames) {
+                assert_eq!(values, &[track_frame as f32, -(track_frame as f32)][..]);
+                assert!(last < Some(track_frame));
+                last = Some(track_frame);
+            }
+            if closed {
+                break;
+            }
+        }
+        writer_thread.join().unwrap();
+        assert_eq!(last, Some(total - 1));
+        assert!(received > 0);
+    }
+}
@@ -0,0 +1,818 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use hound;
+use claxon;
+use lewton::inside_ogg::OggStreamReader;
+use minimp3;
+use super::convert::*;
+
+/*
+Everything the rest of the app needs to know about a decoded stream.
+Samples coming out of a source are always interleaved f32 in [-1, 1].
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    // How the samples were stored before we normalized them
+    pub sample_format: SampleFormat,
+    // Total number of frames in the stream, if the container tells us
+    pub total_frames: Option<u64>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Vorbis,
+    Mp3
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    UnknownFormat,
+    Decode(String)
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceError::Io(ref err) => write!(f, "I/O error: {}", err),
+            SourceError::UnknownFormat => write!(f, "unrecognized audio format"),
+            SourceError::Decode(ref msg) => write!(f, "decode error: {}", msg)
+        }
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(err: io::Error) -> SourceError {
+        SourceError::Io(err)
+    }
+}
+
+// A decoder that yields interleaved frames. `read` only ever writes whole
+// frames and returns the number of samples written; 0 means end of stream.
+// `seek` moves the next read to the given frame; seeking past the end
+// leaves the source at the end of the stream.
+pub trait AudioSource {
+    fn spec(&self) -> SourceSpec;
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError>;
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError>;
+}
+
+// Opens an audio file, picking the decoder from the first bytes of the file
+// rather than from its extension.
+pub fn open_source(filename: &str) -> Result<Box<dyn AudioSource + Send>, SourceError> {
+    let mut file = File::open(filename)?;
+    let mut header = [0u8; 12];
+    let header_len = read_header(&mut file, &mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+    let reader = BufReader::new(file);
+
+    match sniff_format(&header[..header_len]) {
+        Some(AudioFormat::Wav) => Ok(Box::new(WavSource::new(reader)?)),
+        Some(AudioFormat::Flac) => Ok(Box::new(FlacSource::new(reader)?)),
+        Some(AudioFormat::Vorbis) => Ok(Box::new(VorbisSource::new(reader)?)),
+        Some(AudioFormat::Mp3) => Ok(Box::new(Mp3Source::new(reader)?)),
+        None => Err(SourceError::UnknownFormat)
+    }
+}
+
+// Identifies the container from its magic bytes
+pub fn sniff_format(header: &[u8]) -> Option<AudioFormat> {
+    if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" {
+        Some(AudioFormat::Wav)
+    } else if header.starts_with(b"fLaC") {
+        Some(AudioFormat::Flac)
+    } else if header.starts_with(b"OggS") {
+        Some(AudioFormat::Vorbis)
+    } else if header.starts_with(b"ID3") ||
+        (header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0) {
+        // either an ID3v2 tag or a bare MPEG audio frame sync
+        Some(AudioFormat::Mp3)
+    } else {
+        None
+    }
+}
+
+fn read_header(file: &mut File, header: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < header.len() {
+        let n = file.read(&mut header[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    Ok(len)
+}
+
+/*
+Holds the most recently decoded packet for decoders that hand us audio
+in blocks of their own choosing, and copies it out in whatever sizes
+the caller asks for.
+*/
+struct PacketBuffer {
+    samples: Vec<f32>,
+    pos: usize
+}
+
+impl PacketBuffer {
+    fn new() -> PacketBuffer {
+        PacketBuffer { samples: Vec::new(), pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.samples.len()
+    }
+
+    fn refill<I: IntoIterator<Item = f32>>(&mut self, samples: I) {
+        self.samples.clear();
+        self.samples.extend(samples);
+        self.pos = 0;
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.pos = 0;
+    }
+
+    fn skip(&mut self, samples: usize) {
+        self.pos = (self.pos + samples).min(self.samples.len());
+    }
+
+    // Copies as many whole frames as fit into `buf`
+    fn drain_into(&mut self, buf: &mut [f32], channels: usize) -> usize {
+        let available = self.samples.len() - self.pos;
+        let n = available.min(buf.len() / channels * channels);
+        buf[..n].copy_from_slice(&self.samples[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+}
+
+/*
+   WAV
+*/
+
+pub struct WavSource {
+    reader: hound::WavReader<BufReader<File>>,
+    spec: SourceSpec
+}
+
+fn read_wav_samples<S, F>(reader: &mut hound::WavReader<BufReader<File>>, buf: &mut [f32],
+    convert: F) -> Result<usize, SourceError>
+    where S: hound::Sample, F: Fn(S) -> f32 {
+    let mut n = 0;
+    for sample in reader.samples::<S>().take(buf.len()) {
+        let sample = sample.map_err(|err| SourceError::Decode(err.to_string()))?;
+        buf[n] = convert(sample);
+        n += 1;
+    }
+    Ok(n)
+}
+
+impl WavSource {
+    pub fn new(reader: BufReader<File>) -> Result<WavSource, SourceError> {
+        let reader = hound::WavReader::new(reader)
+            .map_err(|err| SourceError::Decode(err.to_string()))?;
+        let wav_spec = reader.spec();
+        let sample_format = SampleFormat::from_wav(&wav_spec)
+            .ok_or_else(|| SourceError::Decode(format!("unsupported WAV format: {}-bit {:?}",
+                wav_spec.bits_per_sample, wav_spec.sample_format)))?;
+        let spec = SourceSpec {
+            channels: wav_spec.channels,
+            sample_rate: wav_spec.sample_rate,
+            sample_format,
+            total_frames: Some(reader.duration() as u64)
+        };
+        Ok(WavSource { reader, spec })
+    }
+}
+
+impl AudioSource for WavSource {
+    fn spec(&self) -> SourceSpec {
+        self.spec
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+        let channels = self.spec.channels as usize;
+        let wanted = buf.len() / channels * channels;
+        match self.spec.sample_format {
+            SampleFormat::Float => read_wav_samples(&mut self.reader, &mut buf[..wanted],
+                |s: f32| s),
+            SampleFormat::Int(bits) => read_wav_samples(&mut self.reader, &mut buf[..wanted],
+                |s: i32| int_to_f32(s, bits))
+        }
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+        let frame = frame.min(self.reader.duration() as u64);
+        self.reader.seek(frame as u32)?;
+        Ok(())
+    }
+}
+
+/*
+   FLAC
+*/
+
+type FlacFrames = claxon::frame::FrameReader<claxon::input::BufferedReader<File>>;
+
+pub struct FlacSource {
+    frames: FlacFrames,
+    // second handle to the file, for jumping to another frame, since
+    // claxon can only read forwards
+    file: File,
+    // byte offset of the first audio frame, and the seek points of the
+    // file's SEEKTABLE as (frame, byte offset from the first audio frame)
+    audio_start: u64,
+    seek_points: Vec<(u64, u64)>,
+    spec: SourceSpec,
+    bits: u16,
+    block_buffer: Vec<i32>,
+    packet: PacketBuffer,
+    // frame of the first sample in the packet buffer
+    packet_start: u64
+}
+
+impl FlacSource {
+    pub fn new(reader: BufReader<File>) -> Result<FlacSource, SourceError> {
+        let mut file = reader.get_ref().try_clone()?;
+        let reader = claxon::FlacReader::new(reader)
+            .map_err(|err| SourceError::Decode(err.to_string()))?;
+        let info = reader.streaminfo();
+        let spec = SourceSpec {
+            channels: info.channels as u16,
+            sample_rate: info.sample_rate,
+            sample_format: SampleFormat::Int(info.bits_per_sample as u16),
+            total_frames: info.samples
+        };
+        // claxon skips the seek table, so go over the metadata again
+        file.seek(SeekFrom::Start(0))?;
+        let (audio_start, seek_points) = read_flac_layout(&mut BufReader::new(file.try_clone()?))?;
+        let frames = open_flac_frames(&mut file, audio_start)?;
+        Ok(FlacSource {
+            frames, file, audio_start, seek_points, spec,
+            bits: info.bits_per_sample as u16,
+            block_buffer: Vec::new(),
+            packet: PacketBuffer::new(),
+            packet_start: 0
+        })
+    }
+
+    // Decodes the next FLAC block into the packet buffer, interleaving as
+    // we go. Returns the number of the block's first frame, or None at the
+    // end of the stream. The frame is counted rather than taken from the
+    // block, since claxon gets it wrong for a short last block.
+    fn decode_block(&mut self) -> Result<Option<u64>, SourceError> {
+        let buffer = ::std::mem::take(&mut self.block_buffer);
+        let block = self.frames.read_next_or_eof(buffer)
+            .map_err(|err| SourceError::Decode(err.to_string()))?;
+        match block {
+            Some(block) => {
+                let bits = self.bits;
+                let channels = block.channels();
+                let frames = block.duration();
+                self.packet_start += (self.packet.samples.len() / channels as usize) as u64;
+                self.packet.refill((0..frames).flat_map(|i| {
+                    let block = &block;
+                    (0..channels).map(move |ch| int_to_f32(block.sample(ch, i), bits))
+                }));
+                self.block_buffer = block.into_buffer();
+                Ok(Some(self.packet_start))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+// Starts reading audio frames `offset` bytes into the file
+fn open_flac_frames(file: &mut File, offset: u64) -> Result<FlacFrames, SourceError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let input = claxon::input::BufferedReader::new(file.try_clone()?);
+    Ok(claxon::frame::FrameReader::new(input))
+}
+
+// Finds where the audio frames of a FLAC stream start and the seek points
+// it carries, as `FlacSource` keeps them. Placeholder points are left out.
+fn read_flac_layout<R: Read>(reader: &mut R) -> io::Result<(u64, Vec<(u64, u64)>)> {
+    let be = |bytes: &[u8]| bytes.iter().fold(0u64, |n, &byte| n << 8 | byte as u64);
+    let mut header = [0u8; 4];
+    // the "fLaC" marker
+    reader.read_exact(&mut header)?;
+    let mut offset = 4u64;
+    let mut seek_points = Vec::new();
+    loop {
+        reader.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let length = be(&header[1..4]);
+        offset += 4 + length;
+        let mut block = Vec::new();
+        reader.by_ref().take(length).read_to_end(&mut block)?;
+        // block type 3 is the SEEKTABLE, 18 bytes a point
+        if header[0] & 0x7F == 3 {
+            seek_points.extend(block.chunks_exact(18)
+                .map(|point| (be(&point[0..8]), be(&point[8..16])))
+                .filter(|&(frame, _)| frame != u64::MAX));
+        }
+        if last {
+            return Ok((offset, seek_points));
+        }
+    }
+}
+
+impl AudioSource for FlacSource {
+    fn spec(&self) -> SourceSpec {
+        self.spec
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+        let channels = self.spec.channels as usize;
+        let mut n = 0;
+        while n + channels <= buf.len() {
+            if self.packet.is_empty() && self.decode_block()?.is_none() {
+                break;
+            }
+            n += self.packet.drain_into(&mut buf[n..], channels);
+        }
+        Ok(n)
+    }
+
+    // Decodes forward from the current position when the target is ahead.
+    // Otherwise, or when a seek point lies between the two, jumps to the
+    // last seek point before the target, or back to the first frame when
+    // the file has no seek table, and decodes forward from there.
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+        let channels = self.spec.channels as usize;
+        let current = self.packet_start + (self.packet.pos / channels) as u64;
+        let (point_frame, point_offset) = self.seek_points.iter().rev()
+            .find(|&&(point_frame, _)| point_frame <= frame)
+            .cloned()
+            .unwrap_or((0, 0));
+        if frame < current || point_frame > current {
+            self.frames = open_flac_frames(&mut self.file, self.audio_start + point_offset)?;
+            self.packet.clear();
+            self.packet_start = point_frame;
+        }
+        loop {
+            let frames = (self.packet.samples.len() / channels) as u64;
+            if self.packet_start + frames > frame {
+                let current = self.packet_start + (self.packet.pos / channels) as u64;
+                self.packet.skip(frame.saturating_sub(current) as usize * channels);
+                return Ok(());
+            }
+            if self.decode_block()?.is_none() {
+                // past the end, which is where this leaves us
+                self.packet_start += frames;
+                self.packet.clear();
+                return Ok(());
+            }
+        }
+    }
+}
+
+/*
+   Ogg Vorbis
+*/
+
+pub struct VorbisSource {
+    reader: OggStreamReader<BufReader<File>>,
+    // second handle to the file for rewinding to the first audio page
+    file: File,
+    spec: SourceSpec,
+    packet: PacketBuffer
+}
+
+impl VorbisSource {
+    pub fn new(reader: BufReader<File>) -> Result<VorbisSource, SourceError> {
+        let file = reader.get_ref().try_clone()?;
+        let reader = OggStreamReader::new(reader)
+            .map_err(|err| SourceError::Decode(err.to_string()))?;
+        let spec = SourceSpec {
+            channels: reader.ident_hdr.audio_channels as u16,
+            sample_rate: reader.ident_hdr.audio_sample_rate,
+            sample_format: SampleFormat::Int(16),
+            total_frames: None
+        };
+        Ok(VorbisSource { reader, file, spec, packet: PacketBuffer::new() })
+    }
+
+    // Decodes up to the end of the first page finishing after `frame` and
+    // keeps that page's samples. Returns the frame they start at, or None
+    // at the end of the stream.
+    fn decode_page_past(&mut self, frame: u64) -> Result<Option<u64>, SourceError> {
+        let channels = self.spec.channels as usize;
+        // the granule position is only known once a page has been completed,
+        // so hold on to what is decoded until then and count back from it
+        let mut pending: Vec<i16> = Vec::new();
+        loop {
+            let packet = self.reader.read_dec_packet_itl()
+                .map_err(|err| SourceError::Decode(err.to_string()))?;
+            match packet {
+                Some(samples) => pending.extend(samples),
+                None => return Ok(None)
+            }
+            // from then on, it is the frame just past every packet
+            if let Some(end) = self.reader.get_last_absgp() {
+                if end > frame {
+                    let start = end.saturating_sub((pending.len() / channels) as u64);
+                    self.packet.refill(pending.into_iter().map(i16_to_f32));
+                    return Ok(Some(start));
+                }
+                pending.clear();
+            }
+        }
+    }
+}
+
+impl AudioSource for VorbisSource {
+    fn spec(&self) -> SourceSpec {
+        self.spec
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+        let channels = self.spec.channels as usize;
+        let mut n = 0;
+        while n + channels <= buf.len() {
+            if self.packet.is_empty() {
+                let packet = self.reader.read_dec_packet_itl()
+                    .map_err(|err| SourceError::Decode(err.to_string()))?;
+                match packet {
+                    Some(samples) => self.packet.refill(samples.into_iter().map(i16_to_f32)),
+                    None => break
+                }
+            }
+            n += self.packet.drain_into(&mut buf[n..], channels);
+        }
+        Ok(n)
+    }
+
+    // lewton only seeks to page boundaries, so decode from the page before
+    // the target and drop the samples in front of it
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+        let channels = self.spec.channels as usize;
+        // the first packet after a seek only primes the decoder, so when the
+        // target falls in it, go back a page and try again. No packet adds
+        // more than half a long block.
+        let back = (1u64 << self.reader.ident_hdr.blocksize_1) / 2;
+        let mut goal = frame;
+        loop {
+            if goal == 0 {
+                // granule 0 is the header pages, so start over instead
+                let mut file = self.file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                self.reader = OggStreamReader::new(BufReader::new(file))
+                    .map_err(|err| SourceError::Decode(err.to_string()))?;
+            } else {
+                self.reader.seek_absgp_pg(goal)
+                    .map_err(|err| SourceError::Decode(err.to_string()))?;
+            }
+            self.packet.clear();
+            match self.decode_page_past(frame)? {
+                Some(start) if start > frame && goal > 0 => goal = start.saturating_sub(back),
+                Some(start) => {
+                    self.packet.skip(frame.saturating_sub(start) as usize * channels);
+                    return Ok(());
+                },
+                None => return Ok(())
+            }
+        }
+    }
+}
+
+/*
+   MP3
+*/
+
+pub struct Mp3Source {
+    decoder: Mp3Decoder,
+    // second handle to the file for rewinding, minimp3 cannot seek
+    file: File,
+    spec: SourceSpec,
+    packet: PacketBuffer,
+    // frame of the first sample in the packet buffer
+    packet_start: u64,
+    finished: bool
+}
+
+impl Mp3Source {
+    pub fn new(reader: BufReader<File>) -> Result<Mp3Source, SourceError> {
+        // MP3 has no header with the stream parameters, so decode the first
+        // frame up front and keep it around for the first read
+        let file = reader.get_ref().try_clone()?;
+        let mut decoder = Mp3Decoder::new(reader);
+        let frame = decoder.next_frame()?
+            .ok_or_else(|| SourceError::Decode("no MPEG audio frames found".to_string()))?;
+        let spec = SourceSpec {
+            channels: frame.channels as u16,
+            sample_rate: frame.sample_rate,
+            sample_format: SampleFormat::Int(16),
+            total_frames: None
+        };
+        let mut packet = PacketBuffer::new();
+        packet.refill(frame.samples.into_iter().map(i16_to_f32));
+        Ok(Mp3Source { decoder, file, spec, packet, packet_start: 0, finished: false })
+    }
+}
+
+// Bytes of the file kept ahead of the decoder, enough for minimp3 to check
+// the frames after the one it syncs to
+const MP3_BUFFER_BYTES: usize = minimp3::MAX_SAMPLES_PER_FRAME * 8;
+
+// One decoded MPEG audio frame, interleaved
+struct Mp3Frame {
+    samples: Vec<i16>,
+    channels: usize,
+    sample_rate: u32
+}
+
+/*
+Feeds a file to minimp3's frame decoder. The reader minimp3 comes with
+keeps its input in a SliceDeque, which writes out of bounds on every
+refill (and aborts debug builds), so the bytes are buffered here instead.
+*/
+struct Mp3Decoder {
+    reader: BufReader<File>,
+    decoder: Box<minimp3::ffi::mp3dec_t>,
+    buffer: Vec<u8>,
+    // bytes at the front of `buffer` already decoded
+    start: usize,
+    eof: bool
+}
+
+impl Mp3Decoder {
+    fn new(reader: BufReader<File>) -> Mp3Decoder {
+        // an all zero state is what mp3dec_init leaves too
+        let mut decoder: Box<minimp3::ffi::mp3dec_t> = Box::new(unsafe { ::std::mem::zeroed() });
+        unsafe { minimp3::ffi::mp3dec_init(&mut *decoder) };
+        Mp3Decoder { reader, decoder, buffer: Vec::new(), start: 0, eof: false }
+    }
+
+    // The next frame, or None at the end of the stream. ID3 tags and other
+    // junk between frames are skipped.
+    fn next_frame(&mut self) -> Result<Option<Mp3Frame>, SourceError> {
+        // set when the frame at the front is cut off by the end of the buffer
+        let mut starved = false;
+        loop {
+            if !self.eof && (starved || self.buffer.len() - self.start < MP3_BUFFER_BYTES) {
+                self.fill()?;
+            }
+            let input = &self.buffer[self.start..];
+            if input.is_empty() {
+                return Ok(None);
+            }
+            let mut info: minimp3::ffi::mp3dec_frame_info_t = unsafe { ::std::mem::zeroed() };
+            let mut samples = vec![0i16; minimp3::MAX_SAMPLES_PER_FRAME];
+            let frames = unsafe {
+                minimp3::ffi::mp3dec_decode_frame(&mut *self.decoder, input.as_ptr(), input.len() as i32,
+                    samples.as_mut_ptr(), &mut info)
+            } as usize;
+            self.start += info.frame_bytes as usize;
+            if frames > 0 {
+                samples.truncate(frames * info.channels as usize);
+                return Ok(Some(Mp3Frame {
+                    samples,
+                    channels: info.channels as usize,
+                    sample_rate: info.hz as u32
+                }));
+            }
+            if info.frame_bytes == 0 && self.eof {
+                return Ok(None);
+            }
+            starved = info.frame_bytes == 0;
+        }
+    }
+
+    // Drops the bytes already decoded and reads more of the file
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        let len = self.buffer.len();
+        self.buffer.resize(len + MP3_BUFFER_BYTES, 0);
+        let n = self.reader.read(&mut self.buffer[len..])?;
+        self.buffer.truncate(len + n);
+        self.eof = n == 0;
+        Ok(())
+    }
+}
+
+impl AudioSource for Mp3Source {
+    fn spec(&self) -> SourceSpec {
+        self.spec
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+        let channels = self.spec.channels as usize;
+        let mut n = 0;
+        while n + channels <= buf.len() && !self.finished {
+            if self.packet.is_empty() {
+                match self.decoder.next_frame()? {
+                    Some(frame) => {
+                        if frame.channels != channels {
+                            return Err(SourceError::Decode(
+                                "channel count changed mid-stream".to_string()));
+                        }
+                        self.packet_start += (self.packet.samples.len() / channels) as u64;
+                        self.packet.refill(frame.samples.into_iter().map(i16_to_f32));
+                    },
+                    None => {
+                        self.finished = true;
+                        break;
+                    }
+                }
+            }
+            n += self.packet.drain_into(&mut buf[n..], channels);
+        }
+        Ok(n)
+    }
+
+    // Without a frame index the only way to find a position in an MP3 is
+    // to decode and count frames: forward from here when the target is
+    // ahead, or from the start when it is behind
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+        let channels = self.spec.channels as usize;
+        let current = self.packet_start + (self.packet.pos / channels) as u64;
+        if frame < current {
+            let mut file = self.file.try_clone()?;
+            file.seek(SeekFrom::Start(0))?;
+            self.decoder = Mp3Decoder::new(BufReader::new(file));
+            self.packet.clear();
+            self.packet_start = 0;
+            self.finished = false;
+        }
+        loop {
+            let frames = (self.packet.samples.len() / channels) as u64;
+            if self.packet_start + frames > frame {
+                let current = self.packet_start + (self.packet.pos / channels) as u64;
+                self.packet.skip(frame.saturating_sub(current) as usize * channels);
+                return Ok(());
+            }
+            if self.finished {
+                // past the end, which is where this leaves us
+                self.packet_start += frames;
+                self.packet.clear();
+                return Ok(());
+            }
+            match self.decoder.next_frame()? {
+                Some(mp3_frame) => {
+                    self.packet_start += frames;
+                    self.packet.refill(mp3_frame.samples.into_iter().map(i16_to_f32));
+                },
+                None => self.finished = true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::f64::consts::PI;
+    use std::fs;
+
+    // Path of one of the files in testdata/, which make_fixtures.py there
+    // writes
+    fn fixture(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    // Every sample left in a source, read in awkwardly sized pieces
+    fn read_to_end(source: &mut dyn AudioSource) -> Vec<f32> {
+        let mut samples = Vec::new();
+        let mut buf = vec![0f32; 1001];
+        loop {
+            let n = source.read(&mut buf).unwrap();
+            if n == 0 {
+                return samples;
+            }
+            samples.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn sniffs_every_magic_number() {
+        assert_eq!(sniff_format(b"RIFF\x24\x08\x00\x00WAVE"), Some(AudioFormat::Wav));
+        assert_eq!(sniff_format(b"fLaC\x00\x00\x00\x22\x10\x00\x10\x00"), Some(AudioFormat::Flac));
+        assert_eq!(sniff_format(b"OggS\x00\x02\x00\x00\x00\x00\x00\x00"), Some(AudioFormat::Vorbis));
+        assert_eq!(sniff_format(b"ID3\x04\x00\x00\x00\x00\x00\x16TS"), Some(AudioFormat::Mp3));
+        assert_eq!(sniff_format(&[0xff, 0xfb, 0x18, 0xc0, 0, 0, 0, 0, 0, 0, 0, 0]), Some(AudioFormat::Mp3));
+        // MPEG-2 Layer III, whose sync word differs in the last bits
+        assert_eq!(sniff_format(&[0xff, 0xf3, 0x80, 0xc4]), Some(AudioFormat::Mp3));
+    }
+
+    #[test]
+    fn rejects_unknown_and_short_headers() {
+        // a RIFF container holding something other than WAVE
+        assert_eq!(sniff_format(b"RIFF\x24\x08\x00\x00AVI "), None);
+        // too short to tell it is WAVE
+        assert_eq!(sniff_format(b"RIFF\x24\x08"), None);
+        assert_eq!(sniff_format(b""), None);
+        assert_eq!(sniff_format(&[0xff]), None);
+        // 0xff not followed by the rest of a frame sync
+        assert_eq!(sniff_format(&[0xff, 0x1b, 0, 0]), None);
+        assert_eq!(sniff_format(b"<html><body>"), None);
+    }
+
+    #[test]
+    fn open_source_reports_unknown_files() {
+        let path = env::temp_dir().join("final_proj_source_unknown.txt");
+        fs::write(&path, b"not audio at all").unwrap();
+        let result = open_source(path.to_str().unwrap());
+        fs::remove_file(&path).ok();
+        assert!(matches!(result, Err(SourceError::UnknownFormat)));
+        assert!(matches!(open_source(&fixture("missing.flac")), Err(SourceError::Io(_))));
+    }
+
+    #[test]
+    fn decodes_flac_fixture() {
+        let mut source = open_source(&fixture("tone.flac")).unwrap();
+        assert_eq!(source.spec(), SourceSpec {
+            channels: 2,
+            sample_rate: 8000,
+            sample_format: SampleFormat::Int(16),
+            total_frames: Some(2660)
+        });
+        let samples = read_to_end(&mut *source);
+        assert_eq!(samples.len(), 2 * 2660);
+        // the left channel is a 440 Hz sine at 12000 / 32768
+        for (n, frame) in samples.chunks(2).enumerate().take(100) {
+            let expected = (12000f64 * (2f64 * PI * 440f64 * n as f64 / 8000f64).sin()) as i32;
+            assert_eq!(frame[0], int_to_f32(expected, 16));
+        }
+    }
+
+    #[test]
+    fn decodes_vorbis_fixture() {
+        let mut source = open_source(&fixture("noise.ogg")).unwrap();
+        assert_eq!(source.spec(), SourceSpec {
+            channels: 2,
+            sample_rate: 8000,
+            sample_format: SampleFormat::Int(16),
+            total_frames: None
+        });
+        let samples = read_to_end(&mut *source);
+        // 41 packets of 256-sample blocks, the first only priming the overlap
+        assert_eq!(samples.len(), 2 * 40 * 128);
+        assert!(samples.iter().any(|&x| x != 0f32));
+    }
+
+    #[test]
+    fn decodes_mp3_fixture() {
+        let mut source = open_source(&fixture("noise.mp3")).unwrap();
+        assert_eq!(source.spec(), SourceSpec {
+            channels: 1,
+            sample_rate: 32000,
+            sample_format: SampleFormat::Int(16),
+            total_frames: None
+        });
+        let samples = read_to_end(&mut *source);
+        assert_eq!(samples.len(), 20 * 1152);
+        assert!(samples.iter().any(|&x| x != 0f32));
+    }
+
+    // Seeks a source forwards, backwards, past the end and back to the
+    // start, checking each time that what follows matches a straight decode
+    fn check_seeks(name: &str, targets: &[u64]) {
+        let straight = read_to_end(&mut *open_source(&fixture(name)).unwrap());
+        let mut source = open_source(&fixture(name)).unwrap();
+        let channels = source.spec().channels as usize;
+        let total = (straight.len() / channels) as u64;
+        let mut buf = vec![0f32; 300 * channels];
+        // start somewhere in the middle of a block
+        source.read(&mut buf[..37 * channels]).unwrap();
+        for &target in targets.iter().chain(&[total + 500, 0]) {
+            source.seek(target).unwrap();
+            let n = source.read(&mut buf).unwrap();
+            let start = (target.min(total) as usize) * channels;
+            let expected = &straight[start..(start + buf.len()).min(straight.len())];
+            assert_eq!(&buf[..n], expected, "{} after seeking to {}", name, target);
+        }
+    }
+
+    #[test]
+    fn seeks_flac_with_and_without_a_table() {
+        // forward past a seek point, backward within the same stretch,
+        // backward to before the previous point, and into the short last block
+        let targets = [1900, 1800, 700, 2600];
+        check_seeks("tone.flac", &targets);
+        check_seeks("tone_no_table.flac", &targets);
+    }
+
+    #[test]
+    fn seeks_vorbis() {
+        // forward across pages, backward within a page and to an earlier one
+        check_seeks("noise.ogg", &[3000, 2950, 700, 5000]);
+    }
+
+    #[test]
+    fn seeks_mp3() {
+        // forward across frames, backward within a frame and to an earlier one
+        check_seeks("noise.mp3", &[15000, 14200, 1200, 22800]);
+    }
+
+}
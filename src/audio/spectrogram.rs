@@ -0,0 +1,136 @@
+use super::analysis::*;
+use super::bands::*;
+use super::channels::*;
+use super::offline::*;
+use super::source::*;
+
+// Lowest frequency shown on a log axis unless another is given
+const LOG_MIN_HZ: f32 = 20f32;
+
+// How frequencies are spread up the height of a spectrogram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrequencyAxis {
+    Linear,
+    // log scale from the given frequency up
+    Log(f32),
+    Mel
+}
+
+impl FrequencyAxis {
+    // Parses "linear", "mel" or "log" (optionally with the lowest frequency
+    // shown, e.g. "log:40")
+    pub fn from_name(name: &str) -> Option<FrequencyAxis> {
+        let mut parts = name.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("linear"), None) => Some(FrequencyAxis::Linear),
+            (Some("mel"), None) => Some(FrequencyAxis::Mel),
+            (Some("log"), None) => Some(FrequencyAxis::Log(LOG_MIN_HZ)),
+            (Some("log"), Some(min_hz)) => min_hz.parse().ok()
+                .filter(|&min_hz| min_hz > 0f32)
+                .map(FrequencyAxis::Log),
+            _ => None
+        }
+    }
+
+    // The frequency `position` of the way up an axis reaching `max_hz`
+    pub fn frequency(self, position: f32, max_hz: f32) -> f32 {
+        match self {
+            FrequencyAxis::Linear => position * max_hz,
+            FrequencyAxis::Log(min_hz) => min_hz * (max_hz / min_hz).powf(position),
+            FrequencyAxis::Mel => mel_to_hz(position * hz_to_mel(max_hz))
+        }
+    }
+
+    // How far up an axis reaching `max_hz` a frequency is, from 0 to 1
+    // inside the axis
+    pub fn position(self, hz: f32, max_hz: f32) -> f32 {
+        match self {
+            FrequencyAxis::Linear => hz / max_hz,
+            FrequencyAxis::Log(min_hz) => (hz / min_hz).ln() / (max_hz / min_hz).ln(),
+            FrequencyAxis::Mel => hz_to_mel(hz) / hz_to_mel(max_hz)
+        }
+    }
+}
+
+/*
+Where the level of every row of a spectrogram comes from: the loudest bin
+inside it, or where no bin is, the two bins either side of its centre.
+*/
+enum RowSource {
+    Bins(usize, usize),
+    Between(usize, f32)
+}
+
+/*
+The spectrum of every signal of a file frame by frame, as the analysis
+sees it, resampled onto `rows` rows up a frequency axis.
+*/
+pub struct Spectrogram {
+    // time of the first frame's centre, and the time between frames
+    pub start_secs: f64,
+    pub frame_secs: f64,
+    pub axis: FrequencyAxis,
+    // frequency at the top of the axis (Nyquist)
+    pub max_hz: f32,
+    pub rows: usize,
+    // level in dB of every row, lowest frequency first, of every signal in
+    // every frame ([frame][signal][row]). A full scale sine reads 0 dB.
+    pub frames: Vec<Vec<Vec<f32>>>
+}
+
+impl Spectrogram {
+    pub fn time_secs(&self, frame: usize) -> f64 {
+        self.start_secs + frame as f64 * self.frame_secs
+    }
+
+    pub fn signal_count(&self) -> usize {
+        self.frames.first().map_or(0, |frame| frame.len())
+    }
+}
+
+// Works out the spectrogram of every signal `mode` makes of a file on
+// `threads` threads, telling `progress` how far it has got
+pub fn spectrogram(filename: &str, config: &AnalysisConfig, mode: ChannelMode, axis: FrequencyAxis,
+    rows: usize, threads: usize, progress: &mut dyn FnMut(Progress)) -> Result<Spectrogram, SourceError> {
+    let mut source = open_source(filename)?;
+    let spec = source.spec();
+    let max_hz = spec.sample_rate as f32 / 2f32;
+    if let FrequencyAxis::Log(min_hz) = axis {
+        if min_hz >= max_hz {
+            return Err(SourceError::Decode(format!("log axis starts at {} Hz, not below the Nyquist frequency of {} Hz",
+                min_hz, max_hz)));
+        }
+    }
+    let bin_width = config.bin_width(spec.sample_rate);
+    let last_bin = config.num_bins() - 1;
+    let sources: Vec<RowSource> = (0..rows).map(|row| {
+        let hz = |position: f32| axis.frequency(position / rows as f32, max_hz) / bin_width;
+        let (low, high) = (hz(row as f32).ceil() as usize, hz(row as f32 + 1f32).floor() as usize);
+        if low <= high {
+            RowSource::Bins(low.min(last_bin), high.min(last_bin))
+        } else {
+            let centre = hz(row as f32 + 0.5f32);
+            RowSource::Between((centre as usize).min(last_bin - 1), centre.fract())
+        }
+    }).collect();
+    let sources = &sources;
+
+    let frames = analyse_frames(&mut *source, config, mode, threads, || {
+        move |stft: &mut Stft, signals: &[&[f32]]| signals.iter().map(|signal| {
+            let magnitudes = stft.process(signal);
+            sources.iter().map(|source| {
+                let magnitude = match *source {
+                    RowSource::Bins(low, high) => magnitudes[low..high + 1].iter().cloned().fold(0f32, f32::max),
+                    RowSource::Between(bin, fraction) =>
+                        magnitudes[bin] + fraction * (magnitudes[bin + 1] - magnitudes[bin])
+                };
+                to_db(magnitude * magnitude)
+            }).collect()
+        }).collect()
+    }, progress)?;
+    Ok(Spectrogram {
+        start_secs: config.centre_offset_secs(spec.sample_rate),
+        frame_secs: config.hop_size as f64 / spec.sample_rate as f64,
+        axis, max_hz, rows, frames
+    })
+}
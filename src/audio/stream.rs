@@ -0,0 +1,470 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::thread::JoinHandle;
+use super::source::*;
+use super::resample::*;
+use super::convert::*;
+use super::controller::*;
+use super::clock::*;
+use super::ring::*;
+
+// Number of frames the decoder reads from a source at a time
+pub const CHUNK_FRAMES: usize = 4096;
+// Number of decoded chunks allowed to queue up ahead of the audio callback.
+// Together with CHUNK_FRAMES this bounds the memory used by playback.
+pub const QUEUE_CHUNKS: usize = 8;
+
+// Reads a source chunk by chunk, handing each block of interleaved samples
+// to `f`, so callers never need the whole file in memory
+pub fn for_each_chunk<F>(source: &mut dyn AudioSource, mut f: F) -> Result<(), SourceError>
+    where F: FnMut(&[f32]) {
+    let mut buf = vec![0f32; CHUNK_FRAMES * source.spec().channels as usize];
+    loop {
+        let n = source.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        f(&buf[..n]);
+    }
+}
+
+// A block of decoded audio on its way to the callback. An empty chunk
+// with `end` set marks the end of the track.
+struct Chunk {
+    generation: u64,
+    // output frame of the first sample in the chunk
+    start_frame: u64,
+    samples: Vec<f32>,
+    end: bool
+}
+
+impl Chunk {
+    fn end(generation: u64, start_frame: u64) -> Chunk {
+        Chunk { generation, start_frame, samples: Vec::new(), end: true }
+    }
+}
+
+/*
+The audio callback's end of the decoder queue. Spent chunks are handed
+back to the decoder thread for reuse, so the realtime thread never has
+to free memory. The way back has room for every chunk in flight and is
+never waited on; should it ever be full, the chunk is dropped instead.
+*/
+pub struct StreamBuffer {
+    chunk_rx: Receiver<Chunk>,
+    recycle_tx: SyncSender<Vec<f32>>,
+    state: Arc<PlaybackState>,
+    channels: usize,
+    current: Option<Chunk>,
+    pos: usize,
+    ring: Option<RingWriter>
+}
+
+impl StreamBuffer {
+    // Fills a device buffer with the next samples. If the decoder has fallen
+    // behind, playback is paused, or the stream has ended, the rest of the
+    // buffer is silenced. Chunks always hold whole frames, so the silence
+    // never shifts channels.
+    // Returns false once the track has ended or playback was stopped.
+    pub fn fill<S: DeviceSample>(&mut self, buffer: &mut [S]) -> bool {
+        let silence = S::from_normalized(0f32);
+        // read before the generation, so a seek in between is caught below
+        let mut reported = self.state.position.load(Ordering::SeqCst);
+        let generation = self.state.generation.load(Ordering::SeqCst);
+        if self.state.stopped.load(Ordering::SeqCst) {
+            fill_silence(buffer, silence);
+            return false;
+        }
+        if self.state.paused.load(Ordering::SeqCst) {
+            // keep clearing out stale audio so a seek while paused can't
+            // leave the decoder blocked on a full queue
+            self.discard_stale(generation);
+            fill_silence(buffer, silence);
+            return true;
+        }
+
+        let mut i = 0;
+        while i < buffer.len() {
+            if !self.has_current(generation) {
+                self.recycle_current();
+                match self.chunk_rx.try_recv() {
+                    Ok(chunk) => {
+                        if chunk.generation != generation {
+                            self.recycle(chunk.samples);
+                            continue;
+                        }
+                        if chunk.end {
+                            fill_silence(&mut buffer[i..], silence);
+                            return false;
+                        }
+                        self.current = Some(chunk);
+                        self.pos = 0;
+                    },
+                    Err(err) => {
+                        fill_silence(&mut buffer[i..], silence);
+                        return err == TryRecvError::Empty;
+                    }
+                }
+            }
+            if let Some(ref chunk) = self.current {
+                let n = (chunk.samples.len() - self.pos).min(buffer.len() - i);
+                for (out_sample, &sample) in buffer[i..i + n].iter_mut()
+                    .zip(&chunk.samples[self.pos..self.pos + n]) {
+                    *out_sample = S::from_normalized(sample);
+                }
+                if let Some(ref mut ring) = self.ring {
+                    let frame = chunk.start_frame + (self.pos / self.channels) as u64;
+                    ring.write(frame, &chunk.samples[self.pos..self.pos + n]);
+                }
+                self.pos += n;
+                i += n;
+                // a seek since this buffer started owns the position now,
+                // so only move on from what was last reported here
+                let frame = chunk.start_frame + (self.pos / self.channels) as u64;
+                let position = &self.state.position;
+                if self.state.generation.load(Ordering::SeqCst) == generation &&
+                    position.compare_exchange(reported, frame, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                    reported = frame;
+                }
+            }
+        }
+        true
+    }
+
+    // Copies everything played from now on into a sample ring, for the
+    // visuals and the live analysis
+    pub fn set_ring(&mut self, ring: RingWriter) {
+        self.ring = Some(ring);
+    }
+
+    // Track frame just past the last one handed out by `fill`
+    pub fn position(&self) -> u64 {
+        self.state.position.load(Ordering::SeqCst)
+    }
+
+    fn has_current(&self, generation: u64) -> bool {
+        match self.current {
+            Some(ref chunk) => chunk.generation == generation && self.pos < chunk.samples.len(),
+            None => false
+        }
+    }
+
+    fn recycle_current(&mut self) {
+        if let Some(chunk) = self.current.take() {
+            self.recycle(chunk.samples);
+        }
+    }
+
+    // Hands a spent buffer back to the decoder, or drops it if the decoder
+    // has stopped taking them
+    fn recycle(&self, samples: Vec<f32>) {
+        self.recycle_tx.try_send(samples).ok();
+    }
+
+    fn discard_stale(&mut self, generation: u64) {
+        if self.has_current(generation) {
+            return;
+        }
+        self.recycle_current();
+        while let Ok(chunk) = self.chunk_rx.try_recv() {
+            if chunk.generation == generation {
+                // nothing stale is left; hold on to it for when we resume
+                self.current = Some(chunk);
+                self.pos = 0;
+                return;
+            }
+            self.recycle(chunk.samples);
+        }
+    }
+}
+
+fn fill_silence<S: Copy>(buffer: &mut [S], silence: S) {
+    for out_sample in buffer {
+        *out_sample = silence;
+    }
+}
+
+// Starts a thread that decodes `source`, converts it to `out_rate` and keeps
+// the returned buffer topped up. The returned controller steers both the
+// decoder and the buffer. The thread exits once playback is stopped or the
+// buffer is dropped, or with the error that ended playback early.
+pub fn spawn_decoder(mut source: Box<dyn AudioSource + Send>, out_rate: u32)
+    -> (StreamBuffer, PlaybackController, JoinHandle<Result<(), SourceError>>) {
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(QUEUE_CHUNKS);
+    let (recycle_tx, recycle_rx) = mpsc::sync_channel(QUEUE_CHUNKS + 1);
+    let (command_tx, command_rx) = mpsc::channel();
+    let state = Arc::new(PlaybackState::new());
+
+    let spec = source.spec();
+    let channels = spec.channels as usize;
+    let duration = spec.total_frames
+        .map(|frames| frames * out_rate as u64 / spec.sample_rate as u64);
+    let clock = Arc::new(PlaybackClock::new(out_rate));
+    let controller = PlaybackController::new(state.clone(), command_tx, clock, out_rate, duration);
+
+    let handle = thread::spawn(move || {
+        let new_resampler = || if spec.sample_rate != out_rate {
+            Some(Resampler::new(channels, spec.sample_rate, out_rate))
+        } else {
+            None
+        };
+        let mut resampler = new_resampler();
+        let mut buf = vec![0f32; CHUNK_FRAMES * channels];
+        let mut generation = 0;
+        let mut out_frame = 0;
+        let mut at_end = false;
+
+        loop {
+            // once the whole track is queued, sleep until someone seeks or stops
+            let command = if at_end {
+                match command_rx.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return Ok(())
+                }
+            } else {
+                command_rx.try_recv().ok()
+            };
+            match command {
+                Some(DecoderCommand::Stop) => return Ok(()),
+                Some(DecoderCommand::Seek { generation: new_generation, frame }) => {
+                    let source_frame = frame * spec.sample_rate as u64 / out_rate as u64;
+                    generation = new_generation;
+                    if let Err(err) = source.seek(source_frame) {
+                        // end playback where the seek was asked for
+                        chunk_tx.send(Chunk::end(generation, frame)).ok();
+                        return Err(err);
+                    }
+                    resampler = new_resampler();
+                    out_frame = frame;
+                    at_end = false;
+                    continue;
+                },
+                None => {}
+            }
+
+            let n = match source.read(&mut buf) {
+                Ok(n) => n,
+                Err(err) => {
+                    // end playback with what was already queued
+                    chunk_tx.send(Chunk::end(generation, out_frame)).ok();
+                    return Err(err);
+                }
+            };
+            let mut out: Vec<f32> = recycle_rx.try_recv().unwrap_or_default();
+            out.clear();
+            match resampler {
+                Some(ref mut resampler) if n == 0 => resampler.flush(&mut out),
+                Some(ref mut resampler) => resampler.process(&buf[..n], &mut out),
+                None => out.extend_from_slice(&buf[..n])
+            }
+
+            // a failed send means playback is over, so stop decoding too
+            let frames = (out.len() / channels) as u64;
+            if frames > 0 {
+                let chunk = Chunk { generation, start_frame: out_frame, samples: out, end: false };
+                if chunk_tx.send(chunk).is_err() {
+                    return Ok(());
+                }
+                out_frame += frames;
+            }
+            if n == 0 {
+                if chunk_tx.send(Chunk::end(generation, out_frame)).is_err() {
+                    return Ok(());
+                }
+                at_end = true;
+            }
+        }
+    });
+
+    let buffer = StreamBuffer {
+        chunk_rx, recycle_tx, state, channels,
+        current: None,
+        pos: 0,
+        ring: None
+    };
+    (buffer, controller, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A stereo source whose left channel counts frames up from 0 in steps
+    // of 1 / 65536 and whose right channel counts down, optionally failing
+    // once it reaches `fail_at`
+    struct CountingSource {
+        frames: u64,
+        pos: u64,
+        fail_at: Option<u64>
+    }
+
+    impl AudioSource for CountingSource {
+        fn spec(&self) -> SourceSpec {
+            SourceSpec {
+                channels: 2,
+                sample_rate: 1000,
+                sample_format: SampleFormat::Float,
+                total_frames: Some(self.frames)
+            }
+        }
+
+        fn read(&mut self, buf: &mut [f32]) -> Result<usize, SourceError> {
+            let end = self.fail_at.unwrap_or(self.frames).min(self.frames);
+            if self.fail_at == Some(self.pos) {
+                return Err(SourceError::Decode("bad block".to_string()));
+            }
+            let frames = ((buf.len() / 2) as u64).min(end - self.pos) as usize;
+            for frame in buf[..frames * 2].chunks_mut(2) {
+                frame.copy_from_slice(&counting(self.pos, self.pos + 1));
+                self.pos += 1;
+            }
+            Ok(frames * 2)
+        }
+
+        fn seek(&mut self, frame: u64) -> Result<(), SourceError> {
+            self.pos = frame.min(self.frames);
+            Ok(())
+        }
+    }
+
+    fn counting(from: u64, to: u64) -> Vec<f32> {
+        (from..to).flat_map(|frame| {
+            let sample = frame as f32 / 65536f32;
+            vec![sample, -sample]
+        }).collect()
+    }
+
+    // A buffer fed by hand rather than by a decoder thread
+    fn hand_fed_buffer() -> (StreamBuffer, Arc<PlaybackState>, SyncSender<Chunk>, Receiver<Vec<f32>>) {
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(QUEUE_CHUNKS);
+        let (recycle_tx, recycle_rx) = mpsc::sync_channel(QUEUE_CHUNKS + 1);
+        let state = Arc::new(PlaybackState::new());
+        let buffer = StreamBuffer {
+            chunk_rx, recycle_tx,
+            state: state.clone(),
+            channels: 2,
+            current: None,
+            pos: 0,
+            ring: None
+        };
+        (buffer, state, chunk_tx, recycle_rx)
+    }
+
+    fn chunk(generation: u64, start_frame: u64, frames: u64) -> Chunk {
+        Chunk { generation, start_frame, samples: counting(start_frame, start_frame + frames), end: false }
+    }
+
+    // Plays until the buffer reports the end, keeping only the frames it
+    // handed out and checking that the rest of each device buffer is silent
+    fn play_to_end(buffer: &mut StreamBuffer) -> Vec<f32> {
+        let mut played = Vec::new();
+        let mut out = vec![1f32; 2 * 100];
+        loop {
+            let before = buffer.position();
+            let playing = buffer.fill(&mut out);
+            let n = (buffer.position() - before) as usize * 2;
+            played.extend_from_slice(&out[..n]);
+            assert!(out[n..].iter().all(|&sample| sample == 0f32));
+            if !playing {
+                return played;
+            }
+            if n == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    #[test]
+    fn underruns_are_silent() {
+        let (mut buffer, _state, chunk_tx, _recycle_rx) = hand_fed_buffer();
+        let mut out = vec![1f32; 2 * 100];
+        assert!(buffer.fill(&mut out));
+        assert!(out.iter().all(|&sample| sample == 0f32));
+        // half a buffer of audio, then nothing
+        chunk_tx.send(chunk(0, 0, 50)).unwrap();
+        out.iter_mut().for_each(|sample| *sample = 1f32);
+        assert!(buffer.fill(&mut out));
+        assert_eq!(&out[..100], &counting(0, 50)[..]);
+        assert!(out[100..].iter().all(|&sample| sample == 0f32));
+        assert_eq!(buffer.position(), 50);
+        // the decoder going away ends playback
+        drop(chunk_tx);
+        assert!(!buffer.fill(&mut out));
+    }
+
+    #[test]
+    fn end_chunk_ends_playback() {
+        let (mut buffer, _state, chunk_tx, _recycle_rx) = hand_fed_buffer();
+        chunk_tx.send(chunk(0, 0, 30)).unwrap();
+        chunk_tx.send(Chunk::end(0, 30)).unwrap();
+        let mut out = vec![1f32; 2 * 100];
+        assert!(!buffer.fill(&mut out));
+        assert_eq!(&out[..60], &counting(0, 30)[..]);
+        assert!(out[60..].iter().all(|&sample| sample == 0f32));
+    }
+
+    #[test]
+    fn stale_chunks_are_dropped_and_recycled() {
+        let (mut buffer, state, chunk_tx, recycle_rx) = hand_fed_buffer();
+        chunk_tx.send(chunk(0, 0, 60)).unwrap();
+        let mut out = vec![0f32; 2 * 40];
+        assert!(buffer.fill(&mut out));
+        assert_eq!(out, counting(0, 40));
+
+        // a seek to 500 while one chunk of the old generation is current
+        // and another is still queued
+        chunk_tx.send(chunk(0, 60, 60)).unwrap();
+        state.generation.store(1, Ordering::SeqCst);
+        state.position.store(500, Ordering::SeqCst);
+        chunk_tx.send(chunk(1, 500, 40)).unwrap();
+        assert!(buffer.fill(&mut out));
+        assert_eq!(out, counting(500, 540));
+        assert_eq!(buffer.position(), 540);
+
+        // both old chunks went back to the decoder with their memory intact
+        let recycled: Vec<Vec<f32>> = recycle_rx.try_iter().collect();
+        assert_eq!(recycled.len(), 2);
+        assert!(recycled.iter().all(|samples| samples.capacity() >= 2 * 60));
+        // and the played one follows once the buffer moves past it
+        chunk_tx.send(Chunk::end(1, 540)).unwrap();
+        assert!(!buffer.fill(&mut out));
+        assert_eq!(recycle_rx.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn decoder_plays_a_source_through() {
+        let source = CountingSource { frames: 3 * CHUNK_FRAMES as u64 + 123, pos: 0, fail_at: None };
+        let (mut buffer, controller, handle) = spawn_decoder(Box::new(source), 1000);
+        assert_eq!(play_to_end(&mut buffer), counting(0, 3 * CHUNK_FRAMES as u64 + 123));
+        controller.stop();
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn decoder_starts_over_after_a_seek() {
+        let source = CountingSource { frames: 20000, pos: 0, fail_at: None };
+        let (mut buffer, controller, handle) = spawn_decoder(Box::new(source), 1000);
+        let mut out = vec![0f32; 2 * 100];
+        while buffer.position() < 300 {
+            buffer.fill(&mut out);
+        }
+        // everything queued before this is of the old generation
+        assert_eq!(controller.seek_to(12f64), 12f64);
+        assert_eq!(play_to_end(&mut buffer), counting(12000, 20000));
+        controller.stop();
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn decoder_reports_a_failed_read() {
+        let source = CountingSource { frames: 20000, pos: 0, fail_at: Some(5000) };
+        let (mut buffer, _controller, handle) = spawn_decoder(Box::new(source), 1000);
+        // what was decoded before the error still plays
+        assert_eq!(play_to_end(&mut buffer), counting(0, 5000));
+        assert!(matches!(handle.join().unwrap(), Err(SourceError::Decode(_))));
+    }
+}
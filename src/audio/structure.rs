@@ -0,0 +1,301 @@
+use std::f32::consts::PI;
+use super::analysis::*;
+use super::bands::*;
+use super::chroma::*;
+use super::tempo::*;
+
+// Mel bands the MFCCs summarise, and how many coefficients are kept. The
+// coefficient before them only follows the overall level, so it is left out.
+const MEL_BANDS: usize = 40;
+pub const MFCC_COUNT: usize = 12;
+// Length of the blocks frames are averaged over when there is no beat grid
+const BLOCK_SECS: f64 = 0.5;
+// How much music either side of a point is compared to find boundaries
+const KERNEL_SECS: f64 = 8f64;
+// Shortest section reported
+const MIN_SEGMENT_SECS: f64 = 8f64;
+// Boundaries need a novelty this many standard deviations above average
+const NOVELTY_THRESHOLD: f32 = 0.5;
+// Sections whose blocks match at least this well on average, from -1 to
+// 1, are taken to be the same kind of section
+const REPEAT_SIMILARITY: f32 = 0.5;
+
+// Mel-frequency cepstral coefficients: the broad shape of a spectrum,
+// which tells timbres apart whatever the notes
+pub type Mfcc = [f32; MFCC_COUNT];
+
+/*
+Works out the MFCCs of magnitude spectra: the level of every mel band in
+dB, turned into coefficients by a discrete cosine transform.
+*/
+pub struct MfccAnalyzer {
+    bands: BandAnalyzer,
+    // cosine of every kept coefficient at every band
+    dct: Vec<Vec<f32>>
+}
+
+impl MfccAnalyzer {
+    pub fn new(config: &AnalysisConfig, sample_rate: u32) -> MfccAnalyzer {
+        let bands = BandAnalyzer::new(BandScale::Mel(MEL_BANDS), sample_rate, config.num_bins(),
+            config.bin_width(sample_rate), config.noise_bandwidth());
+        let count = bands.centres().len();
+        let dct = (1..MFCC_COUNT + 1).map(|k| {
+            (0..count).map(|n| (PI * k as f32 * (n as f32 + 0.5f32) / count as f32).cos()).collect()
+        }).collect();
+        MfccAnalyzer { bands, dct }
+    }
+
+    // `levels` is scratch space for the band levels
+    pub fn mfcc(&self, magnitudes: &[f32], levels: &mut Vec<f32>) -> Mfcc {
+        self.bands.levels(magnitudes, levels);
+        let mut mfcc = [0f32; MFCC_COUNT];
+        for (coefficient, cosines) in mfcc.iter_mut().zip(&self.dct) {
+            *coefficient = cosines.iter().zip(levels.iter()).map(|(c, db)| c * db).sum::<f32>() / levels.len() as f32;
+        }
+        mfcc
+    }
+}
+
+// One section of a track. Sections given the same label sound alike, such
+// as every chorus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    // 0 for the first kind of section heard, 1 for the next new one, ...
+    pub label: usize
+}
+
+impl Segment {
+    // "A" for the first kind of section, "B" for the next, and so on
+    pub fn label_name(&self) -> String {
+        match self.label {
+            label if label < 26 => ((b'A' + label as u8) as char).to_string(),
+            label => format!("S{}", label)
+        }
+    }
+}
+
+/*
+Divides a track into sections and labels the ones that repeat.
+
+The frames are averaged over blocks, one per beat when the beat grid is
+known, and every block is described by its chroma (the harmony) and MFCCs
+(the timbre). Comparing every block with every other gives a
+self-similarity matrix, and sliding a checkerboard kernel along its
+diagonal gives a novelty curve that peaks where the music before a point
+is unlike the music after it. Boundaries go at the strongest peaks, at
+least `MIN_SEGMENT_SECS` apart. A section then takes the label of the
+earlier section it matches best, block by block, if they match well
+enough, or a new label otherwise.
+*/
+pub fn segment_structure(chromagram: &Chromagram, mfccs: &[Mfcc], beat_grid: Option<&BeatGrid>) -> Vec<Segment> {
+    let frames = chromagram.frames.len().min(mfccs.len());
+    if frames == 0 {
+        return Vec::new();
+    }
+    let frame_secs = chromagram.frame_secs;
+    let frame_start = |frame: usize| (chromagram.time_secs(frame) - frame_secs / 2f64).max(0f64);
+    let end_secs = frame_start(frames - 1) + frame_secs;
+
+    // frames where every block starts, and the frame after the last
+    let mut edges = vec![0];
+    match beat_grid.filter(|grid| grid.beat_secs() >= 2f64 * frame_secs) {
+        Some(grid) => edges.extend(grid.beats(0f64, end_secs).into_iter()
+            .map(|(secs, _)| ((secs - chromagram.start_secs) / frame_secs).round().max(0f64) as usize)),
+        None => {
+            let step = ((BLOCK_SECS / frame_secs).round() as usize).max(1);
+            edges.extend((1..).map(|block| block * step).take_while(|&frame| frame < frames));
+        }
+    }
+    edges.push(frames);
+    edges.retain(|&frame| frame <= frames);
+    edges.dedup();
+    let blocks = edges.len() - 1;
+    if blocks == 0 {
+        return Vec::new();
+    }
+
+    // the chroma and MFCCs of every block, each dimension scaled to the
+    // same spread over the track so harmony and timbre count alike
+    let mut features: Vec<Vec<f32>> = edges.windows(2).map(|edge| {
+        let mut sum = vec![0f32; 12 + MFCC_COUNT];
+        let frames = chromagram.frames[edge[0]..edge[1]].iter().zip(&mfccs[edge[0]..edge[1]]);
+        for (chroma, mfcc) in frames {
+            for (total, &x) in sum.iter_mut().zip(chroma.iter().chain(mfcc)) {
+                *total += x / (edge[1] - edge[0]) as f32;
+            }
+        }
+        sum
+    }).collect();
+    for dimension in 0..12 + MFCC_COUNT {
+        let mean = features.iter().map(|block| block[dimension]).sum::<f32>() / blocks as f32;
+        let variance = features.iter().map(|block| (block[dimension] - mean).powi(2)).sum::<f32>() / blocks as f32;
+        for block in &mut features {
+            block[dimension] = if variance > 0f32 { (block[dimension] - mean) / variance.sqrt() } else { 0f32 };
+        }
+    }
+    for block in &mut features {
+        let norm = block.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0f32 {
+            block.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    // how alike two blocks are, from -1 to 1, worked out when needed as
+    // the whole matrix grows with the square of the track's length
+    let similarity = |a: usize, b: usize| features[a].iter().zip(&features[b]).map(|(x, y)| x * y).sum::<f32>();
+
+    let block_secs = end_secs / blocks as f64;
+    let block_start = |block: usize| if block == 0 { 0f64 } else { frame_start(edges[block]) };
+    let novelty = novelty_curve(blocks, &similarity, ((KERNEL_SECS / block_secs).round() as usize).max(1));
+    let boundaries = pick_boundaries(&novelty, block_start, end_secs);
+
+    let mut bounds = vec![0];
+    bounds.extend(boundaries);
+    bounds.push(blocks);
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut labels = 0;
+    for (i, bound) in bounds.windows(2).enumerate() {
+        let best = bounds.windows(2).take(i).enumerate()
+            .map(|(earlier, other)| (earlier, diagonal_similarity(&similarity, (other[0], other[1]), (bound[0], bound[1]))))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let label = match best {
+            Some((earlier, score)) if score >= REPEAT_SIMILARITY => segments[earlier].label,
+            _ => {
+                labels += 1;
+                labels - 1
+            }
+        };
+        segments.push(Segment {
+            start_secs: block_start(bound[0]),
+            end_secs: if bound[1] == blocks { end_secs } else { block_start(bound[1]) },
+            label
+        });
+    }
+    segments
+}
+
+// How much the blocks before each block differ from those after, from the
+// similarity of every pair of `blocks` blocks. The checkerboard kernel reaches `half` blocks
+// either side and fades towards its edges; near the ends of the track only
+// the part of it that fits is used.
+fn novelty_curve<F>(blocks: usize, similarity: &F, half: usize) -> Vec<f32>
+    where F: Fn(usize, usize) -> f32 {
+    let sigma = half as f32 / 2f32;
+    (0..blocks).map(|centre| {
+        let (mut sum, mut weight) = (0f32, 0f32);
+        let (start, end) = (centre.saturating_sub(half), (centre + half).min(blocks));
+        for a in start..end {
+            for b in start..end {
+                let (da, db) = (a as f32 - centre as f32 + 0.5f32, b as f32 - centre as f32 + 0.5f32);
+                let taper = (-(da * da + db * db) / (2f32 * sigma * sigma)).exp();
+                // blocks on the same side of the centre should match, and
+                // blocks on opposite sides differ
+                let sign = if (a < centre) == (b < centre) { 1f32 } else { -1f32 };
+                sum += sign * taper * similarity(a, b);
+                weight += taper;
+            }
+        }
+        if weight > 0f32 { (sum / weight).max(0f32) } else { 0f32 }
+    }).collect()
+}
+
+// The blocks starting a new section: novelty peaks well above average,
+// strongest first, at least `MIN_SEGMENT_SECS` from each other and from
+// either end of the track. `start_secs` gives when a block starts.
+fn pick_boundaries<F>(novelty: &[f32], start_secs: F, end_secs: f64) -> Vec<usize>
+    where F: Fn(usize) -> f64 {
+    let n = novelty.len() as f32;
+    let mean = novelty.iter().sum::<f32>() / n;
+    let deviation = (novelty.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n).sqrt();
+    let threshold = mean + NOVELTY_THRESHOLD * deviation;
+    let mut peaks: Vec<usize> = (1..novelty.len().saturating_sub(1))
+        .filter(|&i| novelty[i] > threshold && novelty[i] >= novelty[i - 1] && novelty[i] > novelty[i + 1])
+        .filter(|&i| start_secs(i) >= MIN_SEGMENT_SECS && end_secs - start_secs(i) >= MIN_SEGMENT_SECS)
+        .collect();
+    peaks.sort_by(|&a, &b| novelty[b].total_cmp(&novelty[a]));
+    let mut chosen: Vec<usize> = Vec::new();
+    for peak in peaks {
+        if chosen.iter().all(|&other| (start_secs(other) - start_secs(peak)).abs() >= MIN_SEGMENT_SECS) {
+            chosen.push(peak);
+        }
+    }
+    chosen.sort_unstable();
+    chosen
+}
+
+// How well two runs of blocks match in order: the average similarity of
+// their blocks side by side, with the shorter run slid along the longer to
+// where it fits best
+fn diagonal_similarity<F>(similarity: &F, a: (usize, usize), b: (usize, usize)) -> f32
+    where F: Fn(usize, usize) -> f32 {
+    let (short, long) = if a.1 - a.0 <= b.1 - b.0 { (a, b) } else { (b, a) };
+    let len = short.1 - short.0;
+    if len == 0 {
+        return f32::NEG_INFINITY;
+    }
+    (0..(long.1 - long.0) - len + 1).map(|offset| {
+        (0..len).map(|k| similarity(short.0 + k, long.0 + offset + k)).sum::<f32>() / len as f32
+    }).fold(f32::NEG_INFINITY, f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A chromagram and MFCCs holding `sections` one after another, each
+    // `secs` long, with the harmony and timbre of the section type given
+    fn sections(sections: &[usize], secs: f64) -> (Chromagram, Vec<Mfcc>) {
+        let frame_secs = 0.1f64;
+        let frames_per_section = (secs / frame_secs) as usize;
+        let mut chromagram = Chromagram { start_secs: frame_secs / 2f64, frame_secs, frames: Vec::new() };
+        let mut mfccs = Vec::new();
+        for (i, &section) in sections.iter().enumerate() {
+            for frame in 0..frames_per_section {
+                // a little movement within every section, as real music has
+                let wobble = 0.05f32 * ((i * frames_per_section + frame) as f32 * 0.7f32).sin();
+                let mut chroma = [0.1f32; 12];
+                for &note in &[[0, 4, 7], [2, 5, 9]][section] {
+                    chroma[note] = 1f32 + wobble;
+                }
+                let mut mfcc = [0f32; MFCC_COUNT];
+                for (k, coefficient) in mfcc.iter_mut().enumerate() {
+                    *coefficient = if section == 0 { 10f32 / (k + 1) as f32 } else { -5f32 + k as f32 } + wobble;
+                }
+                chromagram.frames.push(chroma);
+                mfccs.push(mfcc);
+            }
+        }
+        (chromagram, mfccs)
+    }
+
+    #[test]
+    fn finds_and_labels_a_repeated_section() {
+        let (chromagram, mfccs) = sections(&[0, 1, 0], 30f64);
+        let segments = segment_structure(&chromagram, &mfccs, None);
+        assert_eq!(segments.len(), 3, "{:?}", segments);
+        assert_eq!(segments.iter().map(|segment| segment.label).collect::<Vec<_>>(), vec![0, 1, 0]);
+        assert_eq!(segments[0].start_secs, 0f64);
+        for (segment, boundary) in segments[1..].iter().zip(&[30f64, 60f64]) {
+            assert!((segment.start_secs - boundary).abs() <= BLOCK_SECS, "{:?}", segments);
+        }
+        assert!((segments[2].end_secs - 90f64).abs() < 0.2f64);
+        for pair in segments.windows(2) {
+            assert_eq!(pair[0].end_secs, pair[1].start_secs);
+        }
+    }
+
+    #[test]
+    fn unchanging_music_is_one_section() {
+        let (chromagram, mfccs) = sections(&[0, 0, 0], 30f64);
+        let segments = segment_structure(&chromagram, &mfccs, None);
+        assert_eq!(segments.len(), 1, "{:?}", segments);
+        assert_eq!(segments[0].label, 0);
+    }
+
+    #[test]
+    fn nothing_to_segment_without_frames() {
+        let chromagram = Chromagram { start_secs: 0f64, frame_secs: 0.1f64, frames: Vec::new() };
+        assert!(segment_structure(&chromagram, &[], None).is_empty());
+    }
+}
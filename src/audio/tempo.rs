@@ -0,0 +1,183 @@
+use super::onset::*;
+
+// Range of tempos considered, in beats per minute
+const MIN_BPM: f64 = 60f64;
+const MAX_BPM: f64 = 200f64;
+// Tempo that listeners tend to hear when a track could be counted at
+// several, and how many octaves either side of it are still likely
+const PREFERRED_BPM: f64 = 120f64;
+const PREFERENCE_OCTAVES: f64 = 1f64;
+// Only bars of four beats are recognised
+const BEATS_PER_BAR: u32 = 4;
+
+/*
+A steady grid of beats laid over a track: the tempo, where the beats fall
+and which of them start a bar.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeatGrid {
+    pub bpm: f64,
+    // time of the first downbeat; earlier beats count back from it
+    pub downbeat_secs: f64,
+    pub beats_per_bar: u32
+}
+
+// A point in time counted in beats and bars
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MusicalTime {
+    // bars since the first downbeat, negative before it
+    pub bar: i64,
+    // beat within the bar, counting from 0 at the downbeat
+    pub beat_in_bar: u32,
+    // how far through the current beat and bar, from 0 to 1
+    pub beat_phase: f32,
+    pub bar_phase: f32
+}
+
+impl BeatGrid {
+    pub fn beat_secs(&self) -> f64 {
+        60f64 / self.bpm
+    }
+
+    pub fn musical_time(&self, time_secs: f64) -> MusicalTime {
+        let beats = (time_secs - self.downbeat_secs) / self.beat_secs();
+        let bar_beats = beats / self.beats_per_bar as f64;
+        MusicalTime {
+            bar: bar_beats.floor() as i64,
+            beat_in_bar: (beats.floor() as i64).rem_euclid(self.beats_per_bar as i64) as u32,
+            beat_phase: (beats - beats.floor()) as f32,
+            bar_phase: (bar_beats - bar_beats.floor()) as f32
+        }
+    }
+
+    // Times of every beat from `start_secs` up to `end_secs`, each with
+    // whether it is a downbeat
+    pub fn beats(&self, start_secs: f64, end_secs: f64) -> Vec<(f64, bool)> {
+        let first = ((start_secs - self.downbeat_secs) / self.beat_secs()).ceil() as i64;
+        let mut beats = Vec::new();
+        let mut index = first;
+        loop {
+            let time_secs = self.downbeat_secs + index as f64 * self.beat_secs();
+            if time_secs > end_secs {
+                return beats;
+            }
+            beats.push((time_secs, index.rem_euclid(self.beats_per_bar as i64) == 0));
+            index += 1;
+        }
+    }
+}
+
+// Estimates the tempo and beat grid of a track from its onset envelope.
+// The tempo is the lag at which the envelope best matches itself, the beats
+// are placed where they line up with the most onset strength, and the
+// downbeat is the beat of the bar carrying the most strength. Returns None
+// when the track is too short or too flat to tell.
+pub fn estimate_beat_grid(envelope: &OnsetEnvelope) -> Option<BeatGrid> {
+    let curve = &envelope.total;
+    let frames_per_min = 60f64 / envelope.frame_secs;
+    let min_lag = (frames_per_min / MAX_BPM).floor().max(1f64) as usize;
+    let max_lag = (frames_per_min / MIN_BPM).ceil() as usize;
+    if curve.len() < 2 * max_lag {
+        return None;
+    }
+
+    // autocorrelation of the envelope with its mean removed, weighted
+    // towards tempos people usually tap along to
+    let mean = curve.iter().sum::<f32>() / curve.len() as f32;
+    let centred: Vec<f32> = curve.iter().map(|&x| x - mean).collect();
+    let scores: Vec<f64> = (0..max_lag + 2).map(|lag| {
+        if lag < min_lag || lag > max_lag {
+            return 0f64;
+        }
+        let sum: f64 = centred.iter().zip(&centred[lag..])
+            .map(|(&a, &b)| a as f64 * b as f64)
+            .sum();
+        let octaves = (frames_per_min / lag as f64 / PREFERRED_BPM).log2() / PREFERENCE_OCTAVES;
+        let weight = (-0.5f64 * octaves * octaves).exp();
+        weight * sum / (curve.len() - lag) as f64
+    }).collect();
+    let best = (min_lag..max_lag + 1)
+        .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))?;
+    if scores[best] <= 0f64 {
+        return None;
+    }
+
+    // refine the lag between frames with a parabola through its neighbours
+    let (before, peak, after) = (scores[best - 1], scores[best], scores[best + 1]);
+    let curvature = before - 2f64 * peak + after;
+    let offset = if curvature < 0f64 { 0.5f64 * (before - after) / curvature } else { 0f64 };
+    let period = best as f64 + offset.clamp(-0.5f64, 0.5f64);
+
+    // the beat phase that collects the most onset strength
+    let strength_at = |phase: f64, step: f64, every: usize, skip: usize| -> f64 {
+        let mut sum = 0f64;
+        let mut index = skip;
+        loop {
+            let frame = (phase + index as f64 * step).round() as usize;
+            if frame >= curve.len() {
+                return sum;
+            }
+            sum += curve[frame] as f64;
+            index += every;
+        }
+    };
+    let phases = period.ceil() as usize;
+    let phase = (0..phases)
+        .max_by(|&a, &b| {
+            strength_at(a as f64, period, 1, 0).total_cmp(&strength_at(b as f64, period, 1, 0))
+        })? as f64;
+
+    // the beat of the bar that collects the most strength is the downbeat
+    let bar = BEATS_PER_BAR as usize;
+    let downbeat = (0..bar)
+        .max_by(|&a, &b| {
+            strength_at(phase, period, bar, a).total_cmp(&strength_at(phase, period, bar, b))
+        })?;
+
+    Some(BeatGrid {
+        bpm: frames_per_min / period,
+        downbeat_secs: envelope.time_secs(0) + (phase + downbeat as f64 * period) * envelope.frame_secs,
+        beats_per_bar: BEATS_PER_BAR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An envelope of 10 ms frames with an impulse every `period` frames from
+    // `first` on, every fourth of them twice as strong, starting with `accent`
+    fn impulse_train(frames: usize, period: usize, first: usize, accent: usize) -> OnsetEnvelope {
+        let mut total = vec![0f32; frames];
+        for (beat, frame) in (first..frames).step_by(period).enumerate() {
+            total[frame] = if beat % 4 == accent { 2f32 } else { 1f32 };
+        }
+        OnsetEnvelope { start_secs: 0.1, frame_secs: 0.01, total, bands: Vec::new() }
+    }
+
+    #[test]
+    fn beat_grid_of_an_impulse_train_at_120_bpm() {
+        // beats at 0.17 s, 0.67 s, ..., the bars starting from the second
+        let grid = estimate_beat_grid(&impulse_train(3000, 50, 7, 1)).unwrap();
+        assert!((grid.bpm - 120f64).abs() < 0.5, "{}", grid.bpm);
+        assert!((grid.downbeat_secs - 0.67).abs() < 0.015, "{}", grid.downbeat_secs);
+        assert_eq!(grid.beats_per_bar, 4);
+        let time = grid.musical_time(0.67 + 4.0 * 0.5 + 0.25);
+        assert_eq!((time.bar, time.beat_in_bar), (1, 0));
+        assert!((time.beat_phase - 0.5).abs() < 0.05, "{}", time.beat_phase);
+    }
+
+    #[test]
+    fn no_beat_grid_for_short_or_flat_envelopes() {
+        assert_eq!(estimate_beat_grid(&impulse_train(100, 50, 7, 1)), None);
+        let flat = OnsetEnvelope { start_secs: 0f64, frame_secs: 0.01, total: vec![1f32; 3000], bands: Vec::new() };
+        assert_eq!(estimate_beat_grid(&flat), None);
+    }
+
+    #[test]
+    fn beats_mark_the_downbeats() {
+        let grid = BeatGrid { bpm: 120f64, downbeat_secs: 1f64, beats_per_bar: 4 };
+        let beats = grid.beats(0.2, 2.1);
+        assert_eq!(beats, vec![(0.5, false), (1.0, true), (1.5, false), (2.0, false)]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::thread;
+use super::analysis::*;
+use super::channels::*;
+use super::chroma::*;
+use super::envelope::*;
+use super::loudness::*;
+use super::offline::*;
+use super::onset::*;
+use super::source::*;
+use super::stream::*;
+use super::structure::*;
+use super::tempo::*;
+use super::waveform::*;
+
+// Keys are estimated over sections this long, starting this often
+const KEY_SECTION_SECS: f64 = 10f64;
+const KEY_HOP_SECS: f64 = 2.5f64;
+
+/*
+Everything worked out about a whole track before or while it plays, as
+opposed to the live analysis of the audio being heard.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackAnalysis {
+    pub loudness: Loudness,
+    pub onsets: Vec<OnsetEvent>,
+    pub beat_grid: Option<BeatGrid>,
+    pub key: Option<Key>,
+    // keys of overlapping sections, in order
+    pub key_sections: Vec<KeySection>,
+    // the sections of the track, one after another from the start
+    pub segments: Vec<Segment>,
+    // when every signal the channel mode makes is at its loudest, and the
+    // smoothed level there in dB
+    pub loudest: Vec<(f64, f32)>,
+    // the waveform of every signal the channel mode makes, at every zoom level
+    pub waveform: WaveformPyramid
+}
+
+impl TrackAnalysis {
+    // The key of the section centred closest to `time_secs`
+    pub fn key_at(&self, time_secs: f64) -> Option<Key> {
+        self.key_sections.iter()
+            .filter(|section| section.start_secs <= time_secs && time_secs < section.end_secs)
+            .min_by(|a, b| {
+                let distance = |section: &KeySection| (section.start_secs + section.end_secs - 2f64 * time_secs).abs();
+                distance(a).total_cmp(&distance(b))
+            })
+            .map(|section| section.key)
+    }
+}
+
+// Runs every whole-track analysis over a file, sharing the frames of each
+// pass out between `threads` threads while the loudness is measured and the
+// waveform summarised on a thread of their own. `progress` hears about
+// every pass that is shared out, so the total it is told is twice the
+// length of the file.
+pub fn analyse_track(filename: &str, config: &AnalysisConfig, mode: ChannelMode, threads: usize,
+    progress: &mut dyn FnMut(Progress)) -> Result<TrackAnalysis, SourceError> {
+    thread::scope(|scope| {
+        let levels = scope.spawn(|| measure_levels(filename, mode));
+        let mut passes = Passes { progress, count: 2, finished_secs: 0f64, done_secs: 0f64 };
+
+        let (envelope, chromagram, mfccs) = analyse_mono(filename, config, threads, &mut |step| passes.report(step))?;
+        passes.next();
+        let onsets = pick_onsets(&envelope, &config.onsets);
+        let beat_grid = estimate_beat_grid(&envelope);
+        let key = chromagram.key();
+        let key_sections = chromagram.section_keys(KEY_SECTION_SECS, KEY_HOP_SECS);
+        let segments = segment_structure(&chromagram, &mfccs, beat_grid.as_ref());
+
+        // the loudest frame of every signal so far, as (time, level)
+        let mut loudest: Vec<(f64, Level)> = Vec::new();
+        follow_envelope(filename, config, mode, threads, &mut |step| passes.report(step), |time_secs, envelope| {
+            loudest.resize(envelope.signals.len(), (0f64, Level { linear: -1f32, db: 0f32 }));
+            for (max, &level) in loudest.iter_mut().zip(&envelope.signals) {
+                if level.linear > max.1.linear {
+                    *max = (time_secs, level);
+                }
+            }
+        })?;
+        let loudest = loudest.into_iter().map(|(time_secs, level)| (time_secs, level.db)).collect();
+
+        let (loudness, waveform) = levels.join().unwrap()?;
+        Ok(TrackAnalysis { loudness, onsets, beat_grid, key, key_sections, segments, loudest, waveform })
+    })
+}
+
+// Works out the onset envelope, chromagram and MFCCs of a mono mix of a
+// file in one pass, sharing the frames out between `threads` threads
+fn analyse_mono(filename: &str, config: &AnalysisConfig, threads: usize,
+    progress: &mut dyn FnMut(Progress)) -> Result<(OnsetEnvelope, Chromagram, Vec<Mfcc>), SourceError> {
+    let mut source = open_source(filename)?;
+    let spec = source.spec();
+    let chroma = ChromaAnalyzer::new(config.num_bins(), config.bin_width(spec.sample_rate));
+    let mfcc = MfccAnalyzer::new(config, spec.sample_rate);
+    let (chroma, mfcc) = (&chroma, &mfcc);
+    let history = OnsetFlux::history(config, spec.sample_rate);
+    let frames = analyse_frames_with_history(&mut *source, config, ChannelMode::Mono, history, threads, || {
+        let mut flux = OnsetFlux::new(config, spec.sample_rate);
+        let mut levels = Vec::new();
+        move |stft: &mut Stft, signals: &[&[f32]]| {
+            let magnitudes = stft.process(signals[0]);
+            (flux.process(magnitudes), chroma.chroma(magnitudes), mfcc.mfcc(magnitudes, &mut levels))
+        }
+    }, progress)?;
+
+    let mut flux = Vec::with_capacity(frames.len());
+    let mut chromagram = Chromagram {
+        start_secs: config.centre_offset_secs(spec.sample_rate),
+        frame_secs: config.hop_size as f64 / spec.sample_rate as f64,
+        frames: Vec::with_capacity(frames.len())
+    };
+    let mut mfccs = Vec::with_capacity(frames.len());
+    for (frame_flux, frame_chroma, frame_mfcc) in frames {
+        flux.push(frame_flux);
+        chromagram.frames.push(frame_chroma);
+        mfccs.push(frame_mfcc);
+    }
+    Ok((OnsetEnvelope::from_flux(config, spec.sample_rate, flux), chromagram, mfccs))
+}
+
+// Measures the loudness of all the channels of a file and summarises the
+// waveform of every signal `mode` makes, in one read through it
+fn measure_levels(filename: &str, mode: ChannelMode) -> Result<(Loudness, WaveformPyramid), SourceError> {
+    let mut source = open_source(filename)?;
+    let spec = source.spec();
+    let mut meter = LoudnessMeter::new(spec.channels as usize, spec.sample_rate);
+    let mut waveform = WaveformBuilder::new(mode, spec.channels as usize, spec.sample_rate);
+    for_each_chunk(&mut *source, |chunk| {
+        meter.process(chunk);
+        waveform.process(chunk);
+    })?;
+    Ok((meter.summary(), waveform.finish()))
+}
+
+// Adds up the progress of `count` passes over the same file
+struct Passes<'a> {
+    progress: &'a mut dyn FnMut(Progress),
+    count: usize,
+    // audio analysed by the passes already over, and by the current one
+    finished_secs: f64,
+    done_secs: f64
+}
+
+impl<'a> Passes<'a> {
+    fn report(&mut self, step: Progress) {
+        self.done_secs = step.done_secs;
+        let total_secs = step.total_secs.map(|total| total * self.count as f64);
+        (self.progress)(Progress { done_secs: self.finished_secs + step.done_secs, total_secs });
+    }
+
+    fn next(&mut self) {
+        self.finished_secs += self.done_secs;
+        self.done_secs = 0f64;
+    }
+}
@@ -0,0 +1,326 @@
+use super::channels::*;
+
+// Sample frames summarised by every bucket of the finest level
+const BASE_FRAMES: u64 = 256;
+
+// The extremes and RMS level of a stretch of one signal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaveformBucket {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32
+}
+
+impl WaveformBucket {
+    // Combines the summaries of two neighbouring stretches `frames` and
+    // `other_frames` long
+    fn merge(self, frames: u64, other: WaveformBucket, other_frames: u64) -> WaveformBucket {
+        let total = (frames + other_frames).max(1) as f64;
+        let power = (self.rms as f64).powi(2) * frames as f64 + (other.rms as f64).powi(2) * other_frames as f64;
+        WaveformBucket {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            rms: (power / total).sqrt() as f32
+        }
+    }
+}
+
+// One zoom level of a waveform pyramid: the buckets of every signal
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveformLevel {
+    pub frames_per_bucket: u64,
+    // [signal][bucket]
+    pub signals: Vec<Vec<WaveformBucket>>
+}
+
+/*
+Summaries of the waveform of a whole track at a range of zoom levels,
+for drawing overviews and scrub bars at any width without going back to
+the audio. The finest level has a bucket every `BASE_FRAMES` sample
+frames, each level above merges pairs of buckets of the one below, and
+the last has a single bucket covering the whole track.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveformPyramid {
+    pub sample_rate: u32,
+    // sample frames in the track
+    pub frames: u64,
+    // finest first
+    pub levels: Vec<WaveformLevel>
+}
+
+impl WaveformPyramid {
+    pub fn duration_secs(&self) -> f64 {
+        self.frames as f64 / self.sample_rate as f64
+    }
+
+    pub fn signal_count(&self) -> usize {
+        self.levels.first().map_or(0, |level| level.signals.len())
+    }
+
+    // Summarises one signal from `start_secs` to `end_secs`, to the nearest
+    // finest bucket, or None if that is outside the track. The stretch is
+    // covered by the fewest buckets of any level that fit it exactly, so
+    // this takes a handful of steps however long it is.
+    pub fn summary(&self, signal: usize, start_secs: f64, end_secs: f64) -> Option<WaveformBucket> {
+        let base = self.levels.first()?.signals.get(signal)?;
+        // the nearest edge between buckets, the last being the end of the
+        // track rather than a whole bucket after the start of the last one
+        let last_start = base.len().saturating_sub(1) as f64 * BASE_FRAMES as f64;
+        let bucket = |secs: f64| {
+            let frame = secs.max(0f64) * self.sample_rate as f64;
+            if frame >= (last_start + self.frames as f64) / 2f64 {
+                base.len()
+            } else {
+                (frame / BASE_FRAMES as f64).round() as usize
+            }
+        };
+        let (mut start, mut end) = (bucket(start_secs), bucket(end_secs));
+        if start == end && start < base.len() && end_secs > start_secs {
+            // shorter than a bucket, so summarised by the one it falls in
+            end += 1;
+        }
+        let mut summary: Option<(WaveformBucket, u64)> = None;
+        let mut add = |level: usize, index: usize| {
+            let bucket = self.levels[level].signals[signal][index];
+            let frames = self.bucket_frames(level, index);
+            summary = Some(match summary {
+                Some((sum, sum_frames)) => (sum.merge(sum_frames, bucket, frames), sum_frames + frames),
+                None => (bucket, frames)
+            });
+        };
+        let mut level = 0;
+        while start < end {
+            // take the odd buckets at either edge at this level, and the
+            // pairs between them from the level above
+            if start % 2 == 1 || level + 1 == self.levels.len() {
+                add(level, start);
+                start += 1;
+            }
+            if start < end && (end % 2 == 1 || level + 1 == self.levels.len()) {
+                end -= 1;
+                add(level, end);
+            }
+            start /= 2;
+            end /= 2;
+            level += 1;
+        }
+        summary.map(|(bucket, _)| bucket)
+    }
+
+    // Summarises one signal over `columns` equal stretches between
+    // `start_secs` and `end_secs`, e.g. one per pixel of a scrub bar.
+    // Stretches outside the track are None.
+    pub fn columns(&self, signal: usize, start_secs: f64, end_secs: f64, columns: usize)
+        -> Vec<Option<WaveformBucket>> {
+        let width = (end_secs - start_secs) / columns.max(1) as f64;
+        (0..columns).map(|column| {
+            let from = start_secs + column as f64 * width;
+            self.summary(signal, from, from + width)
+        }).collect()
+    }
+
+    // Sample frames a bucket covers, which is fewer for the last of a level
+    fn bucket_frames(&self, level: usize, index: usize) -> u64 {
+        let size = self.levels[level].frames_per_bucket;
+        size.min(self.frames.saturating_sub(index as u64 * size))
+    }
+}
+
+/*
+Builds the waveform pyramid of every signal a channel mode makes of a
+stream, from interleaved samples fed to it a chunk at a time, so the
+track never has to be held in memory.
+*/
+pub struct WaveformBuilder {
+    mode: ChannelMode,
+    channels: usize,
+    sample_rate: u32,
+    frames: u64,
+    values: Vec<f32>,
+    // frames in the buckets being filled
+    filled: u64,
+    // the bucket being filled for every signal, with its sum of squares
+    current: Vec<(WaveformBucket, f64)>,
+    base: Vec<Vec<WaveformBucket>>
+}
+
+impl WaveformBuilder {
+    pub fn new(mode: ChannelMode, channels: usize, sample_rate: u32) -> WaveformBuilder {
+        let signals = mode.signal_count(channels);
+        WaveformBuilder {
+            mode, channels, sample_rate,
+            frames: 0,
+            values: vec![0f32; signals],
+            filled: 0,
+            current: vec![(WaveformBucket { min: 0f32, max: 0f32, rms: 0f32 }, 0f64); signals],
+            base: vec![Vec::new(); signals]
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks(self.channels) {
+            self.mode.split_frame(frame, &mut self.values);
+            let first = self.filled == 0;
+            for (&x, &mut (ref mut bucket, ref mut power)) in self.values.iter().zip(&mut self.current) {
+                if first {
+                    *bucket = WaveformBucket { min: x, max: x, rms: 0f32 };
+                    *power = 0f64;
+                }
+                bucket.min = bucket.min.min(x);
+                bucket.max = bucket.max.max(x);
+                *power += x as f64 * x as f64;
+            }
+            self.frames += 1;
+            self.filled += 1;
+            if self.filled == BASE_FRAMES {
+                self.close_buckets();
+            }
+        }
+    }
+
+    // Closes the last buckets and builds the levels above the finest
+    pub fn finish(mut self) -> WaveformPyramid {
+        if self.filled > 0 {
+            self.close_buckets();
+        }
+        let mut pyramid = WaveformPyramid {
+            sample_rate: self.sample_rate,
+            frames: self.frames,
+            levels: vec![WaveformLevel { frames_per_bucket: BASE_FRAMES, signals: self.base }]
+        };
+        while pyramid.levels.last().unwrap().signals.iter().any(|buckets| buckets.len() > 1) {
+            let level = pyramid.levels.len() - 1;
+            let below = &pyramid.levels[level];
+            let signals = below.signals.iter().map(|buckets| {
+                buckets.chunks(2).enumerate().map(|(pair, children)| {
+                    let index = 2 * pair;
+                    match children.get(1) {
+                        Some(&next) => children[0].merge(pyramid.bucket_frames(level, index), next,
+                            pyramid.bucket_frames(level, index + 1)),
+                        None => children[0]
+                    }
+                }).collect()
+            }).collect();
+            let frames_per_bucket = 2 * below.frames_per_bucket;
+            pyramid.levels.push(WaveformLevel { frames_per_bucket, signals });
+        }
+        pyramid
+    }
+
+    fn close_buckets(&mut self) {
+        for (&(bucket, power), buckets) in self.current.iter().zip(&mut self.base) {
+            buckets.push(WaveformBucket { rms: (power / self.filled as f64).sqrt() as f32, ..bucket });
+        }
+        self.filled = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+    // long enough for several levels, with a partial last bucket
+    const FRAMES: usize = 37 * BASE_FRAMES as usize + 100;
+
+    // A pseudo-random stereo signal and its pyramid, built from chunks of
+    // uneven length
+    fn build() -> (Vec<f32>, WaveformPyramid) {
+        let mut seed = 7u32;
+        let samples: Vec<f32> = (0..2 * FRAMES).map(|_| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1u32 << 23) as f32 - 1f32
+        }).collect();
+        let mut builder = WaveformBuilder::new(ChannelMode::PerChannel, 2, SAMPLE_RATE);
+        let mut rest = &samples[..];
+        let mut chunk = 2;
+        while !rest.is_empty() {
+            let (now, later) = rest.split_at(chunk.min(rest.len()));
+            builder.process(now);
+            rest = later;
+            chunk = (chunk * 3) % 1000 + 2;
+        }
+        (samples, builder.finish())
+    }
+
+    // The summary of one signal from frame `start` up to `end`, worked out
+    // from every sample
+    fn brute_force(samples: &[f32], signal: usize, start: usize, end: usize) -> WaveformBucket {
+        let values: Vec<f32> = samples.chunks(2).take(end).skip(start).map(|frame| frame[signal]).collect();
+        WaveformBucket {
+            min: values.iter().cloned().fold(f32::INFINITY, f32::min),
+            max: values.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+            rms: (values.iter().map(|&x| x as f64 * x as f64).sum::<f64>() / values.len() as f64).sqrt() as f32
+        }
+    }
+
+    fn assert_matches(summary: Option<WaveformBucket>, expected: WaveformBucket) {
+        let summary = summary.unwrap();
+        assert_eq!((summary.min, summary.max), (expected.min, expected.max));
+        assert!((summary.rms - expected.rms).abs() < 1e-4f32, "{:?} {:?}", summary, expected);
+    }
+
+    fn secs(frame: usize) -> f64 {
+        frame as f64 / SAMPLE_RATE as f64
+    }
+
+    #[test]
+    fn levels_halve_up_to_one_bucket() {
+        let (_, pyramid) = build();
+        assert_eq!(pyramid.frames, FRAMES as u64);
+        assert_eq!(pyramid.signal_count(), 2);
+        assert_eq!(pyramid.levels[0].signals[0].len(), 38);
+        for pair in pyramid.levels.windows(2) {
+            assert_eq!(pair[1].frames_per_bucket, 2 * pair[0].frames_per_bucket);
+            assert_eq!(pair[1].signals[0].len(), pair[0].signals[0].len().div_ceil(2));
+        }
+        assert_eq!(pyramid.levels.last().unwrap().signals[0].len(), 1);
+    }
+
+    #[test]
+    fn summaries_match_every_sample() {
+        let (samples, pyramid) = build();
+        let buckets = FRAMES.div_ceil(BASE_FRAMES as usize);
+        let size = BASE_FRAMES as usize;
+        let mut seed = 3u32;
+        let mut random = |below: usize| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as usize % below
+        };
+        for _ in 0..200 {
+            let signal = random(2);
+            let start = random(buckets);
+            let end = start + 1 + random(buckets - start);
+            let expected = brute_force(&samples, signal, start * size, (end * size).min(FRAMES));
+            assert_matches(pyramid.summary(signal, secs(start * size), secs(end * size)), expected);
+        }
+        // the whole track, and the partial last bucket alone
+        assert_matches(pyramid.summary(1, 0f64, pyramid.duration_secs()), brute_force(&samples, 1, 0, FRAMES));
+        assert_matches(pyramid.summary(0, secs((buckets - 1) * size), pyramid.duration_secs()),
+            brute_force(&samples, 0, (buckets - 1) * size, FRAMES));
+    }
+
+    #[test]
+    fn ranges_shorter_than_a_bucket_get_the_bucket_they_fall_in() {
+        let (samples, pyramid) = build();
+        let size = BASE_FRAMES as usize;
+        for &bucket in &[0, 5, 20, 37] {
+            let from = bucket * size + 10;
+            let expected = brute_force(&samples, 0, bucket * size, ((bucket + 1) * size).min(FRAMES));
+            assert_matches(pyramid.summary(0, secs(from), secs(from + 50)), expected);
+        }
+    }
+
+    #[test]
+    fn nothing_outside_the_track() {
+        let (_, pyramid) = build();
+        let end = pyramid.duration_secs();
+        assert_eq!(pyramid.summary(2, 0f64, end), None);
+        assert_eq!(pyramid.summary(0, end + 1f64, end + 2f64), None);
+        assert_eq!(pyramid.summary(0, 1f64, 1f64), None);
+        let columns = pyramid.columns(0, 0f64, 2f64 * end, 4);
+        assert!(columns[..2].iter().all(Option::is_some));
+        assert!(columns[2..].iter().all(Option::is_none));
+    }
+}
@@ -5,10 +5,15 @@ extern crate hound;
 extern crate num;
 extern crate rustfft;
 extern crate portaudio;
+extern crate claxon;
+extern crate lewton;
+extern crate minimp3;
 
 mod graphics;
 mod visualizer;
 mod audio;
+mod spectrogram;
+mod png;
 
 use visualizer::*;
 use graphics::*;
@@ -20,22 +25,83 @@ use std::env;
 use std::sync::mpsc::{Sender, Receiver, TryRecvError};
 use std::sync::mpsc;
 use std::thread;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use spectrogram::*;
+use png::*;
 
 fn main() {
 	let args: Vec<String> = env::args().collect();
-	if args.len() != 2 {
-		println!("Please input one filename in quotation marks.");
+	match args.get(1).map(|arg| &arg[..]) {
+		Some("spectrogram") => return export_spectrogram(&args[2..]),
+		Some("analyze") => return export_features_command(&args[2..]),
+		_ => ()
+	}
+
+	// Options such as --fft-size 4096 tune the analysis; everything else is positional
+	let mut config = AnalysisConfig::default();
+	let mut cache_dir = AnalysisCache::default_dir();
+	let mut threads = default_threads();
+	let positional = parse_args(&args[1..], |name, value| {
+		match name {
+			"cache-dir" => cache_dir = PathBuf::from(value),
+			"threads" => threads = parse_threads(value)?,
+			_ => config.set_option(name, value)?
+		}
+		Ok(())
+	});
+	if positional.is_empty() || positional.len() > 2 {
+		eprintln!("Please input one filename in quotation marks, optionally followed by a channel mode (per-channel, mono or mid-side).");
+		print_analysis_options();
+		eprintln!("Analyses of whole tracks are saved in {} unless --cache-dir DIR is given",
+			AnalysisCache::default_dir().display());
+		eprintln!("    (the most recent few hundred are kept; delete the directory to clear them),");
+		eprintln!("    and run on {} threads unless --threads N is given.", default_threads());
+		eprintln!("To draw a spectrogram of a track instead, run: spectrogram FILE OUT.png [channel mode] [options]");
+		eprintln!("To write out the features of every frame, run: analyze FILE OUT.csv|OUT.jsonl|- [channel mode] [options]");
 		process::exit(1);
 	}
-	let filename = &args[1];
+	let filename = positional[0];
+	let channel_mode = positional.get(1).map_or(ChannelMode::PerChannel, |name| parse_channel_mode(name));
 	println!("Song choice is: {}", filename);
-	// if let Some(peak) = find_spectral_peak(filename) {
-	// 	println!("Max frequency: {} Hz", peak);
-	// }
-	return_rms(filename);
-
-	// Preload a vector of frequencies from the track to sync with the audio
-	let peak_data : Vec<f32> = get_peaks(filename);
+	// Analyse the whole track in the background while it plays, unless an
+	// earlier run already did and saved the results
+	let cache = AnalysisCache::new(&cache_dir);
+	let track_song = filename.to_string();
+	let (track_tx, track_rx) = mpsc::channel();
+	let (progress_tx, progress_rx) = mpsc::channel();
+	let track_thread = thread::spawn(move || {
+		let key = cache_key(&track_song, &config, channel_mode);
+		if let Err(ref err) = key {
+			println!("Could not hash the track for the analysis cache: {}", err);
+		}
+		let cached = key.as_ref().ok().and_then(|&key| cache.load(key));
+		let analysis = match cached {
+			Some(analysis) => {
+				println!("Using the cached analysis of the track");
+				analysis
+			},
+			None => match analyse_track(&track_song, &config, channel_mode, threads,
+				&mut |progress| { let _ = progress_tx.send(progress); }) {
+				Ok(analysis) => {
+					if let Ok(key) = key {
+						if let Err(err) = cache.store(key, &analysis) {
+							println!("Could not save the analysis to the cache: {}", err);
+						}
+					}
+					analysis
+				},
+				Err(err) => {
+					println!("Track analysis failed: {}", err);
+					return;
+				}
+			}
+		};
+		print_analysis(&analysis);
+		let _ = track_tx.send(analysis);
+	});
 
     let mut events_loop = EventsLoop::new();
     let window = WindowBuilder::new()
@@ -79,32 +145,44 @@ fn main() {
         g_state.setup_opengl();
     }
 
-	// Channel for sending time data
-	let (tevent_tx, tevent_rx) : (Sender<f64>, Receiver<f64>) = mpsc::channel();
-
 	// The playback thread will pass a message on this channel to signify it has closed the stream
 	// and the main thread can cleanup the audio and time threads
 	let (pdone_tx, pdone_rx) : (Sender<bool>, Receiver<bool>) = mpsc::channel();
+
+	// The playback thread hands back a controller and the live analysis once
+	// the stream is open
+	let (ready_tx, ready_rx) = mpsc::channel();
     
 	// Spawn a separate thread to stream the audio
-	let song_arg = filename.clone();
+	let song_arg = filename.to_string();
 	let audio_thread = thread::spawn(move || {
-		playback(&song_arg, tevent_tx, pdone_tx);
+		playback(&song_arg, channel_mode, config, pdone_tx, ready_tx)
 	});
+	let PlaybackHandles { controller, analysis: mut live_analysis, samples } = match ready_rx.recv() {
+		Ok(handles) => handles,
+		// Playback gave up before the stream was open
+		Err(_) => {
+			report_playback(audio_thread);
+			return;
+		}
+	};
 
-	// Spawn a separate thread to sync the time and freq data
-	let time_thread = thread::spawn(move || {
-		print_time(tevent_rx, peak_data);
-	});
+	// The visuals follow the audio clock, which tracks what is being heard
+	let clock = controller.clock();
 
-    let program_start = time::Instant::now();
     let mut keep_running = true;
-    let mut previous_tick = program_start;
+    let mut previous_tick = time::Instant::now();
     let frame_period: f64 = 1.0 / 60.0; // in secs
     let frame_duration = time::Duration::from_millis(
         (frame_period * 1000.0) as u64);
 	
 	let mut visualizer = Visualizer::new();
+	// The stretch of audio drawn as a waveform, and buffers for it
+	let scope_frames = 1024;
+	let mut scope_samples = Vec::with_capacity(scope_frames * samples.channels());
+	let mut waveform = Vec::with_capacity(scope_frames);
+	let mut track_analysis: Option<TrackAnalysis> = None;
+	let mut analysis_progress: Option<Progress> = None;
     while keep_running {
         // sleep until the start of the next frame
         let current_time = time::Instant::now();
@@ -118,7 +196,7 @@ fn main() {
         // if we have a window, poll for events and resize to fit the window
         if let Some(ref mut display) = display_opt {
             events_loop.poll_events(|event| {
-                let keep_open = handle_event(display, event);
+                let keep_open = handle_event(display, event, &controller);
                 if !keep_open && keep_running {
                     keep_running = false
                 };
@@ -134,12 +212,57 @@ fn main() {
             }
         }
 
-        let program_duration = time::Instant::now().duration_since(
-            program_start);
-        let program_duration_secs = (program_duration.as_secs()  as f32) + 
-            (program_duration.subsec_millis() as f32) / 1000.0;
+        // Follow the playback clock rather than the wall clock so the visuals
+        // match the audio coming out of the speakers, through pauses and seeks
+        let track_secs = clock.position_secs();
+        live_analysis.update(track_secs);
+        let onsets = live_analysis.take_onsets(Layer::Mix);
+        let harmonic_onsets = live_analysis.take_onsets(Layer::Harmonic);
+        let percussive_onsets = live_analysis.take_onsets(Layer::Percussive);
+        let analysis = live_analysis.current();
+        if let Ok(analysis) = track_rx.try_recv() {
+            track_analysis = Some(analysis);
+        }
+        while let Ok(progress) = progress_rx.try_recv() {
+            analysis_progress = Some(progress);
+        }
+        let key = track_analysis.as_ref().and_then(|analysis| analysis.key_at(track_secs));
+
+        // Grab the audio just heard, mixed down to one channel
+        samples.recent(scope_frames, clock.position_frames() as u64, &mut scope_samples);
+        waveform.clear();
+        let mut value = [0f32];
+        for frame in scope_samples.chunks(samples.channels()) {
+            ChannelMode::Mono.split_frame(frame, &mut value);
+            waveform.push(value[0]);
+        }
+
+        let audio = AudioFeatures {
+            peaks: analysis.map_or(&[][..], |frame| &frame.peaks[..]),
+            spectral: analysis.map_or(&[][..], |frame| &frame.features[..]),
+            bands: analysis.map_or(&[][..], |frame| &frame.bands[..]),
+            pitches: analysis.map_or(&[][..], |frame| &frame.pitches[..]),
+            chroma: analysis.map(|frame| &frame.chroma),
+            key: key.as_ref(),
+            envelope: analysis.map(|frame| &frame.envelope),
+            loudness: analysis.map(|frame| &frame.loudness),
+            track_loudness: track_analysis.as_ref().map(|analysis| &analysis.loudness),
+            waveform: &waveform,
+            overview: track_analysis.as_ref().map(|analysis| &analysis.waveform),
+            onsets: &onsets,
+            harmonic: analysis.and_then(|frame| frame.harmonic.as_ref()),
+            percussive: analysis.and_then(|frame| frame.percussive.as_ref()),
+            harmonic_onsets: &harmonic_onsets,
+            percussive_onsets: &percussive_onsets,
+            beat_grid: track_analysis.as_ref().and_then(|analysis| analysis.beat_grid.as_ref()),
+            segments: track_analysis.as_ref().map_or(&[][..], |analysis| &analysis.segments[..]),
+            analysis_progress: match track_analysis {
+                Some(_) => None,
+                None => analysis_progress.and_then(|progress| progress.fraction()).map(|fraction| fraction as f32)
+            }
+        };
         let canvas = visualizer.update(
-            frame_period as f32, program_duration_secs);
+            frame_period as f32, track_secs as f32, &audio);
 
         // if we have a window, render the canvas to it
         if let Some(ref display) = display_opt {
@@ -149,6 +272,10 @@ fn main() {
             });
         }
 
+		if controller.is_stopped() {
+			keep_running = false;
+		}
+
 		// Check if audio playback has ended
 		match pdone_rx.try_recv() {
 			Err(TryRecvError::Empty) => {}, // Do nothing
@@ -160,16 +287,230 @@ fn main() {
 		}
     }
 	
-	// Cleanup the threads before exiting
-	audio_thread.join().unwrap();
-	time_thread.join().unwrap();
+	// Cleanup the threads before exiting. If the window was closed early,
+	// playback is still running and has to be stopped first. The track
+	// analysis is not waited for, as it may have minutes left to run; it
+	// ends with the process, and cache entries are only ever saved whole.
+	controller.stop();
+	report_playback(audio_thread);
+	drop(track_thread);
+}
+
+// Waits for the playback thread. Exits if it failed, saying why.
+fn report_playback(audio_thread: thread::JoinHandle<Result<(), PlaybackError>>) {
+	if let Err(err) = audio_thread.join().unwrap() {
+		eprintln!("Playback failed: {}", err);
+		process::exit(1);
+	}
+}
+
+// Splits command line arguments into positional ones and options given as
+// --name value, handing every option to `set`. Exits if one is rejected.
+fn parse_args<F>(args: &[String], mut set: F) -> Vec<&str>
+	where F: FnMut(&str, &str) -> Result<(), String> {
+	let mut positional = Vec::new();
+	let mut arg_iter = args.iter();
+	while let Some(arg) = arg_iter.next() {
+		if let Some(name) = arg.strip_prefix("--") {
+			let value = arg_iter.next().map_or("", |value| &value[..]);
+			if let Err(err) = set(name, value) {
+				eprintln!("{}", err);
+				process::exit(1);
+			}
+		} else {
+			positional.push(&arg[..]);
+		}
+	}
+	positional
 }
 
-fn handle_event(display: &mut GlWindow, event: Event) -> bool {
+fn parse_threads(value: &str) -> Result<usize, String> {
+	match value.parse::<usize>() {
+		Ok(threads) if threads > 0 => Ok(threads),
+		_ => Err(format!("Expected a positive whole number for threads, got {}", value))
+	}
+}
+
+fn parse_channel_mode(name: &str) -> ChannelMode {
+	ChannelMode::from_name(name).unwrap_or_else(|| {
+		eprintln!("Unknown channel mode: {}", name);
+		process::exit(1);
+	})
+}
+
+fn print_analysis_options() {
+	eprintln!("Analysis options: --fft-size N, --hop-size N, --zero-padding N, --window hann|hamming|blackman-harris|kaiser[:beta]|rectangular,");
+	eprintln!("    --bands octave|third-octave|mel[:count]|log:count[:min-hz:max-hz], --onset-threshold X, --onset-per-band yes|no,");
+	eprintln!("    --onset-layer mix|harmonic|percussive, --pitch-threshold X, --pitch-range min-hz:max-hz,");
+	eprintln!("    --envelope-detector rms|peak, --envelope-attack SECS, --envelope-release SECS,");
+	eprintln!("    --hpss-harmonic-secs SECS, --hpss-percussive-hz HZ, --layers harmonic,percussive|harmonic|percussive|none");
+}
+
+// Draws the spectrum of a track over time, as the analysis behind the
+// visuals sees it, and saves it as a PNG. The signals the channel mode makes
+// (a mono mix unless told otherwise) are drawn one above the other.
+fn export_spectrogram(args: &[String]) {
+	let mut config = AnalysisConfig::default();
+	let mut options = ImageOptions::default();
+	let mut axis = FrequencyAxis::Linear;
+	let mut threads = default_threads();
+	let positional = parse_args(args, |name, value| {
+		match name {
+			"axis" => axis = FrequencyAxis::from_name(value)
+				.ok_or_else(|| format!("Unknown frequency axis: {}", value))?,
+			"threads" => threads = parse_threads(value)?,
+			_ => if !options.set_option(name, value)? {
+				config.set_option(name, value)?;
+			}
+		}
+		Ok(())
+	});
+	if positional.len() < 2 || positional.len() > 3 {
+		eprintln!("Usage: spectrogram FILE OUT.png [mono|per-channel|mid-side] [options]");
+		eprintln!("Image options: --axis linear|log[:min-hz]|mel, --colour-map magma|viridis|heat|grey,");
+		eprintln!("    --db-range min-db:max-db, --grid yes|no, --width N (one per frame up to 2048 by default),");
+		eprintln!("    --height N (per channel), --threads N");
+		print_analysis_options();
+		process::exit(1);
+	}
+	let (input, output) = (positional[0], positional[1]);
+	let mode = positional.get(2).map_or(ChannelMode::Mono, |name| parse_channel_mode(name));
+
+	let spectrogram = spectrogram(input, &config, mode, axis, options.height, threads, &mut show_progress(input))
+		.unwrap_or_else(|err| {
+			eprintln!();
+			eprintln!("Could not analyse {}: {}", input, err);
+			process::exit(1);
+		});
+	eprintln!();
+
+	let image = render_spectrogram(&spectrogram, &options);
+	if let Err(err) = write_png(Path::new(output), image.width, image.height, &image.pixels) {
+		eprintln!("Could not write {}: {}", output, err);
+		process::exit(1);
+	}
+	println!("Wrote a {}x{} spectrogram of {:.1}s to {}", image.width, image.height,
+		spectrogram.frames.len() as f64 * spectrogram.frame_secs, output);
+	if options.grid {
+		let grid = Grid::new(&spectrogram, image.width, options.height);
+		let frequencies: Vec<String> = grid.frequencies_hz.iter().map(|hz| format!("{}", hz)).collect();
+		println!("Grid lines every {}s and at {} Hz", grid.time_step_secs, frequencies.join(", "));
+	}
+}
+
+// Writes every feature of every analysis frame of a track, as the visuals
+// see them, to a CSV or JSON Lines file or to standard output
+fn export_features_command(args: &[String]) {
+	let mut config = AnalysisConfig::default();
+	let mut format = None;
+	let positional = parse_args(args, |name, value| {
+		match name {
+			"format" => format = Some(ExportFormat::from_name(value)
+				.ok_or_else(|| format!("Unknown export format: {}", value))?),
+			_ => config.set_option(name, value)?
+		}
+		Ok(())
+	});
+	if positional.len() < 2 || positional.len() > 3 {
+		eprintln!("Usage: analyze FILE OUT [per-channel|mono|mid-side] [options]");
+		eprintln!("Writes to standard output if OUT is -. The format follows the extension of OUT");
+		eprintln!("    (.csv or .jsonl) unless --format csv|jsonl is given, and is CSV otherwise.");
+		print_analysis_options();
+		process::exit(1);
+	}
+	let (input, output) = (positional[0], positional[1]);
+	let mode = positional.get(2).map_or(ChannelMode::PerChannel, |name| parse_channel_mode(name));
+	let format = format.or_else(|| ExportFormat::from_path(output)).unwrap_or(ExportFormat::Csv);
+
+	let mut out: Box<dyn Write> = if output == "-" {
+		Box::new(io::stdout())
+	} else {
+		match File::create(output) {
+			Ok(file) => Box::new(BufWriter::new(file)),
+			Err(err) => {
+				eprintln!("Could not create {}: {}", output, err);
+				process::exit(1);
+			}
+		}
+	};
+	let result = export_features(input, &config, mode, format, &mut *out, &mut show_progress(input));
+	eprintln!();
+	match result {
+		Ok(frames) => eprintln!("Wrote {} frames to {}", frames, output),
+		Err(err) => {
+			eprintln!("Could not analyse {}: {}", input, err);
+			process::exit(1);
+		}
+	}
+}
+
+// Shows how far an analysis of `filename` has got, a whole percent at a
+// time, on standard error so it stays out of anything written to standard
+// output
+fn show_progress(filename: &str) -> impl FnMut(Progress) + '_ {
+	let mut shown = None;
+	move |progress| {
+		if let Some(fraction) = progress.fraction() {
+			let percent = (fraction * 100f64) as u32;
+			if shown != Some(percent) {
+				eprint!("\rAnalysing {}: {}%", filename, percent);
+				shown = Some(percent);
+			}
+		}
+	}
+}
+
+fn print_analysis(analysis: &TrackAnalysis) {
+	let loudness = &analysis.loudness;
+	println!("Loudness: {:.1} LUFS integrated, {:.1} LU range, {:.1} dBTP true peak",
+		loudness.integrated_lufs, loudness.range_lu, loudness.true_peak_dbtp);
+	println!("Found {} onsets in the track", analysis.onsets.len());
+	match analysis.beat_grid {
+		Some(grid) => println!("Tempo: {:.1} BPM, first downbeat at {:.2} s", grid.bpm, grid.downbeat_secs),
+		None => println!("No steady tempo found")
+	}
+	match analysis.key {
+		Some(key) => println!("Key: {}", key),
+		None => println!("No key found")
+	}
+	let sections: Vec<String> = analysis.segments.iter()
+		.map(|segment| format!("{} {:.1}-{:.1}s", segment.label_name(), segment.start_secs, segment.end_secs))
+		.collect();
+	println!("Sections: {}", sections.join(", "));
+	for (signal, &(time_secs, db)) in analysis.loudest.iter().enumerate() {
+		println!("Signal {} is loudest at {:.1}s ({:.1} dB)", signal, time_secs, db);
+	}
+}
+
+// Space pauses and resumes, the arrow keys skip 5 seconds either way,
+// Home restarts the track and Escape stops it
+fn handle_event(display: &mut GlWindow, event: Event, controller: &PlaybackController) -> bool {
     match event {
         Event::WindowEvent{event: win_event, ..} => {
             match win_event {
                 WindowEvent::CloseRequested => return false,
+                WindowEvent::KeyboardInput { input: KeyboardInput {
+                    state: ElementState::Pressed, virtual_keycode: Some(key), .. }, .. } => {
+                    let position = match key {
+                        VirtualKeyCode::Space => {
+                            if controller.is_paused() {
+                                controller.resume();
+                            } else {
+                                controller.pause();
+                            }
+                            controller.position_secs()
+                        },
+                        VirtualKeyCode::Left => controller.seek_by(-5.0),
+                        VirtualKeyCode::Right => controller.seek_by(5.0),
+                        VirtualKeyCode::Home => controller.seek_to(0.0),
+                        VirtualKeyCode::Escape => {
+                            controller.stop();
+                            controller.position_secs()
+                        },
+                        _ => return true
+                    };
+                    print_position(position, controller);
+                },
                 WindowEvent::Resized(logical_size) => {
                     // when the window resizes, we must resize the context
                     let dpi = display.get_hidpi_factor();
@@ -185,33 +526,9 @@ fn handle_event(display: &mut GlWindow, event: Event) -> bool {
     true
 }
 
-// We run this in a separate thread, synchronizing the time data from the playback
-// and the precomputed frequency data. We sychronize the data by mapping each
-// frequency to a 10ms time slice. Theoretically, we would then pass the frequency
-// back in a message to the main function to forward to the graphics.
-fn print_time(tevent_rx: Receiver<f64>, peaks: Vec<f32>) {
-	let mut peaks_iter = peaks.into_iter();
-	// Wait until the playback thread begins streaming time data
-	tevent_rx.recv().unwrap();
-
-	let start_time = time::Instant::now();
-	let inc = time::Duration::from_millis(10 as u64);
-	let mut diff_time = time::Duration::from_millis(0 as u64);
-
-	loop {
-		let elapsed = start_time.elapsed();
-		if elapsed > diff_time {
-			let curr_peak = match peaks_iter.next() {
-				Some(p) => p,
-				None => break
-			};
-			diff_time = diff_time + inc;
-			println!("Time: {:?}, Peak: {:?}", elapsed, curr_peak);
-		}
-		while let Ok(_) = tevent_rx.try_recv() {
-			println!("Time: {:?}", start_time.elapsed());
-		}
-
-		thread::sleep(time::Duration::from_millis(1));
-	}
+fn print_position(position: f64, controller: &PlaybackController) {
+    match controller.duration_secs() {
+        Some(duration) => println!("Position: {:.1}s / {:.1}s", position, duration),
+        None => println!("Position: {:.1}s", position)
+    }
 }
@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+// Most bytes a stored (uncompressed) deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+// Writes 8-bit RGB pixels, row by row from the top, as a PNG file. The
+// image data is stored without compression, which every PNG reader
+// accepts, so no compression library is needed.
+pub fn write_png(path: &Path, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height * 3);
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per sample, RGB, deflate, standard filters, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header)?;
+
+    // every row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in pixels.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(crc32(!0u32, kind), data);
+    out.write_all(&(!crc).to_be_bytes())
+}
+
+// A zlib stream holding `data` in stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len() / MAX_STORED_BLOCK + 1;
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // deflate with a 32 KiB window, no preset dictionary, fastest
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(last as u8);
+        out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Carries a CRC-32 (as PNG uses it) on over more bytes, before the final
+// inversion
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn crc32_matches_known_values() {
+        assert_eq!(!crc32(!0u32, b"123456789"), 0xcbf43926);
+        // the CRC every PNG ends with
+        assert_eq!(!crc32(!0u32, b"IEND"), 0xae426082);
+        // carrying it on over a second slice gives the same as one slice
+        assert_eq!(crc32(crc32(!0u32, b"IHDR"), b"data"), crc32(!0u32, b"IHDRdata"));
+    }
+
+    #[test]
+    fn adler32_matches_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn zlib_stored_splits_long_data_into_blocks() {
+        assert_eq!(zlib_stored(&[]), vec![0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+        let data = vec![7u8; MAX_STORED_BLOCK + 10];
+        let out = zlib_stored(&data);
+        assert_eq!(out.len(), 2 + 5 + MAX_STORED_BLOCK + 5 + 10 + 4);
+        assert_eq!(&out[2..7], &[0, 0xff, 0xff, 0, 0]);
+        assert_eq!(&out[7 + MAX_STORED_BLOCK..12 + MAX_STORED_BLOCK], &[1, 10, 0, 0xf5, 0xff]);
+    }
+
+    #[test]
+    fn writes_a_one_pixel_image() {
+        let path = env::temp_dir().join("final_proj_png_test.png");
+        write_png(&path, 1, 1, &[0xff, 0, 0]).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected: Vec<u8> = [
+            &SIGNATURE[..],
+            &[0, 0, 0, 13], b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0], &[0x90, 0x77, 0x53, 0xde],
+            // one stored block holding the filter byte and the pixel, then
+            // the Adler-32 of those
+            &[0, 0, 0, 15], b"IDAT", &[0x78, 0x01, 1, 4, 0, 0xfb, 0xff, 0, 0xff, 0, 0, 0x03, 0x01, 0x01, 0x00],
+            &[0x8d, 0x1d, 0xe5, 0x82],
+            &[0, 0, 0, 0], b"IEND", &[0xae, 0x42, 0x60, 0x82]
+        ].concat();
+        assert_eq!(bytes, expected);
+    }
+}
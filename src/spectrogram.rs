@@ -0,0 +1,248 @@
+use super::audio::{FrequencyAxis, Spectrogram};
+
+// Rows of black between the panels of different signals
+const PANEL_GAP: usize = 4;
+// Closest that grid lines are drawn to each other, in pixels
+const MIN_GRID_SPACING: f64 = 60f64;
+// How far grid lines lighten the pixels under them, towards white
+const GRID_BLEND: f32 = 0.4;
+// Most columns drawn when no width is given, so long files make images
+// of a sensible size
+const MAX_DEFAULT_WIDTH: usize = 2048;
+
+// Colours running from quiet to loud
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColourMap {
+    Grey,
+    Heat,
+    Viridis,
+    Magma
+}
+
+impl ColourMap {
+    pub fn from_name(name: &str) -> Option<ColourMap> {
+        match name {
+            "grey" | "gray" => Some(ColourMap::Grey),
+            "heat" => Some(ColourMap::Heat),
+            "viridis" => Some(ColourMap::Viridis),
+            "magma" => Some(ColourMap::Magma),
+            _ => None
+        }
+    }
+
+    // The colour of `value`, from 0 for the quietest level shown to 1 for
+    // the loudest, blended between evenly spaced stops
+    pub fn colour(self, value: f32) -> [u8; 3] {
+        let stops: &[[u8; 3]] = match self {
+            ColourMap::Grey => &[[0, 0, 0], [255, 255, 255]],
+            ColourMap::Heat => &[[0, 0, 0], [128, 0, 0], [255, 64, 0], [255, 200, 0], [255, 255, 255]],
+            ColourMap::Viridis => &[[68, 1, 84], [59, 82, 139], [33, 145, 140], [94, 201, 98], [253, 231, 37]],
+            ColourMap::Magma => &[[0, 0, 4], [59, 15, 112], [140, 41, 129], [222, 73, 104], [254, 159, 109],
+                [252, 253, 191]]
+        };
+        let position = value.clamp(0f32, 1f32) * (stops.len() - 1) as f32;
+        let stop = (position as usize).min(stops.len() - 2);
+        let fraction = position - stop as f32;
+        let mut colour = [0u8; 3];
+        for (channel, out) in colour.iter_mut().enumerate() {
+            let (from, to) = (stops[stop][channel] as f32, stops[stop + 1][channel] as f32);
+            *out = (from + fraction * (to - from)).round() as u8;
+        }
+        colour
+    }
+}
+
+/*
+How a spectrogram is drawn. Levels from `min_db` up to `max_db` run
+through the colour map, every signal gets a panel `height` pixels tall,
+and the frames are squeezed or stretched to `width` columns if it is set
+(one column per frame otherwise, up to `MAX_DEFAULT_WIDTH`).
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageOptions {
+    pub colour_map: ColourMap,
+    pub min_db: f32,
+    pub max_db: f32,
+    pub grid: bool,
+    pub width: Option<usize>,
+    pub height: usize
+}
+
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            colour_map: ColourMap::Magma,
+            min_db: -100f32,
+            max_db: 0f32,
+            grid: true,
+            width: None,
+            height: 512
+        }
+    }
+}
+
+impl ImageOptions {
+    // Sets one option from the command line: "colour-map", "db-range" (as
+    // "min-db:max-db"), "grid" (yes or no), "width" or "height". Returns
+    // false for any other name, which may be meant for the analysis.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<bool, String> {
+        let count = || match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Expected a positive whole number for {}, got {}", name, value))
+        };
+        match name {
+            "colour-map" => self.colour_map = ColourMap::from_name(value)
+                .ok_or_else(|| format!("Unknown colour map: {}", value))?,
+            "db-range" => {
+                let mut parts = value.splitn(2, ':').map(|part| part.parse::<f32>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(min)), Some(Ok(max))) if min < max => {
+                        self.min_db = min;
+                        self.max_db = max;
+                    },
+                    _ => return Err(format!("Expected min-db:max-db for {}, got {}", name, value))
+                }
+            },
+            "grid" => self.grid = match value {
+                "yes" | "true" => true,
+                "no" | "false" => false,
+                _ => return Err(format!("Expected yes or no for {}, got {}", name, value))
+            },
+            "width" => self.width = Some(count()?),
+            "height" => self.height = count()?,
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+}
+
+// An image as 8-bit RGB pixels, row by row from the top
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>
+}
+
+/*
+Where grid lines fall: every `time_step_secs` seconds from the start of the
+track, and at each of the frequencies in `frequencies_hz`.
+*/
+pub struct Grid {
+    pub time_step_secs: f64,
+    pub frequencies_hz: Vec<f32>
+}
+
+impl Grid {
+    // Lines far enough apart not to crowd an image of `width` columns and
+    // panels `height` rows tall, on round numbers
+    pub fn new(spectrogram: &Spectrogram, width: usize, height: usize) -> Grid {
+        let duration = spectrogram.frames.len() as f64 * spectrogram.frame_secs;
+        let time_step_secs = round_step(MIN_GRID_SPACING * duration / width.max(1) as f64);
+
+        let (axis, max_hz) = (spectrogram.axis, spectrogram.max_hz);
+        let row = |hz: f32| axis.position(hz, max_hz) as f64 * height as f64;
+        let mut frequencies_hz = Vec::new();
+        if axis == FrequencyAxis::Linear {
+            // evenly spaced lines
+            let step = round_step(MIN_GRID_SPACING * max_hz as f64 / height.max(1) as f64) as f32;
+            let mut hz = step;
+            while hz < max_hz {
+                frequencies_hz.push(hz);
+                hz += step;
+            }
+        } else {
+            // 1, 2 and 5 times every power of ten, thinned out where the
+            // axis crowds them together
+            let mut last_row = f64::NEG_INFINITY;
+            let mut decade = 1f32;
+            while decade < max_hz {
+                for &multiple in &[1f32, 2f32, 5f32] {
+                    let hz = decade * multiple;
+                    let position = row(hz);
+                    if hz < max_hz && position >= MIN_GRID_SPACING / 2f64 && position - last_row >= MIN_GRID_SPACING {
+                        frequencies_hz.push(hz);
+                        last_row = position;
+                    }
+                }
+                decade *= 10f32;
+            }
+        }
+        Grid { time_step_secs, frequencies_hz }
+    }
+}
+
+// The smallest of 1, 2 and 5 times a power of ten that is at least `step`
+fn round_step(step: f64) -> f64 {
+    let decade = 10f64.powf(step.max(1e-3).log10().floor());
+    [1f64, 2f64, 5f64, 10f64].iter().map(|multiple| multiple * decade)
+        .find(|&candidate| candidate >= step)
+        .unwrap_or(10f64 * decade)
+}
+
+// Draws every signal of a spectrogram as a panel, the first at the top,
+// with high frequencies up and time running left to right
+pub fn render_spectrogram(spectrogram: &Spectrogram, options: &ImageOptions) -> Image {
+    let frames = spectrogram.frames.len();
+    let signals = spectrogram.signal_count();
+    let width = options.width.unwrap_or_else(|| frames.min(MAX_DEFAULT_WIDTH)).max(1);
+    let panel = options.height;
+    let height = (signals * (panel + PANEL_GAP)).saturating_sub(PANEL_GAP).max(1);
+    let mut image = Image { width, height, pixels: vec![0u8; width * height * 3] };
+    if frames == 0 {
+        return image;
+    }
+
+    let rows = spectrogram.rows;
+    let range = options.max_db - options.min_db;
+    let mut levels = vec![0f32; rows];
+    for column in 0..width {
+        // the frames squeezed into this column, or the one it stretches
+        let first = column * frames / width;
+        let last = ((column + 1) * frames / width).max(first + 1);
+        for signal in 0..signals {
+            for (row, level) in levels.iter_mut().enumerate() {
+                *level = spectrogram.frames[first..last].iter()
+                    .map(|frame| frame[signal][row])
+                    .fold(f32::NEG_INFINITY, f32::max);
+            }
+            for y in 0..panel {
+                // row of the spectrogram under this pixel, counting up
+                let row = ((panel - 1 - y) * rows / panel).min(rows - 1);
+                let colour = options.colour_map.colour((levels[row] - options.min_db) / range);
+                let offset = ((signal * (panel + PANEL_GAP) + y) * width + column) * 3;
+                image.pixels[offset..offset + 3].copy_from_slice(&colour);
+            }
+        }
+    }
+
+    if options.grid {
+        let grid = Grid::new(spectrogram, width, panel);
+        let secs_per_column = frames as f64 * spectrogram.frame_secs / width as f64;
+        let start_secs = spectrogram.time_secs(0) - spectrogram.frame_secs / 2f64;
+        let mut line_secs = grid.time_step_secs;
+        while line_secs < start_secs + frames as f64 * spectrogram.frame_secs {
+            let column = ((line_secs - start_secs) / secs_per_column) as usize;
+            for y in (0..height).filter(|y| y % (panel + PANEL_GAP) < panel) {
+                lighten(&mut image, column.min(width - 1), y);
+            }
+            line_secs += grid.time_step_secs;
+        }
+        for &hz in &grid.frequencies_hz {
+            let position = spectrogram.axis.position(hz, spectrogram.max_hz);
+            let y = panel - 1 - ((position * panel as f32) as usize).min(panel - 1);
+            for signal in 0..signals {
+                for column in 0..width {
+                    lighten(&mut image, column, signal * (panel + PANEL_GAP) + y);
+                }
+            }
+        }
+    }
+    image
+}
+
+fn lighten(image: &mut Image, x: usize, y: usize) {
+    let offset = (y * image.width + x) * 3;
+    for value in &mut image.pixels[offset..offset + 3] {
+        *value = (*value as f32 + GRID_BLEND * (255f32 - *value as f32)).round() as u8;
+    }
+}
@@ -1,55 +1,419 @@
 use super::graphics::*;
+use super::audio::{OnsetEvent, BeatGrid, Pitch, Chroma, Key, Mode, Loudness, LoudnessReading, Envelope,
+    SpectralFeatures, Segment, LayerFrame, WaveformPyramid};
 use std::f32::consts::*;
 use cgmath::*;
 
+// Band levels at or below this many dB draw as empty bars
+const MIN_BAND_DB: f32 = -80f32;
+// How quickly the flash from an onset fades, in units per second
+const PULSE_DECAY: f32 = 4f32;
+// Bars taken by one trip of the camera around the scene
+const BARS_PER_LOOP: i64 = 2;
+// How far ahead upcoming beats are shown, in seconds
+const BEAT_LOOKAHEAD_SECS: f64 = 2f64;
+// Pitches found with less confidence than this are not drawn
+const MIN_PITCH_CONFIDENCE: f32 = 0.6;
+// Range of MIDI notes spread over the height of the scene (C2 to C7)
+const LOWEST_NOTE: f32 = 36f32;
+const HIGHEST_NOTE: f32 = 96f32;
+// Tracks are drawn as if they had been mastered to this loudness, so quiet
+// and loud masters fill the scene alike, within the limits of the gain range
+const REFERENCE_LUFS: f32 = -14f32;
+const MIN_GAIN_DB: f32 = -12f32;
+const MAX_GAIN_DB: f32 = 12f32;
+// Loudness shown by the meter, relative to the reference
+const METER_RANGE_LU: f32 = 30f32;
+// True peaks above this light the meter up as clipping
+const MAX_TRUE_PEAK_DBTP: f32 = -1f32;
+// Columns the overview of the whole track's waveform is drawn in
+const OVERVIEW_COLUMNS: usize = 100;
+// Camera distance from the centre and height for every kind of section,
+// and how long the camera takes to move between them
+const SCENES: [(f32, f32); 4] = [(100f32, 40f32), (70f32, 20f32), (130f32, 70f32), (90f32, 90f32)];
+const SCENE_FADE_SECS: f64 = 2f64;
+
 pub struct Visualizer {
     // TODO: add in anything that must be kept around between updates
+    // flashes from 1 down to 0 after every onset
+    pulse: f32,
+    // the same for every band, when onsets are detected per band
+    band_pulses: Vec<f32>,
+    // the same for new notes, when the harmonic layer is separated
+    note_pulse: f32
+}
+
+/*
+What the visualizer draws from on each frame, all describing the audio
+being heard at the time. Slices are empty until the analysis catches up.
+*/
+pub struct AudioFeatures<'a> {
+    // the peak frequency (in Hz) of every analysed channel, in the order
+    // the analysis channel mode produces them
+    pub peaks: &'a [f32],
+    // the timbre of every analysed channel
+    pub spectral: &'a [SpectralFeatures],
+    // the level of every frequency band (in dB) of every analysed channel
+    pub bands: &'a [Vec<f32>],
+    // the pitch of every analysed channel, when it has one
+    pub pitches: &'a [Option<Pitch>],
+    // the pitch classes being heard
+    pub chroma: Option<&'a Chroma>,
+    // the key of the current section of the track, once it is known
+    pub key: Option<&'a Key>,
+    // the smoothed level of every analysed channel and of its bands
+    pub envelope: Option<&'a Envelope>,
+    // the loudness of what is being heard, and of the whole track
+    pub loudness: Option<&'a LoudnessReading>,
+    pub track_loudness: Option<&'a Loudness>,
+    // the audio just heard, oldest sample first
+    pub waveform: &'a [f32],
+    // the waveform of the whole track, once it has been summarised
+    pub overview: Option<&'a WaveformPyramid>,
+    // onsets heard since the last update
+    pub onsets: &'a [OnsetEvent],
+    // the harmonic and percussive layers of what is being heard described
+    // on their own, and the onsets heard in each since the last update,
+    // when the analysis separates them
+    pub harmonic: Option<&'a LayerFrame>,
+    pub percussive: Option<&'a LayerFrame>,
+    pub harmonic_onsets: &'a [OnsetEvent],
+    pub percussive_onsets: &'a [OnsetEvent],
+    // the track's beats, once the tempo has been estimated
+    pub beat_grid: Option<&'a BeatGrid>,
+    // the sections of the track in order, once its structure is known
+    pub segments: &'a [Segment],
+    // how much of the whole-track analysis is done, from 0 to 1, while it
+    // is still running
+    pub analysis_progress: Option<f32>
 }
 
 impl Visualizer {
     
     pub fn new() -> Visualizer {
-        return Visualizer {};
+        return Visualizer { pulse: 0f32, band_pulses: Vec::new(), note_pulse: 0f32 };
     }
 
-    // TODO:
-    // later, also pass in a struct containing the relevant audio data
-    pub fn update(&mut self, delta_secs: f32, time_secs: f32) -> Canvas {
+    pub fn update(&mut self, delta_secs: f32, time_secs: f32, audio: &AudioFeatures) -> Canvas {
         let mut canvas = Canvas::new();
+        // the drums alone flash the scene when they are separated out, so
+        // sustained notes starting do not
+        let onsets = audio.percussive.map_or(audio.onsets, |_| audio.percussive_onsets);
+        self.update_pulses(delta_secs, onsets, audio.harmonic_onsets);
         
         // TODO: for debugging
         // println!("time (s): {}", time_secs);
 
         // loops from 0 to 1, then back to 0, and so on
         let anim_factor = map((2f32 * PI * time_secs / 5.0f32).sin(), -1f32, 1f32);
-        // loops from 0 to 1, with wraparound
-        let a_p = 3f32;
-        let anim_mod = (time_secs % a_p) / a_p;
+        // loops from 0 to 1, with wraparound, in time with the music once
+        // the beat grid is known. `bounce` jumps to 1 on every beat and
+        // settles back to 0 before the next, twice as high on downbeats.
+        let (anim_mod, bounce) = match audio.beat_grid {
+            Some(grid) => {
+                let musical = grid.musical_time(time_secs as f64);
+                let bars = musical.bar.rem_euclid(BARS_PER_LOOP) as f32 + musical.bar_phase;
+                let accent = if musical.beat_in_bar == 0 { 1f32 } else { 0.5f32 };
+                (bars / BARS_PER_LOOP as f32, accent * (1f32 - musical.beat_phase).powi(3))
+            },
+            None => {
+                let a_p = 3f32;
+                ((time_secs % a_p) / a_p, 0f32)
+            }
+        };
+
+        // every kind of section has its own scene, which the camera moves
+        // to over the first moments of the section
+        let now = time_secs as f64;
+        let current = audio.segments.iter()
+            .position(|segment| segment.start_secs <= now && now < segment.end_secs);
+        let (radius, height) = match current {
+            Some(index) => {
+                let scene = |segment: &Segment| SCENES[segment.label % SCENES.len()];
+                let segment = &audio.segments[index];
+                let to = scene(segment);
+                let from = if index > 0 { scene(&audio.segments[index - 1]) } else { to };
+                let fade = ((now - segment.start_secs) / SCENE_FADE_SECS).min(1f64) as f32;
+                let eased = fade * fade * (3f32 - 2f32 * fade);
+                (lerp(eased, from.0, to.0), lerp(eased, from.1, to.1))
+            },
+            None => SCENES[0]
+        };
 
         // move the camera in a loop around the center
         let angle = 2f32 * PI * anim_mod;
-        let pos = 100f32 * (angle.cos() * vec3(1f32, 0f32, 0f32) +
+        let pos = radius * (angle.cos() * vec3(1f32, 0f32, 0f32) +
                            angle.sin() * vec3(0f32, 0f32, 1f32));
-        canvas.set_camera(pos + vec3(0f32, 40f32, 0f32),
+        canvas.set_camera(pos + vec3(0f32, height + 8f32 * bounce, 0f32),
             vec3(0f32, 0f32, 0f32), vec3(0f32, 1f32, 0f32));
 
         let l_pos = 500f32 * vec3(1f32, 1f32, 1f32);
         canvas.set_light_position(l_pos);
 
-        // draw sample cube
+        // draw one bar per channel, taller for higher peak frequencies and
+        // thicker the louder the channel is
         let len = 10f32;
-        canvas.draw_ppiped(
-            vec3(0f32, 0f32, 0f32),
-            vec3(len, 0f32, 0f32),
-            vec3(0f32, len, 0f32),
-            vec3(0f32, 0f32, len),
-            vec4(0.75f32, 0f32, 0f32, 1f32)
-        );
+        let spacing = 1.5f32 * len;
+        let start_x = -spacing * (audio.peaks.len() as f32 - 1f32) / 2f32;
+        for (i, &peak) in audio.peaks.iter().enumerate() {
+            let height = lerp(frequency_factor(peak), len, 6f32 * len);
+            let loudness = audio.envelope.and_then(|envelope| envelope.signals.get(i))
+                .map_or(1f32, |level| map(level.db.max(MIN_BAND_DB), MIN_BAND_DB, 0f32).min(1f32));
+            let thickness = lerp(loudness, 0.3f32 * len, len);
+            // onsets flash the bars towards white
+            let flash = 0.8f32 * self.pulse;
+            canvas.draw_ppiped(
+                vec3(start_x + i as f32 * spacing - thickness / 2f32, 0f32, -thickness / 2f32),
+                vec3(thickness, 0f32, 0f32),
+                vec3(0f32, height, 0f32),
+                vec3(0f32, 0f32, thickness),
+                vec4(lerp(flash, 0.75f32, 1f32), flash, flash, 1f32)
+            );
+        }
+
+        // float a cube over each bar at the height of the note being played,
+        // coloured around the colour wheel by its pitch class, and turned
+        // by how far out of tune it is. The harmonic layer's pitches are
+        // steadier, when there are any, and its new notes flash the cubes.
+        let pitches = audio.harmonic.map_or(audio.pitches, |layer| &layer.pitches[..]);
+        for (i, pitch) in pitches.iter().enumerate() {
+            let pitch = match *pitch {
+                Some(pitch) if pitch.confidence >= MIN_PITCH_CONFIDENCE => pitch,
+                _ => continue
+            };
+            let note = pitch.note.midi as f32 + pitch.note.cents / 100f32;
+            let height = lerp(map(note, LOWEST_NOTE, HIGHEST_NOTE).clamp(0f32, 1f32), 70f32, 130f32);
+            let size = lerp(pitch.confidence, 2f32, 6f32);
+            let turn = PI / 4f32 * pitch.note.cents / 50f32;
+            let (across, deep) = (size * vec3(turn.cos(), 0f32, turn.sin()),
+                                  size * vec3(-turn.sin(), 0f32, turn.cos()));
+            let (r, g, b) = hue_to_rgb(pitch.note.midi.rem_euclid(12) as f32 / 12f32);
+            let flash = 0.6f32 * self.note_pulse;
+            canvas.draw_ppiped(
+                vec3(start_x + i as f32 * spacing, height, 0f32) - (across + deep) / 2f32,
+                across,
+                vec3(0f32, size, 0f32),
+                deep,
+                vec4(lerp(flash, r, 1f32), lerp(flash, g, 1f32), lerp(flash, b, 1f32), 1f32)
+            );
+        }
+
+        // draw a row of thin bars for the bands of each channel in front,
+        // from the lowest band on the left to the highest on the right. The
+        // heights follow the smoothed band levels, where there are any, so
+        // they rise with the music without jittering.
+        let band_width = 2f32;
+        let max_band_height = 40f32;
+        // quiet bands take on the colour of the key, bright for major keys
+        // and dark for minor ones, and loud bands go green
+        let key_colour = match audio.key {
+            Some(key) => {
+                let (r, g, b) = key_colour(key);
+                let brightness = if key.mode == Mode::Major { 1f32 } else { 0.5f32 };
+                vec3(r, g, b) * brightness
+            },
+            None => vec3(0f32, 0.3f32, 1f32)
+        };
+        for (row, levels) in audio.bands.iter().enumerate() {
+            let row_x = -band_width * levels.len() as f32 / 2f32;
+            let row_z = 25f32 + 2f32 * band_width * row as f32;
+            let smoothed = audio.envelope.and_then(|envelope| envelope.bands.get(row));
+            for (i, &level) in levels.iter().enumerate() {
+                let factor = map(level.max(MIN_BAND_DB), MIN_BAND_DB, 0f32).min(1f32);
+                let height_factor = smoothed.and_then(|levels| levels.get(i))
+                    .map_or(factor, |level| map(level.db.max(MIN_BAND_DB), MIN_BAND_DB, 0f32).min(1f32));
+                let flash = self.band_pulses.get(i).map_or(0f32, |&pulse| pulse);
+                canvas.draw_ppiped(
+                    vec3(row_x + i as f32 * band_width, 0f32, row_z),
+                    vec3(0.8f32 * band_width, 0f32, 0f32),
+                    vec3(0f32, lerp(height_factor, 0.5f32, max_band_height), 0f32),
+                    vec3(0f32, 0f32, band_width),
+                    vec4(lerp(factor, key_colour.x, flash), lerp(factor, key_colour.y, 1f32),
+                         lerp(factor, key_colour.z, flash), 1f32)
+                );
+            }
+        }
+
+        // draw the waveform as a ribbon behind the bars, oscilloscope style,
+        // scaled up or down to make up for how loud the track was mastered.
+        // Bright sounds (a high spectral centroid) turn it from deep blue
+        // to white, and noisy ones (a flat spectrum) make it thicker.
+        let gain_db = audio.track_loudness
+            .map(|loudness| REFERENCE_LUFS - loudness.integrated_lufs)
+            .filter(|gain_db| gain_db.is_finite())
+            .map_or(0f32, |gain_db| gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB));
+        let waveform = audio.waveform;
+        let points = 256;
+        let width = 120f32;
+        let amplitude = lerp(self.pulse, 20f32, 30f32) * 10f32.powf(gain_db / 20f32);
+        let (brightness, noisiness) = match audio.spectral.len() {
+            0 => (0f32, 0f32),
+            n => (audio.spectral.iter().map(|f| frequency_factor(f.centroid_hz)).sum::<f32>() / n as f32,
+                  audio.spectral.iter().map(|f| f.flatness).sum::<f32>() / n as f32)
+        };
+        let ribbon = vec3(0f32, lerp(noisiness.sqrt(), 0.5f32, 4f32), 0f32);
+        let colour = vec4(brightness, lerp(brightness, 0.3f32, 1f32), lerp(brightness, 0.8f32, 1f32), 1f32);
+        let step = (waveform.len() / points).max(1);
+        let trace: Vec<Vec3> = waveform.iter().step_by(step).enumerate().map(|(i, &sample)| {
+            vec3(-width / 2f32 + width * (i * step) as f32 / waveform.len() as f32,
+                 30f32 + amplitude * sample, -30f32)
+        }).collect();
+        for pair in trace.windows(2) {
+            canvas.draw_pgram(pair[0], pair[1] - pair[0], ribbon, colour);
+        }
+
+        // draw a loudness meter at the right: the short-term loudness as a
+        // tall bar with the momentary loudness beside it, both after the
+        // same gain as the waveform, turning red when the audio clips
+        if let Some(loudness) = audio.loudness {
+            let level = |lufs: f32| {
+                let relative = lufs + gain_db - REFERENCE_LUFS;
+                map(relative.max(-METER_RANGE_LU), -METER_RANGE_LU, 0f32).min(1.2f32)
+            };
+            let clipping = loudness.true_peak_dbtp > MAX_TRUE_PEAK_DBTP;
+            let colour = if clipping { vec4(1f32, 0.1f32, 0.1f32, 1f32) } else { vec4(0.9f32, 0.9f32, 0.9f32, 1f32) };
+            for &(lufs, x, width) in &[(loudness.short_term_lufs, 70f32, 4f32),
+                                       (loudness.momentary_lufs, 75f32, 2f32)] {
+                canvas.draw_ppiped(
+                    vec3(x, 0f32, 0f32),
+                    vec3(width, 0f32, 0f32),
+                    vec3(0f32, lerp(level(lufs), 0.5f32, 50f32), 0f32),
+                    vec3(0f32, 0f32, width),
+                    colour
+                );
+            }
+        }
+
+        // draw the strength of every pitch class as a ring of bars around
+        // the scene, C at the back and the rest following clockwise, each in
+        // the colour its note is given everywhere else. Drums smear across
+        // every pitch class, so the harmonic layer is shown when there is one.
+        let chroma = audio.harmonic.map(|layer| &layer.chroma).or(audio.chroma);
+        if let Some(chroma) = chroma {
+            let radius = 60f32;
+            let size = 3f32;
+            for (class, &energy) in chroma.iter().enumerate() {
+                let angle = 2f32 * PI * class as f32 / 12f32;
+                let (r, g, b) = hue_to_rgb(class as f32 / 12f32);
+                canvas.draw_ppiped(
+                    vec3(radius * angle.sin(), 0f32, -radius * angle.cos()),
+                    vec3(size, 0f32, 0f32),
+                    vec3(0f32, lerp(energy, 0.5f32, 20f32), 0f32),
+                    vec3(0f32, 0f32, size),
+                    vec4(r, g, b, 1f32)
+                );
+            }
+        }
+
+        // draw the coming beats as blocks sliding in along the floor to the
+        // left, reaching the front as they are heard; downbeats stand taller
+        if let Some(grid) = audio.beat_grid {
+            let now = time_secs as f64;
+            let track_len = 80f32;
+            for (beat_secs, downbeat) in grid.beats(now, now + BEAT_LOOKAHEAD_SECS) {
+                let ahead = ((beat_secs - now) / BEAT_LOOKAHEAD_SECS) as f32;
+                let size = if downbeat { 4f32 } else { 2f32 };
+                canvas.draw_ppiped(
+                    vec3(-70f32, 0f32, 40f32 - ahead * track_len),
+                    vec3(size, 0f32, 0f32),
+                    vec3(0f32, size, 0f32),
+                    vec3(0f32, 0f32, size),
+                    vec4(1f32, if downbeat { 0.8f32 } else { 1f32 }, lerp(ahead, 0.2f32, 1f32), 1f32)
+                );
+            }
+        }
+
+        // draw the sections of the track as a timeline along the front of
+        // the floor, one colour for every kind of section, with the one
+        // playing raised and a marker where playback is
+        if let Some(last) = audio.segments.last() {
+            let width = 100f32;
+            let x = |secs: f64| -width / 2f32 + width * (secs / last.end_secs) as f32;
+            for (index, segment) in audio.segments.iter().enumerate() {
+                let (r, g, b) = hue_to_rgb((segment.label as f32 * 0.618f32).fract());
+                let height = if current == Some(index) { 2f32 } else { 0.5f32 };
+                canvas.draw_ppiped(
+                    vec3(x(segment.start_secs) + 0.2f32, 0f32, 55f32),
+                    vec3(x(segment.end_secs) - x(segment.start_secs) - 0.4f32, 0f32, 0f32),
+                    vec3(0f32, height, 0f32),
+                    vec3(0f32, 0f32, 2f32),
+                    vec4(r, g, b, 1f32)
+                );
+            }
+            canvas.draw_ppiped(
+                vec3(x(now.min(last.end_secs)) - 0.25f32, 0f32, 54.5f32),
+                vec3(0.5f32, 0f32, 0f32),
+                vec3(0f32, 3f32, 0f32),
+                vec3(0f32, 0f32, 3f32),
+                vec4(1f32, 1f32, 1f32, 1f32)
+            );
+        }
+
+        // draw the waveform of the whole track as a row of columns behind
+        // the timeline, each reaching from the lowest to the highest sample
+        // of its stretch of every channel, with the part already heard lit
+        if let Some(overview) = audio.overview {
+            let width = 100f32;
+            let duration = overview.duration_secs();
+            let column_width = width / OVERVIEW_COLUMNS as f32;
+            let signals: Vec<_> = (0..overview.signal_count())
+                .map(|signal| overview.columns(signal, 0f64, duration, OVERVIEW_COLUMNS))
+                .collect();
+            for column in 0..OVERVIEW_COLUMNS {
+                let buckets = signals.iter().filter_map(|columns| columns[column]);
+                let (low, high) = buckets.fold((0f32, 0f32), |(low, high), bucket| {
+                    (low.min(bucket.min), high.max(bucket.max))
+                });
+                let heard = (column as f64 + 0.5f64) * duration / OVERVIEW_COLUMNS as f64 <= now;
+                let brightness = if heard { 1f32 } else { 0.4f32 };
+                canvas.draw_ppiped(
+                    vec3(-width / 2f32 + column as f32 * column_width, 4f32 + 4f32 * low, 51f32),
+                    vec3(0.8f32 * column_width, 0f32, 0f32),
+                    vec3(0f32, 4f32 * (high - low).max(0.05f32), 0f32),
+                    vec3(0f32, 0f32, 1f32),
+                    vec4(0.4f32 * brightness, 0.8f32 * brightness, brightness, 1f32)
+                );
+            }
+        }
+
+        // while the whole track is still being analysed, draw how far it has
+        // got as a bar filling up along the front of the floor
+        if let Some(progress) = audio.analysis_progress {
+            let width = 100f32;
+            canvas.draw_ppiped(
+                vec3(-width / 2f32, 0f32, 50f32),
+                vec3(lerp(progress.clamp(0f32, 1f32), 0.5f32, width), 0f32, 0f32),
+                vec3(0f32, 1f32, 0f32),
+                vec3(0f32, 0f32, 1f32),
+                vec4(0.4f32, 0.8f32, 1f32, 1f32)
+            );
+        }
         
         canvas
     }
 }
 
+impl Visualizer {
+    // Fades the onset flashes, then restarts them for any new onsets and notes
+    fn update_pulses(&mut self, delta_secs: f32, onsets: &[OnsetEvent], notes: &[OnsetEvent]) {
+        let decay = PULSE_DECAY * delta_secs;
+        self.pulse = (self.pulse - decay).max(0f32);
+        self.note_pulse = if notes.is_empty() { (self.note_pulse - decay).max(0f32) } else { 1f32 };
+        for pulse in &mut self.band_pulses {
+            *pulse = (*pulse - decay).max(0f32);
+        }
+        for onset in onsets {
+            self.pulse = 1f32;
+            if let Some(band) = onset.band {
+                if self.band_pulses.len() <= band {
+                    self.band_pulses.resize(band + 1, 0f32);
+                }
+                self.band_pulses[band] = 1f32;
+            }
+        }
+    }
+}
+
 /*
    Math utilities
 */
@@ -59,8 +423,30 @@ fn map(val: f32, cur_min: f32, cur_max: f32) -> f32 {
     (val - cur_min) / (cur_max - cur_min)
 }
 
+// Maps a frequency onto [0, 1] on a log scale across the audible range
+fn frequency_factor(freq: f32) -> f32 {
+    let factor = map(freq.max(20f32).log2(), 20f32.log2(), 20000f32.log2());
+    factor.min(1f32)
+}
+
 // Maps a value from the range [0, 1] to [min, max]
 fn lerp(factor: f32, min: f32, max: f32) -> f32 {
     min + factor * (max - min)
 }
 
+// The colour of a key's tonic. Keys a fifth apart share most of their
+// notes, so the tonics are spread around the colour wheel by the circle of
+// fifths to give related keys neighbouring colours.
+fn key_colour(key: &Key) -> (f32, f32, f32) {
+    hue_to_rgb((key.tonic * 7 % 12) as f32 / 12f32)
+}
+
+// Fully saturated colour at a position in [0, 1) around the colour wheel
+fn hue_to_rgb(hue: f32) -> (f32, f32, f32) {
+    let channel = |offset: f32| {
+        let distance = ((hue - offset).rem_euclid(1f32) - 0.5f32).abs();
+        (6f32 * distance - 1f32).clamp(0f32, 1f32)
+    };
+    (channel(0f32), channel(1f32 / 3f32), channel(2f32 / 3f32))
+}
+
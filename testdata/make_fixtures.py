@@ -0,0 +1,279 @@
+#!/usr/bin/env python3
+# Writes the small audio files the decoder tests read. Every format is
+# written by hand, so nothing beyond Python is needed to remake them:
+#
+#   tone.flac          8 kHz stereo 16-bit, 2660 frames in verbatim
+#                      blocks of 256 (the last one short), with a SEEKTABLE
+#   tone_no_table.flac the same audio without a SEEKTABLE
+#   noise.ogg          8 kHz stereo Vorbis, 256-sample blocks, several
+#                      packets a page, with a made up spectrum
+#   noise.mp3          32 kHz mono MPEG-1 Layer III, 20 frames, with a
+#                      made up spectrum
+#
+# The Vorbis and MP3 files are not encodings of any signal; they only need
+# to decode to something that changes from frame to frame.
+import math
+import os
+import struct
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def lcg(seed):
+    while True:
+        seed = (seed * 1664525 + 1013904223) & 0xffffffff
+        yield seed >> 8
+
+
+class BitWriter:
+    # Packs bits least significant first, as Vorbis does, or most
+    # significant first, as MP3 does
+    def __init__(self, msb_first=False):
+        self.bytes = bytearray()
+        self.bits = 0
+        self.msb_first = msb_first
+
+    def write(self, value, count):
+        order = range(count - 1, -1, -1) if self.msb_first else range(count)
+        for i in order:
+            if self.bits % 8 == 0:
+                self.bytes.append(0)
+            bit = (value >> i) & 1
+            shift = 7 - self.bits % 8 if self.msb_first else self.bits % 8
+            self.bytes[-1] |= bit << shift
+            self.bits += 1
+
+    # A Huffman codeword, which Vorbis reads a bit at a time from its top
+    def codeword(self, value, length):
+        for i in range(length - 1, -1, -1):
+            self.write((value >> i) & 1, 1)
+
+
+# FLAC
+
+def crc8(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x07) & 0xff if crc & 0x80 else (crc << 1) & 0xff
+    return crc
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x8005) & 0xffff if crc & 0x8000 else (crc << 1) & 0xffff
+    return crc
+
+
+def write_flac(path, seek_table):
+    block, rate, total = 256, 8000, 10 * 256 + 100
+    samples = [(int(12000 * math.sin(2 * math.pi * 440 * n / rate)),
+                int(9000 * math.sin(2 * math.pi * (200 + n / 20) * n / rate))) for n in range(total)]
+    frames = []
+    for number, start in enumerate(range(0, total, block)):
+        chunk = samples[start:start + block]
+        if len(chunk) == block:
+            # block size code 8 is 256, sample rate and size from STREAMINFO
+            header = bytes([0xff, 0xf8, 0x80, 0x18, number])
+        else:
+            # block size code 7 is a 16-bit size - 1 after the frame number
+            header = bytes([0xff, 0xf8, 0x70, 0x18, number]) + struct.pack('>H', len(chunk) - 1)
+        header += bytes([crc8(header)])
+        body = b''
+        for channel in range(2):
+            # verbatim subframe
+            body += b'\x02' + b''.join(struct.pack('>h', frame[channel]) for frame in chunk)
+        frame = header + body
+        frames.append((start, frame + struct.pack('>H', crc16(frame)), len(chunk)))
+
+    info = struct.pack('>HH', block, block) + bytes(6)
+    info += ((rate << 44) | (1 << 41) | (15 << 36) | total).to_bytes(8, 'big') + bytes(16)
+    out = b'fLaC' + bytes([0x80 if not seek_table else 0]) + len(info).to_bytes(3, 'big') + info
+    if seek_table:
+        points, offset = [], 0
+        for i, (start, frame, length) in enumerate(frames):
+            if i % 3 == 0:
+                points.append(start.to_bytes(8, 'big') + offset.to_bytes(8, 'big') + length.to_bytes(2, 'big'))
+            offset += len(frame)
+        # a placeholder point, which readers skip
+        points.append((2 ** 64 - 1).to_bytes(8, 'big') + bytes(10))
+        table = b''.join(points)
+        out += bytes([0x83]) + len(table).to_bytes(3, 'big') + table
+    with open(path, 'wb') as f:
+        f.write(out + b''.join(frame for _, frame, _ in frames))
+
+
+# Ogg Vorbis
+
+def ogg_crc(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x04c11db7) & 0xffffffff if crc & 0x80000000 else (crc << 1) & 0xffffffff
+    return crc
+
+
+def ogg_page(packets, granule, sequence, flags):
+    lacing = bytearray()
+    for packet in packets:
+        lacing += bytes([255] * (len(packet) // 255) + [len(packet) % 255])
+    header = b'OggS' + bytes([0, flags]) + struct.pack('<qII', granule, 0x5eed, sequence)
+    page = header + bytes(4) + bytes([len(lacing)]) + lacing + b''.join(packets)
+    return page[:22] + struct.pack('<I', ogg_crc(page)) + page[26:]
+
+
+def vorbis_float(value):
+    # mantissa * 2^(exponent - 788), with the sign in the top bit
+    sign = 0x80000000 if value < 0 else 0
+    return sign | (788 << 21) | int(abs(value))
+
+
+def write_vorbis(path):
+    channels, rate, packets_per_page, audio_packets = 2, 8000, 4, 41
+    ident = bytes([1]) + b'vorbis' + struct.pack('<IBIiii', 0, channels, rate, 0, 0, 0)
+    # both block sizes 256, then the framing bit
+    ident += bytes([0x88, 1])
+    comment = bytes([3]) + b'vorbis' + struct.pack('<I', 8) + b'fixtures' + struct.pack('<I', 0) + bytes([1])
+
+    setup = BitWriter()
+    for byte in bytes([5]) + b'vorbis':
+        setup.write(byte, 8)
+    setup.write(1, 8)  # two codebooks
+    # book 0, the residue classes: 2 entries of length 1
+    setup.write(0x564342, 24)
+    setup.write(1, 16)
+    setup.write(2, 24)
+    setup.write(0, 1)  # not ordered
+    setup.write(0, 1)  # not sparse
+    for _ in range(2):
+        setup.write(0, 5)
+    setup.write(0, 4)  # no lookup
+    # book 1, the residue values -15 to 16: 32 entries of length 5
+    setup.write(0x564342, 24)
+    setup.write(1, 16)
+    setup.write(32, 24)
+    setup.write(0, 1)
+    setup.write(0, 1)
+    for _ in range(32):
+        setup.write(4, 5)
+    setup.write(1, 4)  # lookup type 1
+    setup.write(vorbis_float(-15), 32)
+    setup.write(vorbis_float(1), 32)
+    setup.write(4, 4)  # 5-bit multiplicands
+    setup.write(0, 1)
+    for value in range(32):
+        setup.write(value, 5)
+    setup.write(0, 6)  # one time domain transform
+    setup.write(0, 16)
+    setup.write(0, 6)  # one floor
+    setup.write(1, 16)  # type 1 with no partitions, so a straight line
+    setup.write(0, 5)
+    setup.write(0, 2)  # multiplier 1
+    setup.write(7, 4)  # range 128
+    setup.write(0, 6)  # one residue
+    setup.write(1, 16)  # type 1
+    setup.write(0, 24)
+    setup.write(128, 24)
+    setup.write(15, 24)  # partitions of 16
+    setup.write(1, 6)  # 2 classes
+    setup.write(0, 8)  # class book 0
+    setup.write(1, 3)  # class 0 decodes in pass 0
+    setup.write(0, 1)
+    setup.write(0, 3)  # class 1 decodes nothing
+    setup.write(0, 1)
+    setup.write(1, 8)  # with book 1
+    setup.write(0, 6)  # one mapping
+    setup.write(0, 16)
+    setup.write(0, 1)  # one submap
+    setup.write(0, 1)  # no coupling
+    setup.write(0, 2)
+    setup.write(0, 8)
+    setup.write(0, 8)  # floor 0
+    setup.write(0, 8)  # residue 0
+    setup.write(0, 6)  # one mode
+    setup.write(0, 1)  # short blocks
+    setup.write(0, 16)
+    setup.write(0, 16)
+    setup.write(0, 8)
+    setup.write(1, 1)  # framing
+
+    random = lcg(5)
+    packets = []
+    for number in range(audio_packets):
+        packet = BitWriter()
+        packet.write(0, 1)  # audio
+        level = 150 + number % 7
+        for _ in range(channels):
+            packet.write(1, 1)  # floor in use
+            packet.write(level, 8)
+            packet.write(level - 20, 8)
+        for _ in range(8):
+            for _ in range(channels):
+                packet.codeword(0, 1)
+            for _ in range(channels):
+                for _ in range(16):
+                    packet.codeword(next(random) % 32, 5)
+        packets.append(bytes(packet.bytes))
+
+    pages = [ogg_page([ident], 0, 0, 0x02), ogg_page([comment, bytes(setup.bytes)], 0, 1, 0)]
+    for first in range(0, audio_packets, packets_per_page):
+        last = min(first + packets_per_page, audio_packets) - 1
+        flags = 0x04 if last == audio_packets - 1 else 0
+        # every packet after the first completes 128 frames
+        pages.append(ogg_page(packets[first:last + 1], 128 * last, len(pages), flags))
+    with open(path, 'wb') as f:
+        f.write(b''.join(pages))
+
+
+# MP3
+
+def write_mp3(path):
+    random = lcg(9)
+    frames = []
+    for number in range(20):
+        side = BitWriter(msb_first=True)
+        main = BitWriter(msb_first=True)
+        side.write(0, 9)  # no bit reservoir
+        side.write(0, 5)
+        side.write(0, 4)
+        for granule in range(2):
+            data = BitWriter(msb_first=True)
+            # quadruples of -1, 0 or 1 in count1 table B: 4 bits each, the
+            # complement of the pattern of nonzero values, then their signs
+            for _ in range(60):
+                quad = next(random) % 16 & next(random) % 16
+                data.write(15 - quad, 4)
+                for i in range(4):
+                    if quad >> (3 - i) & 1:
+                        data.write(next(random) & 1, 1)
+            side.write(data.bits, 12)  # part2_3_length
+            side.write(0, 9)  # no big values
+            side.write(170 + (number + granule) % 5, 8)  # global gain
+            side.write(0, 4)  # no scale factors
+            side.write(0, 1)  # long blocks
+            side.write(0, 15)
+            side.write(0, 4)
+            side.write(0, 3)
+            side.write(0, 1)
+            side.write(0, 1)
+            side.write(1, 1)  # count1 table B
+            for byte in data.bytes[:-1]:
+                main.write(byte, 8)
+            main.write(data.bytes[-1] >> (8 - (data.bits % 8 or 8)), data.bits % 8 or 8)
+        # MPEG-1 Layer III, no CRC, 32 kbps, 32 kHz, mono: 144 bytes a frame
+        header = bytes([0xff, 0xfb, 0x18, 0xc0])
+        frame = header + bytes(side.bytes) + bytes(main.bytes)
+        frames.append(frame + bytes(144 - len(frame)))
+    with open(path, 'wb') as f:
+        f.write(b''.join(frames))
+
+
+write_flac(os.path.join(HERE, 'tone.flac'), True)
+write_flac(os.path.join(HERE, 'tone_no_table.flac'), False)
+write_vorbis(os.path.join(HERE, 'noise.ogg'))
+write_mp3(os.path.join(HERE, 'noise.mp3'))